@@ -0,0 +1,13 @@
+fn main() {
+    #[cfg(feature = "header")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+
+        cbindgen::generate(&crate_dir)
+            .expect("unable to generate C header")
+            .write_to_file(format!("{}/include/wdepc.h", crate_dir));
+    }
+
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
@@ -0,0 +1,167 @@
+#ifndef WDEPC_H
+#define WDEPC_H
+
+/* Generated by cbindgen with `cargo build --features header`, do not edit */
+
+#include <stdbool.h>
+#include <stdint.h>
+
+typedef enum WdepcPowerMode {
+  WDEPC_POWER_MODE_ACTIVE = 0,
+  WDEPC_POWER_MODE_IDLE_A = 1,
+  WDEPC_POWER_MODE_IDLE_B = 2,
+  WDEPC_POWER_MODE_IDLE_C = 3,
+  WDEPC_POWER_MODE_STANDBY_Y = 4,
+  WDEPC_POWER_MODE_STANDBY_Z = 5,
+  WDEPC_POWER_MODE_UNKNOWN = 6,
+  /**
+   * every supported condition, for set timer, set state and restore, never queried
+   */
+  WDEPC_POWER_MODE_ALL = 7,
+} WdepcPowerMode;
+
+typedef enum WdepcStatus {
+  WDEPC_STATUS_OK = 0,
+  /**
+   * null pointer, invalid string or power mode
+   */
+  WDEPC_STATUS_INVALID_ARGUMENT = -1,
+  /**
+   * device can not be opened
+   */
+  WDEPC_STATUS_OPEN = -2,
+  /**
+   * command failed or was rejected by the drive
+   */
+  WDEPC_STATUS_COMMAND = -3,
+  /**
+   * internal error
+   */
+  WDEPC_STATUS_PANIC = -4,
+} WdepcStatus;
+
+/**
+ * Opaque device handle
+ */
+typedef struct WdepcDevice WdepcDevice;
+
+/**
+ * Power condition, times are in 100 milliseconds
+ */
+typedef struct WdepcPowerCond {
+  bool supported;
+  bool savable;
+  bool changeable;
+  bool default_enable;
+  bool saved_enable;
+  bool current_enable;
+  uint32_t default_timer;
+  uint32_t saved_timer;
+  uint32_t current_timer;
+  uint32_t recovery_time;
+  uint32_t min_timer;
+  uint32_t max_timer;
+} WdepcPowerCond;
+
+typedef struct WdepcEpcSetting {
+  struct WdepcPowerCond idle_a;
+  struct WdepcPowerCond idle_b;
+  struct WdepcPowerCond idle_c;
+  struct WdepcPowerCond standby_y;
+  struct WdepcPowerCond standby_z;
+} WdepcEpcSetting;
+
+/**
+ * Open `path`, `device_type` is as `--type`, eg `sat,12`, null probes pass-through
+ *
+ * On success `*out` holds the handle, release it with `wdepc_close`
+ *
+ * # Safety
+ * `path` and `device_type` are null or NUL terminated strings, `out` is writable
+ */
+enum WdepcStatus wdepc_open(const char *path, const char *device_type, struct WdepcDevice **out);
+
+/**
+ * Close device, null is ignored
+ *
+ * # Safety
+ * `device` is null or returned by `wdepc_open` and not closed yet
+ */
+void wdepc_close(struct WdepcDevice *device);
+
+/**
+ * Message of the last error on this thread, null if none
+ *
+ * The string is valid until the next call on this thread
+ */
+const char *wdepc_last_error(void);
+
+/**
+ * Query current power mode into `*out`
+ *
+ * # Safety
+ * `device` is an open handle, `out` is writable
+ */
+enum WdepcStatus wdepc_query_mode(struct WdepcDevice *device, enum WdepcPowerMode *out);
+
+/**
+ * Query EPC settings into `*out`
+ *
+ * # Safety
+ * `device` is an open handle, `out` is writable
+ */
+enum WdepcStatus wdepc_query_epc_setting(struct WdepcDevice *device, struct WdepcEpcSetting *out);
+
+/**
+ * Set timer of power condition `mode`, a `WdepcPowerMode` from idle a to standby z, or all
+ *
+ * # Safety
+ * `device` is an open handle
+ */
+enum WdepcStatus wdepc_set_timer(struct WdepcDevice *device,
+                                 int mode,
+                                 uint16_t timer,
+                                 bool enable,
+                                 bool save);
+
+/**
+ * Enable or disable power condition `mode`
+ *
+ * # Safety
+ * `device` is an open handle
+ */
+enum WdepcStatus wdepc_set_state(struct WdepcDevice *device, int mode, bool enable, bool save);
+
+/**
+ * Force drive to power condition `mode`, all is rejected
+ *
+ * # Safety
+ * `device` is an open handle
+ */
+enum WdepcStatus wdepc_goto_cond(struct WdepcDevice *device, int mode);
+
+/**
+ * Enable EPC, this disables APM
+ *
+ * # Safety
+ * `device` is an open handle
+ */
+enum WdepcStatus wdepc_enable(struct WdepcDevice *device);
+
+/**
+ * Disable EPC, APM is not enabled
+ *
+ * # Safety
+ * `device` is an open handle
+ */
+enum WdepcStatus wdepc_disable(struct WdepcDevice *device);
+
+/**
+ * Restore power condition `mode` from default if `use_default`, else from saved
+ *
+ * # Safety
+ * `device` is an open handle
+ */
+enum WdepcStatus wdepc_restore(struct WdepcDevice *device, int mode, bool use_default, bool save);
+
+#endif /* WDEPC_H */
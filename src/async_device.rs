@@ -0,0 +1,159 @@
+//! Async [`Device`] for tokio, built with the `async` feature
+//!
+//! Commands run on tokio's blocking pool, so a drive spinning up doesn't stall the runtime.
+//!
+//! ```no_run
+//! use wdepc::async_device::AsyncDevice;
+//! use wdepc::{DeviceType, PowerMode};
+//!
+//! /// Check power mode of all drives concurrently
+//! async fn check_all(paths: Vec<String>, ty: DeviceType) -> Vec<(String, anyhow::Result<PowerMode>)> {
+//!     let mut tasks = tokio::task::JoinSet::new();
+//!     for path in paths {
+//!         tasks.spawn(async move {
+//!             let mode = match AsyncDevice::open(path.clone(), ty).await {
+//!                 Ok(device) => device.query_mode().await,
+//!                 Err(e) => Err(e),
+//!             };
+//!             (path, mode)
+//!         });
+//!     }
+//!
+//!     let mut modes = Vec::new();
+//!     while let Some(Ok(mode)) = tasks.join_next().await {
+//!         modes.push(mode);
+//!     }
+//!     modes
+//! }
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::device::{Device, DeviceType, EPCSetting, Identify, PowerCondition, PowerMode};
+
+/// Default time a command may take, spinning up a drive takes up to about 30 seconds
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// [`Device`] with async methods, cheap to clone, clones share the device
+///
+/// Commands on one device are serialized. A command that timed out or whose future was
+/// dropped still completes in the background, as the ioctl can't be interrupted, and the
+/// next command waits for it.
+#[derive(Clone)]
+pub struct AsyncDevice {
+    device: Arc<Mutex<Device>>,
+    timeout: Duration,
+}
+
+impl AsyncDevice {
+    /// Open device with given path and type, see [`Device::open_with_type`]
+    pub async fn open(device: impl Into<String>, ty: DeviceType) -> Result<AsyncDevice> {
+        let device = device.into();
+        let device =
+            run_blocking(DEFAULT_TIMEOUT, move || Device::open_with_type(device, ty)).await?;
+
+        Ok(AsyncDevice::new(device))
+    }
+
+    pub fn new(device: Device) -> AsyncDevice {
+        AsyncDevice {
+            device: Arc::new(Mutex::new(device)),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Fail commands taking longer than `timeout`, including the wait for earlier commands
+    pub fn with_timeout(mut self, timeout: Duration) -> AsyncDevice {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn query_mode(&self) -> Result<PowerMode> {
+        self.run(|device| device.query_mode()).await
+    }
+
+    pub async fn identify(&self) -> Result<Identify> {
+        self.run(|device| device.identify()).await
+    }
+
+    pub async fn query_epc_setting(&self) -> Result<EPCSetting> {
+        self.run(|device| device.query_epc_setting()).await
+    }
+
+    /// See [`Device::goto_cond`]
+    pub async fn goto_cond(&self, condition: PowerCondition) -> Result<()> {
+        self.run(move |device| device.goto_cond(condition)).await
+    }
+
+    /// See [`Device::set_timer`]
+    pub async fn set_timer(
+        &self,
+        condition: PowerCondition,
+        timer: u16,
+        enable: bool,
+        save: bool,
+    ) -> Result<()> {
+        self.run(move |device| device.set_timer(condition, timer, enable, save))
+            .await
+    }
+
+    /// See [`Device::set_state`]
+    pub async fn set_state(
+        &self,
+        condition: PowerCondition,
+        enable: bool,
+        save: bool,
+    ) -> Result<()> {
+        self.run(move |device| device.set_state(condition, enable, save))
+            .await
+    }
+
+    pub async fn enable_epc(&self) -> Result<()> {
+        self.run(|device| device.enable_epc()).await
+    }
+
+    pub async fn disable_epc(&self) -> Result<()> {
+        self.run(|device| device.disable_epc()).await
+    }
+
+    /// See [`Device::restore`]
+    pub async fn restore(
+        &self,
+        condition: PowerCondition,
+        default: bool,
+        save: bool,
+    ) -> Result<()> {
+        self.run(move |device| device.restore(condition, default, save))
+            .await
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Device) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let device = self.device.clone();
+
+        run_blocking(self.timeout, move || {
+            // a panicking command leaves the device usable
+            let mut device = device.lock().unwrap_or_else(|it| it.into_inner());
+            f(&mut device)
+        })
+        .await
+    }
+}
+
+async fn run_blocking<T: Send + 'static>(
+    timeout: Duration,
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let task = tokio::task::spawn_blocking(f);
+
+    match tokio::time::timeout(timeout, task).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => anyhow::bail!("device task failed: {}", e),
+        Err(_) => anyhow::bail!("command timed out after {:?}", timeout),
+    }
+}
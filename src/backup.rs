@@ -0,0 +1,423 @@
+//! Backup of all EPC settings of a drive, to restore them later
+//!
+//! The layout of `power_conditions` is shared with `wdepc info --output json`.
+
+use std::convert::TryFrom;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::device::{Device, EPCSetting, Identify, PowerMode};
+use crate::profile::Change;
+
+/// Drive identity, EPC state and every power condition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backup {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    #[serde(default, with = "wwn")]
+    pub wwn: Option<u64>,
+    pub epc_enabled: bool,
+    #[serde(rename = "power_conditions")]
+    pub setting: EPCSetting,
+}
+
+impl Backup {
+    pub fn take(device: &Device) -> Result<Backup> {
+        let identify = device.identify()?;
+        anyhow::ensure!(identify.epc_supported, "drive doesn't support EPC");
+        let setting = device.query_epc_setting()?;
+
+        Ok(Backup {
+            model: identify.model,
+            serial: identify.serial,
+            firmware: identify.firmware,
+            wwn: identify.wwn,
+            epc_enabled: identify.epc_enabled,
+            setting,
+        })
+    }
+
+    /// Identity of the drive the backup was taken from, APM is unknown
+    pub fn identify(&self) -> Identify {
+        Identify {
+            model: self.model.clone(),
+            serial: self.serial.clone(),
+            firmware: self.firmware.clone(),
+            wwn: self.wwn,
+            epc_supported: true,
+            epc_enabled: self.epc_enabled,
+            apm_supported: false,
+            apm_level: None,
+        }
+    }
+
+    /// Whether the backup was taken from this drive, by WWN, else serial
+    pub fn is_of(&self, identify: &Identify) -> bool {
+        match (self.wwn, identify.wwn) {
+            (Some(wwn), Some(other)) => wwn == other,
+            _ => !self.serial.is_empty() && self.serial == identify.serial,
+        }
+    }
+
+    /// Backup to restore on a drive, of the drive itself or the only one in `backups`
+    ///
+    /// A backup of another model is refused unless `force`.
+    pub fn find<'a>(backups: &'a [Backup], identify: &Identify, force: bool) -> Result<&'a Backup> {
+        let backup = match backups.iter().find(|it| it.is_of(identify)) {
+            Some(backup) => backup,
+            None if backups.len() == 1 => &backups[0],
+            None => anyhow::bail!("no backup of drive {} in file", identify.serial),
+        };
+        anyhow::ensure!(
+            force || backup.model == identify.model,
+            "backup is of model {}, drive is {}, use --force to restore anyway",
+            backup.model,
+            identify.model
+        );
+
+        Ok(backup)
+    }
+
+    /// Commands to bring the drive back to the backup, empty if it is already there
+    ///
+    /// Current timers and states are restored, saved ones too if `save`, defaults can't be
+    /// changed. Conditions the drive can't change are left out.
+    pub fn plan(
+        &self,
+        identify: &Identify,
+        setting: &EPCSetting,
+        save: bool,
+    ) -> Result<Vec<Change>> {
+        anyhow::ensure!(identify.epc_supported, "drive doesn't support EPC");
+
+        let mut changes = Vec::new();
+        if self.epc_enabled && !identify.epc_enabled {
+            changes.push(Change::EnableEpc);
+        }
+
+        // drives abort power condition commands while EPC is disabled
+        if self.epc_enabled || identify.epc_enabled {
+            for mode in PowerMode::CONDITIONS.iter().copied() {
+                let backup = self.setting.condition(mode).unwrap();
+                let desc = setting.condition(mode).unwrap();
+                if !backup.supported || !desc.supported || !desc.changeable {
+                    continue;
+                }
+
+                // state is set alone if the timer is already right
+                let change = |timer: u32, to: (u32, bool), save: bool| {
+                    if timer == to.0 {
+                        return Ok(Change::SetState {
+                            mode,
+                            enable: to.1,
+                            save,
+                        });
+                    }
+                    let timer = u16::try_from(to.0).ok().with_context(|| {
+                        format!("{} timer {} is too long to restore", mode, to.0)
+                    })?;
+                    Ok::<_, anyhow::Error>(Change::SetTimer {
+                        mode,
+                        timer,
+                        enable: to.1,
+                        save,
+                    })
+                };
+
+                // saving sets the current timer and state as well
+                let mut current = (desc.current_timer, desc.current_enable);
+                let saved = (backup.saved_timer, backup.saved_enable);
+                if save && desc.savable && (desc.saved_timer, desc.saved_enable) != saved {
+                    let change = change(desc.saved_timer, saved, true)?;
+                    current = match change {
+                        Change::SetState { enable, .. } => (current.0, enable),
+                        _ => saved,
+                    };
+                    changes.push(change);
+                }
+
+                let backup = (backup.current_timer, backup.current_enable);
+                if current != backup {
+                    changes.push(change(current.0, backup, false)?);
+                }
+            }
+        }
+
+        if !self.epc_enabled && identify.epc_enabled {
+            changes.push(Change::DisableEpc);
+        }
+
+        Ok(changes)
+    }
+}
+
+/// WWN as `0x` prefixed hex, as `--device wwn:` takes it
+mod wwn {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(wwn: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match wwn {
+            Some(wwn) => serializer.serialize_some(&format!("{:#x}", wwn)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|wwn| {
+                u64::from_str_radix(wwn.trim_start_matches("0x"), 16)
+                    .map_err(|_| D::Error::custom(format!("invalid WWN {}", wwn)))
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake::FakeDrive;
+
+    /// Restore `backup` to `drive`, saving settings too
+    fn restore(backup: &Backup, drive: &FakeDrive) -> Vec<Change> {
+        let mut device = drive.device();
+        let identify = device.identify().unwrap();
+        let setting = device.query_epc_setting().unwrap();
+        let changes = backup.plan(&identify, &setting, true).unwrap();
+        for change in &changes {
+            change.apply(&mut device).unwrap();
+        }
+
+        changes
+    }
+
+    /// Backup as JSON, to compare every field
+    fn value(backup: &Backup) -> serde_json::Value {
+        serde_json::to_value(backup).unwrap()
+    }
+
+    #[test]
+    fn restore_changed_drive() {
+        let drive = FakeDrive::new();
+        let backup = Backup::take(&drive.device()).unwrap();
+        {
+            let mut state = drive.state();
+            let idle_b = state.condition(PowerMode::IdleB);
+            idle_b.current_enable = true;
+            idle_b.saved_enable = true;
+            let standby_z = state.condition(PowerMode::StandbyZ);
+            standby_z.current_timer = 3000;
+            standby_z.saved_timer = 3000;
+            standby_z.saved_enable = false;
+            state.condition(PowerMode::IdleA).current_timer = 10;
+        }
+
+        let changes = restore(&backup, &drive);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            value(&Backup::take(&drive.device()).unwrap()),
+            value(&backup)
+        );
+
+        // nothing left to restore
+        assert_eq!(restore(&backup, &drive), []);
+    }
+
+    #[test]
+    fn restore_enables_epc() {
+        let drive = FakeDrive::new();
+        drive.state().condition(PowerMode::IdleB).current_enable = true;
+        let backup = Backup::take(&drive.device()).unwrap();
+        {
+            let mut state = drive.state();
+            state.epc_enabled = false;
+            let idle_b = state.condition(PowerMode::IdleB);
+            idle_b.current_enable = false;
+            idle_b.current_timer = 600;
+        }
+
+        let changes = restore(&backup, &drive);
+        assert_eq!(
+            changes,
+            [
+                Change::EnableEpc,
+                Change::SetTimer {
+                    mode: PowerMode::IdleB,
+                    timer: 1200,
+                    enable: true,
+                    save: false,
+                },
+            ]
+        );
+        assert_eq!(
+            value(&Backup::take(&drive.device()).unwrap()),
+            value(&backup)
+        );
+    }
+
+    #[test]
+    fn restore_disables_epc() {
+        let drive = FakeDrive::new();
+        drive.state().epc_enabled = false;
+        let backup = Backup::take(&drive.device()).unwrap();
+        drive.state().epc_enabled = true;
+        drive.state().condition(PowerMode::IdleC).current_enable = true;
+
+        // conditions are restored before EPC is disabled
+        let changes = restore(&backup, &drive);
+        assert_eq!(
+            changes,
+            [
+                Change::SetState {
+                    mode: PowerMode::IdleC,
+                    enable: false,
+                    save: false,
+                },
+                Change::DisableEpc,
+            ]
+        );
+        assert_eq!(
+            value(&Backup::take(&drive.device()).unwrap()),
+            value(&backup)
+        );
+    }
+
+    #[test]
+    fn find_backup_of_drive() {
+        let drive = FakeDrive::new();
+        let backup = Backup::take(&drive.device()).unwrap();
+        let identify = drive.device().identify().unwrap();
+        let other = Backup {
+            serial: "3WJ0WXYZ".to_string(),
+            wwn: Some(0x5000_cca2_9ac1_0000),
+            ..backup.clone()
+        };
+
+        let backups = [other.clone(), backup];
+        let found = Backup::find(&backups, &identify, false).unwrap();
+        assert!(std::ptr::eq(found, &backups[1]));
+        // a single backup restores to any drive of the model
+        let found = Backup::find(&backups[..1], &identify, false).unwrap();
+        assert!(std::ptr::eq(found, &backups[0]));
+
+        let e = Backup::find(&[other.clone(), other], &identify, false).unwrap_err();
+        assert_eq!(e.to_string(), "no backup of drive 3WJ0ABCD in file");
+    }
+
+    #[test]
+    fn find_refuses_other_model() {
+        let drive = FakeDrive::new();
+        let identify = drive.device().identify().unwrap();
+        let backup = Backup {
+            model: "WDC WUH721414ALE6L4".to_string(),
+            ..Backup::take(&drive.device()).unwrap()
+        };
+        let backups = [backup];
+
+        let e = Backup::find(&backups, &identify, false).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "backup is of model WDC WUH721414ALE6L4, drive is WDC WUH721818ALE6L4, use --force to restore anyway"
+        );
+        assert!(Backup::find(&backups, &identify, true).is_ok());
+    }
+
+    #[test]
+    fn is_of_by_wwn_then_serial() {
+        let drive = FakeDrive::new();
+        let backup = Backup::take(&drive.device()).unwrap();
+        let identify = drive.device().identify().unwrap();
+        assert!(backup.is_of(&identify));
+
+        // WWN wins over serial
+        let other = Identify {
+            wwn: Some(1),
+            ..identify.clone()
+        };
+        assert!(!backup.is_of(&other));
+
+        let no_wwn = Identify {
+            wwn: None,
+            ..identify.clone()
+        };
+        assert!(backup.is_of(&no_wwn));
+        let other = Identify {
+            serial: "3WJ0WXYZ".to_string(),
+            ..no_wwn.clone()
+        };
+        assert!(!backup.is_of(&other));
+
+        // an empty serial matches nothing
+        let backup = Backup {
+            serial: String::new(),
+            ..backup
+        };
+        let no_serial = Identify {
+            serial: String::new(),
+            ..no_wwn
+        };
+        assert!(!backup.is_of(&no_serial));
+    }
+
+    #[test]
+    fn document_layout() {
+        let drive = FakeDrive::new();
+        let backup = Backup::take(&drive.device()).unwrap();
+
+        let value = serde_json::to_value(&backup).unwrap();
+        assert_eq!(value["model"], "WDC WUH721818ALE6L4");
+        assert_eq!(value["wwn"], "0x5000cca29ac12345");
+        assert_eq!(value["epc_enabled"], true);
+        assert_eq!(
+            value["power_conditions"]["standby_z"],
+            json!({
+                "supported": true,
+                "savable": true,
+                "changeable": true,
+                "default_enable": true,
+                "saved_enable": true,
+                "current_enable": true,
+                "default_timer": 9000,
+                "saved_timer": 9000,
+                "current_timer": 9000,
+                "recovery_time": 10,
+                "min_timer": 1,
+                "max_timer": 0xffff,
+            })
+        );
+
+        let parsed: Backup = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.wwn, backup.wwn);
+        assert_eq!(parsed.setting.idle_a.current_timer, 20);
+    }
+
+    #[test]
+    fn document_errors() {
+        let drive = FakeDrive::new();
+        let mut value = serde_json::to_value(Backup::take(&drive.device()).unwrap()).unwrap();
+
+        value["wwn"] = json!(null);
+        assert_eq!(
+            serde_json::from_value::<Backup>(value.clone()).unwrap().wwn,
+            None
+        );
+
+        value["wwn"] = json!("0xwwn");
+        let e = serde_json::from_value::<Backup>(value.clone()).unwrap_err();
+        assert_eq!(e.to_string(), "invalid WWN 0xwwn");
+
+        value["wwn"] = json!(null);
+        value["power_conditions"]["idle_b"]
+            .as_object_mut()
+            .unwrap()
+            .remove("max_timer");
+        let e = serde_json::from_value::<Backup>(value).unwrap_err();
+        assert_eq!(e.to_string(), "missing field `max_timer`");
+    }
+}
@@ -0,0 +1,304 @@
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+use libc::{poll, pollfd, read, write, O_NONBLOCK, POLLIN};
+
+use crate::device::{parse_sense, Device, DeviceType, PassthroughType, PowerMode};
+use crate::ffi::{AtaCmd, AtaTaskfile, Protocol, SgIoHdr, ATA_16_LEN, SG_DXFER_NONE};
+use crate::transport::{sg_node, TransportType};
+
+/// Command timeout, in milliseconds
+const TIMEOUT: u32 = 20_000;
+
+/// In flight CHECK POWER MODE on one sg node
+struct Request {
+    file: File,
+    cdb: [u8; ATA_16_LEN],
+    sense: [u8; 32],
+    result: Option<Result<PowerMode>>,
+    /// no completion within the timeout
+    timed_out: bool,
+}
+
+/// Query power mode of several devices concurrently
+///
+/// CHECK POWER MODE is written to the sg node of every device, tagged with `pack_id`,
+/// and completions are collected with poll/read, so the total time is about the slowest
+/// device. Devices without a usable sg node, or which don't complete the SAT command,
+/// are queried one by one with `Device`. Devices not answering within the timeout are
+/// reported failed, a query one by one would block as long again.
+///
+/// Results are in the same order as `devices`
+pub fn query_modes(devices: &[&str], ty: DeviceType) -> Vec<Result<PowerMode>> {
+    let concurrent = ty.transport == TransportType::SgIo
+        && matches!(
+            ty.passthrough,
+            PassthroughType::Auto | PassthroughType::Sat16
+        );
+
+    let mut requests: Vec<Option<Request>> = devices
+        .iter()
+        .map(|device| {
+            if concurrent {
+                open_request(device)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    submit_and_collect(&mut requests, TIMEOUT);
+
+    requests
+        .into_iter()
+        .zip(devices)
+        .map(|(request, device)| match request {
+            Some(Request {
+                result: Some(Ok(mode)),
+                ..
+            }) => Ok(mode),
+            Some(Request {
+                timed_out: true, ..
+            }) => anyhow::bail!(
+                "{} didn't answer CHECK POWER MODE within {} s",
+                device,
+                TIMEOUT / 1000
+            ),
+            _ => Device::open_with_type(device, ty)?.query_mode(),
+        })
+        .collect()
+}
+
+fn open_request(device: &str) -> Option<Request> {
+    let sg = sg_node(device)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(O_NONBLOCK)
+        .open(sg)
+        .ok()?;
+
+    Some(Request::new(file))
+}
+
+impl Request {
+    fn new(file: File) -> Request {
+        let cdb = AtaTaskfile {
+            cmd: AtaCmd::CheckPowerMode,
+            protocol: Protocol::None,
+            feature: 0,
+            sector_count: 0,
+            lba: 0,
+        }
+        .to_passthrough16();
+
+        Request {
+            file,
+            cdb,
+            sense: [0u8; 32],
+            result: None,
+            timed_out: false,
+        }
+    }
+}
+
+/// Submit every request, then collect completions for up to `timeout` milliseconds
+fn submit_and_collect(requests: &mut [Option<Request>], timeout: u32) {
+    let mut pending = Vec::new();
+
+    for (pack_id, request) in requests.iter_mut().enumerate() {
+        let request = match request {
+            Some(request) => request,
+            None => continue,
+        };
+
+        match submit(request, pack_id as i32) {
+            Ok(()) => pending.push(pack_id),
+            Err(e) => request.result = Some(Err(e)),
+        }
+    }
+
+    while !pending.is_empty() {
+        let mut fds: Vec<pollfd> = pending
+            .iter()
+            .map(|&pack_id| pollfd {
+                fd: requests[pack_id].as_ref().unwrap().file.as_raw_fd(),
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect();
+
+        let r = unsafe { poll(fds.as_mut_ptr(), fds.len() as _, timeout as i32) };
+        if r == 0 {
+            for &pack_id in &pending {
+                requests[pack_id].as_mut().unwrap().timed_out = true;
+            }
+            break;
+        }
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+
+            // poll failure, leave remaining requests to the blocking path
+            break;
+        }
+
+        let mut done = Vec::new();
+        for (fd, &pack_id) in fds.iter().zip(&pending) {
+            if fd.revents == 0 {
+                continue;
+            }
+
+            let request = requests[pack_id].as_mut().unwrap();
+            request.result = Some(complete(request, pack_id as i32));
+            done.push(pack_id);
+        }
+
+        pending.retain(|it| !done.contains(it));
+    }
+}
+
+fn submit(request: &mut Request, pack_id: i32) -> Result<()> {
+    let hdr = SgIoHdr {
+        dxfer_direction: SG_DXFER_NONE,
+        cmd_len: request.cdb.len() as u8,
+        cmdp: request.cdb.as_mut_ptr(),
+        mx_sb_len: request.sense.len() as u8,
+        sbp: request.sense.as_mut_ptr(),
+        timeout: TIMEOUT,
+        pack_id,
+        ..Default::default()
+    };
+
+    // the driver keeps cdb and sense pointers, `request` must not move until read back
+    let r = unsafe {
+        write(
+            request.file.as_raw_fd(),
+            &hdr as *const SgIoHdr as *const _,
+            size_of::<SgIoHdr>(),
+        )
+    };
+    anyhow::ensure!(
+        r == size_of::<SgIoHdr>() as isize,
+        "sg write failed: {}",
+        std::io::Error::last_os_error()
+    );
+
+    Ok(())
+}
+
+fn complete(request: &mut Request, pack_id: i32) -> Result<PowerMode> {
+    let mut hdr = SgIoHdr::default();
+    let r = unsafe {
+        read(
+            request.file.as_raw_fd(),
+            &mut hdr as *mut SgIoHdr as *mut _,
+            size_of::<SgIoHdr>(),
+        )
+    };
+    anyhow::ensure!(
+        r == size_of::<SgIoHdr>() as isize,
+        "sg read failed: {}",
+        std::io::Error::last_os_error()
+    );
+    anyhow::ensure!(
+        hdr.pack_id == pack_id,
+        "unexpected completion {}, expect {}",
+        hdr.pack_id,
+        pack_id
+    );
+
+    let sense = parse_sense(&request.sense).context("CHECK POWER MODE failed")?;
+
+    Ok(PowerMode::from_sector_count(sense.sector_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::io::FromRawFd;
+
+    use super::*;
+    use crate::fake::ata_return;
+
+    /// Request on one end of a socket pair, the other end plays the sg driver
+    fn socket_request() -> (Request, File) {
+        let mut fds = [0; 2];
+        let r =
+            unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(r, 0);
+        let (ours, driver) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        (Request::new(ours), driver)
+    }
+
+    /// Read a submitted header, as the sg driver does on write
+    fn receive(driver: &mut File) -> SgIoHdr {
+        let mut raw = [0u8; size_of::<SgIoHdr>()];
+        assert_eq!(driver.read(&mut raw).unwrap(), raw.len());
+
+        unsafe { std::ptr::read_unaligned(raw.as_ptr() as *const SgIoHdr) }
+    }
+
+    /// Complete `hdr` with `sense`, written where the request asked for it
+    fn answer(driver: &mut File, hdr: SgIoHdr, sense: [u8; 32]) {
+        unsafe { std::ptr::copy_nonoverlapping(sense.as_ptr(), hdr.sbp, sense.len()) };
+        let raw = unsafe {
+            std::slice::from_raw_parts(&hdr as *const SgIoHdr as *const u8, size_of::<SgIoHdr>())
+        };
+        driver.write_all(raw).unwrap();
+    }
+
+    #[test]
+    fn completions_out_of_order() {
+        let (requests, mut drivers): (Vec<_>, Vec<_>) = (0..4).map(|_| socket_request()).unzip();
+        let mut requests: Vec<Option<Request>> = requests.into_iter().map(Some).collect();
+        requests.insert(1, None);
+
+        let driver = std::thread::spawn(move || {
+            let headers: Vec<SgIoHdr> = drivers.iter_mut().map(receive).collect();
+            for hdr in &headers {
+                let cdb = unsafe { std::slice::from_raw_parts(hdr.cmdp, hdr.cmd_len as usize) };
+                // CHECK POWER MODE
+                assert_eq!((cdb[0], cdb[14]), (0x85, 0xe5));
+            }
+
+            // later submitted complete first, the last drive never answers
+            answer(&mut drivers[2], headers[2], ata_return(0x82, false));
+            answer(&mut drivers[1], headers[1], [0; 32]);
+            answer(&mut drivers[0], headers[0], ata_return(0x00, false));
+
+            let pack_ids: Vec<i32> = headers.iter().map(|it| it.pack_id).collect();
+            (drivers, pack_ids)
+        });
+
+        submit_and_collect(&mut requests, 200);
+        // drivers are kept open until now, a closed one would complete with an error
+        let (_drivers, pack_ids) = driver.join().unwrap();
+        assert_eq!(pack_ids, [0, 2, 3, 4]);
+
+        let request = |n: usize| requests[n].as_ref().unwrap();
+        assert_eq!(
+            request(0).result.as_ref().unwrap().as_ref().unwrap(),
+            &PowerMode::StandbyZ
+        );
+        assert!(requests[1].is_none());
+        // no ATA registers in sense, the blocking path tries other pass-throughs
+        let e = request(2).result.as_ref().unwrap().as_ref().unwrap_err();
+        assert_eq!(e.to_string(), "CHECK POWER MODE failed");
+        assert!(!request(2).timed_out);
+        assert_eq!(
+            request(3).result.as_ref().unwrap().as_ref().unwrap(),
+            &PowerMode::IdleB
+        );
+
+        assert!(request(4).result.is_none());
+        assert!(request(4).timed_out);
+    }
+}
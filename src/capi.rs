@@ -0,0 +1,530 @@
+//! C ABI over [`Device`], the header is `include/wdepc.h`
+//!
+//! Functions return [`WdepcStatus`], the message of the last error on the calling thread is
+//! returned by [`wdepc_last_error`].
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use anyhow::Result;
+
+use crate::device::{
+    Device, DeviceType, EPCSetting, PowerCondDescriptor, PowerCondition, PowerMode,
+};
+#[cfg(test)]
+use crate::{device::PassthroughType, transport::Transport};
+
+/// Opaque device handle
+pub struct WdepcDevice {
+    device: Device,
+}
+
+impl WdepcDevice {
+    /// Handle over any SCSI transport, eg a fake one answering commands in process,
+    /// release it with `wdepc_close`
+    #[cfg(test)]
+    pub(crate) fn with_transport(
+        transport: Box<dyn Transport>,
+        passthrough: PassthroughType,
+    ) -> Result<*mut WdepcDevice> {
+        let device = Device::with_transport(transport, passthrough)?;
+
+        Ok(Box::into_raw(Box::new(WdepcDevice { device })))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WdepcStatus {
+    Ok = 0,
+    /// null pointer, invalid string or power mode
+    InvalidArgument = -1,
+    /// device can not be opened
+    Open = -2,
+    /// command failed or was rejected by the drive
+    Command = -3,
+    /// internal error
+    Panic = -4,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WdepcPowerMode {
+    Active = 0,
+    IdleA = 1,
+    IdleB = 2,
+    IdleC = 3,
+    StandbyY = 4,
+    StandbyZ = 5,
+    Unknown = 6,
+    /// every supported condition, for set timer, set state and restore, never queried
+    All = 7,
+}
+
+/// Power condition, times are in 100 milliseconds
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct WdepcPowerCond {
+    pub supported: bool,
+    pub savable: bool,
+    pub changeable: bool,
+
+    pub default_enable: bool,
+    pub saved_enable: bool,
+    pub current_enable: bool,
+
+    pub default_timer: u32,
+    pub saved_timer: u32,
+    pub current_timer: u32,
+
+    pub recovery_time: u32,
+
+    pub min_timer: u32,
+    pub max_timer: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct WdepcEpcSetting {
+    pub idle_a: WdepcPowerCond,
+    pub idle_b: WdepcPowerCond,
+    pub idle_c: WdepcPowerCond,
+
+    pub standby_y: WdepcPowerCond,
+    pub standby_z: WdepcPowerCond,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+impl From<PowerMode> for WdepcPowerMode {
+    fn from(mode: PowerMode) -> Self {
+        match mode {
+            PowerMode::Active => WdepcPowerMode::Active,
+            PowerMode::IdleA => WdepcPowerMode::IdleA,
+            PowerMode::IdleB => WdepcPowerMode::IdleB,
+            PowerMode::IdleC => WdepcPowerMode::IdleC,
+            PowerMode::StandbyY => WdepcPowerMode::StandbyY,
+            PowerMode::StandbyZ => WdepcPowerMode::StandbyZ,
+            PowerMode::Unknown => WdepcPowerMode::Unknown,
+        }
+    }
+}
+
+impl From<PowerCondDescriptor> for WdepcPowerCond {
+    fn from(desc: PowerCondDescriptor) -> Self {
+        WdepcPowerCond {
+            supported: desc.supported,
+            savable: desc.savable,
+            changeable: desc.changeable,
+            default_enable: desc.default_enable,
+            saved_enable: desc.saved_enable,
+            current_enable: desc.current_enable,
+            default_timer: desc.default_timer,
+            saved_timer: desc.saved_timer,
+            current_timer: desc.current_timer,
+            recovery_time: desc.recovery_time,
+            min_timer: desc.min_timer,
+            max_timer: desc.max_timer,
+        }
+    }
+}
+
+impl From<EPCSetting> for WdepcEpcSetting {
+    fn from(setting: EPCSetting) -> Self {
+        WdepcEpcSetting {
+            idle_a: setting.idle_a.into(),
+            idle_b: setting.idle_b.into(),
+            idle_c: setting.idle_c.into(),
+            standby_y: setting.standby_y.into(),
+            standby_z: setting.standby_z.into(),
+        }
+    }
+}
+
+/// Power condition to set, active and unknown are not settable
+fn settable_mode(mode: c_int) -> Result<PowerCondition> {
+    match mode {
+        1 => Ok(PowerCondition::IdleA),
+        2 => Ok(PowerCondition::IdleB),
+        3 => Ok(PowerCondition::IdleC),
+        4 => Ok(PowerCondition::StandbyY),
+        5 => Ok(PowerCondition::StandbyZ),
+        7 => Ok(PowerCondition::All),
+        _ => anyhow::bail!("invalid power mode {}", mode),
+    }
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|it| *it.borrow_mut() = Some(message));
+}
+
+/// Run `f`, record its error and map it to `error`, panics are caught
+fn call(error: WdepcStatus, f: impl FnOnce() -> Result<()>) -> WdepcStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => WdepcStatus::Ok,
+        Ok(Err(e)) => {
+            set_last_error(format!("{:#}", e));
+            error
+        }
+        Err(_) => {
+            set_last_error("panic in wdepc".to_string());
+            WdepcStatus::Panic
+        }
+    }
+}
+
+/// Run `f` on the device behind `device`
+unsafe fn with_device(
+    device: *mut WdepcDevice,
+    f: impl FnOnce(&mut Device) -> Result<()>,
+) -> WdepcStatus {
+    if device.is_null() {
+        set_last_error("device is null".to_string());
+        return WdepcStatus::InvalidArgument;
+    }
+    let device = &mut (*device).device;
+
+    call(WdepcStatus::Command, || f(device))
+}
+
+/// Mode parameter of setters, mapped before reaching the device
+fn with_mode(mode: c_int, f: impl FnOnce(PowerCondition) -> WdepcStatus) -> WdepcStatus {
+    match settable_mode(mode) {
+        Ok(mode) => f(mode),
+        Err(e) => {
+            set_last_error(e.to_string());
+            WdepcStatus::InvalidArgument
+        }
+    }
+}
+
+unsafe fn c_str<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    anyhow::ensure!(!s.is_null(), "{} is null", name);
+
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| anyhow::anyhow!("{} is not UTF-8", name))
+}
+
+/// Open `path`, `device_type` is as `--type`, eg `sat,12`, null probes pass-through
+///
+/// On success `*out` holds the handle, release it with `wdepc_close`
+///
+/// # Safety
+/// `path` and `device_type` are null or NUL terminated strings, `out` is writable
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_open(
+    path: *const c_char,
+    device_type: *const c_char,
+    out: *mut *mut WdepcDevice,
+) -> WdepcStatus {
+    let args = (|| -> Result<(&str, DeviceType)> {
+        anyhow::ensure!(!out.is_null(), "out is null");
+        let path = c_str(path, "path")?;
+        let ty = if device_type.is_null() {
+            "auto"
+        } else {
+            c_str(device_type, "device_type")?
+        };
+        Ok((path, ty.parse()?))
+    })();
+    let (path, ty) = match args {
+        Ok(it) => it,
+        Err(e) => {
+            set_last_error(format!("{:#}", e));
+            return WdepcStatus::InvalidArgument;
+        }
+    };
+
+    call(WdepcStatus::Open, || {
+        let device = Device::open_with_type(path, ty)?;
+        *out = Box::into_raw(Box::new(WdepcDevice { device }));
+        Ok(())
+    })
+}
+
+/// Close device, null is ignored
+///
+/// # Safety
+/// `device` is null or returned by `wdepc_open` and not closed yet
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_close(device: *mut WdepcDevice) {
+    if !device.is_null() {
+        drop(Box::from_raw(device));
+    }
+}
+
+/// Message of the last error on this thread, null if none
+///
+/// The string is valid until the next call on this thread
+#[no_mangle]
+pub extern "C" fn wdepc_last_error() -> *const c_char {
+    LAST_ERROR.with(|it| {
+        it.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |it| it.as_ptr())
+    })
+}
+
+/// Query current power mode into `*out`
+///
+/// # Safety
+/// `device` is an open handle, `out` is writable
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_query_mode(
+    device: *mut WdepcDevice,
+    out: *mut WdepcPowerMode,
+) -> WdepcStatus {
+    if out.is_null() {
+        set_last_error("out is null".to_string());
+        return WdepcStatus::InvalidArgument;
+    }
+
+    with_device(device, |device| {
+        *out = device.query_mode()?.into();
+        Ok(())
+    })
+}
+
+/// Query EPC settings into `*out`
+///
+/// # Safety
+/// `device` is an open handle, `out` is writable
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_query_epc_setting(
+    device: *mut WdepcDevice,
+    out: *mut WdepcEpcSetting,
+) -> WdepcStatus {
+    if out.is_null() {
+        set_last_error("out is null".to_string());
+        return WdepcStatus::InvalidArgument;
+    }
+
+    with_device(device, |device| {
+        *out = device.query_epc_setting()?.into();
+        Ok(())
+    })
+}
+
+/// Set timer of power condition `mode`, a `WdepcPowerMode` from idle a to standby z, or all
+///
+/// # Safety
+/// `device` is an open handle
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_set_timer(
+    device: *mut WdepcDevice,
+    mode: c_int,
+    timer: u16,
+    enable: bool,
+    save: bool,
+) -> WdepcStatus {
+    with_mode(mode, |mode| {
+        with_device(device, |device| device.set_timer(mode, timer, enable, save))
+    })
+}
+
+/// Enable or disable power condition `mode`
+///
+/// # Safety
+/// `device` is an open handle
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_set_state(
+    device: *mut WdepcDevice,
+    mode: c_int,
+    enable: bool,
+    save: bool,
+) -> WdepcStatus {
+    with_mode(mode, |mode| {
+        with_device(device, |device| device.set_state(mode, enable, save))
+    })
+}
+
+/// Force drive to power condition `mode`, all is rejected
+///
+/// # Safety
+/// `device` is an open handle
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_goto_cond(device: *mut WdepcDevice, mode: c_int) -> WdepcStatus {
+    with_mode(mode, |mode| {
+        with_device(device, |device| device.goto_cond(mode))
+    })
+}
+
+/// Enable EPC, this disables APM
+///
+/// # Safety
+/// `device` is an open handle
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_enable(device: *mut WdepcDevice) -> WdepcStatus {
+    with_device(device, |device| device.enable_epc())
+}
+
+/// Disable EPC, APM is not enabled
+///
+/// # Safety
+/// `device` is an open handle
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_disable(device: *mut WdepcDevice) -> WdepcStatus {
+    with_device(device, |device| device.disable_epc())
+}
+
+/// Restore power condition `mode` from default if `use_default`, else from saved
+///
+/// # Safety
+/// `device` is an open handle
+#[no_mangle]
+pub unsafe extern "C" fn wdepc_restore(
+    device: *mut WdepcDevice,
+    mode: c_int,
+    use_default: bool,
+    save: bool,
+) -> WdepcStatus {
+    with_mode(mode, |mode| {
+        with_device(device, |device| device.restore(mode, use_default, save))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::{null, null_mut};
+
+    use super::*;
+    use crate::fake::FakeDrive;
+
+    fn last_error() -> String {
+        let message = wdepc_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn open_errors() {
+        let mut device = null_mut();
+
+        let status = unsafe { wdepc_open(null(), null(), &mut device) };
+        assert_eq!(status, WdepcStatus::InvalidArgument);
+        assert_eq!(last_error(), "path is null");
+
+        let path = CString::new("/dev/wdepc-nonexistent").unwrap();
+        let ty = CString::new("sat,13").unwrap();
+        let status = unsafe { wdepc_open(path.as_ptr(), ty.as_ptr(), &mut device) };
+        assert_eq!(status, WdepcStatus::InvalidArgument);
+        assert_eq!(last_error(), "unknown device type sat,13");
+
+        let status = unsafe { wdepc_open(path.as_ptr(), null(), &mut device) };
+        assert_eq!(status, WdepcStatus::Open);
+        assert!(last_error().starts_with("open /dev/wdepc-nonexistent failed"));
+        assert!(device.is_null());
+    }
+
+    #[test]
+    fn query_round_trip() {
+        let drive = FakeDrive::new();
+        drive.state().mode = PowerMode::IdleA;
+        let device =
+            WdepcDevice::with_transport(Box::new(drive.clone()), PassthroughType::Auto).unwrap();
+
+        let mut mode = WdepcPowerMode::Unknown;
+        assert_eq!(
+            unsafe { wdepc_query_mode(device, &mut mode) },
+            WdepcStatus::Ok
+        );
+        assert_eq!(mode, WdepcPowerMode::IdleA);
+
+        let mut setting = WdepcEpcSetting::default();
+        assert_eq!(
+            unsafe { wdepc_query_epc_setting(device, &mut setting) },
+            WdepcStatus::Ok
+        );
+        assert!(setting.standby_z.supported);
+        assert_eq!(setting.standby_z.current_timer, 9000);
+        assert!(!setting.idle_b.current_enable);
+
+        assert_eq!(
+            unsafe { wdepc_query_mode(device, null_mut()) },
+            WdepcStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "out is null");
+
+        unsafe { wdepc_close(device) };
+    }
+
+    #[test]
+    fn set_timer_round_trip() {
+        let drive = FakeDrive::new();
+        let device =
+            WdepcDevice::with_transport(Box::new(drive.clone()), PassthroughType::Sat16).unwrap();
+
+        let status =
+            unsafe { wdepc_set_timer(device, WdepcPowerMode::IdleB as c_int, 1200, true, false) };
+        assert_eq!(status, WdepcStatus::Ok);
+
+        let mut setting = WdepcEpcSetting::default();
+        unsafe { wdepc_query_epc_setting(device, &mut setting) };
+        assert_eq!(setting.idle_b.current_timer, 1200);
+        assert!(setting.idle_b.current_enable);
+        assert!(!setting.idle_b.saved_enable);
+
+        let status =
+            unsafe { wdepc_set_timer(device, WdepcPowerMode::Active as c_int, 1, true, false) };
+        assert_eq!(status, WdepcStatus::InvalidArgument);
+        assert_eq!(last_error(), "invalid power mode 0");
+
+        drive.state().failing_set_features = Some(1);
+        let status = unsafe { wdepc_enable(device) };
+        assert_eq!(status, WdepcStatus::Command);
+        assert_eq!(
+            last_error(),
+            "command 0xef aborted, ATA status 0x51, error 0x04"
+        );
+
+        unsafe { wdepc_close(device) };
+    }
+
+    #[test]
+    fn all_conditions() {
+        let drive = FakeDrive::new();
+        let device =
+            WdepcDevice::with_transport(Box::new(drive.clone()), PassthroughType::Sat16).unwrap();
+
+        let all = WdepcPowerMode::All as c_int;
+        assert_eq!(
+            unsafe { wdepc_set_timer(device, all, 300, true, false) },
+            WdepcStatus::Ok
+        );
+        assert_eq!(drive.state().set_features()[0].sector_count, 0xff);
+        assert!(drive
+            .state()
+            .conditions
+            .iter()
+            .all(|it| it.current_timer == 300));
+
+        // the drive is never in all conditions at once
+        assert_eq!(
+            unsafe { wdepc_goto_cond(device, all) },
+            WdepcStatus::Command
+        );
+
+        unsafe { wdepc_close(device) };
+    }
+
+    #[test]
+    fn null_device() {
+        assert_eq!(
+            unsafe { wdepc_disable(null_mut()) },
+            WdepcStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "device is null");
+
+        // closing null is a no-op
+        unsafe { wdepc_close(null_mut()) };
+    }
+}
@@ -0,0 +1,320 @@
+//! Changes applied together, each verified by reading the drive back
+//!
+//! ```no_run
+//! use wdepc::changeset::ChangeSet;
+//! use wdepc::profile::Change;
+//! use wdepc::{Device, PowerMode};
+//!
+//! let mut device = Device::open("/dev/sda")?;
+//! let changes = vec![
+//!     Change::SetTimer { mode: PowerMode::IdleB, timer: 1200, enable: true, save: true },
+//!     Change::SetTimer { mode: PowerMode::StandbyZ, timer: 9000, enable: true, save: true },
+//! ];
+//! // on error the drive is back to where it was
+//! ChangeSet::new(changes).apply(&mut device)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use anyhow::{Context, Result};
+
+use crate::backup::Backup;
+use crate::device::{Device, Identify};
+use crate::diff::{self, State};
+use crate::profile::Change;
+
+pub struct ChangeSet {
+    pub changes: Vec<Change>,
+}
+
+impl ChangeSet {
+    pub fn new(changes: Vec<Change>) -> ChangeSet {
+        ChangeSet { changes }
+    }
+
+    /// Apply changes in order, each is verified by reading back IDENTIFY DEVICE or the
+    /// Power Conditions log
+    ///
+    /// If a change fails or doesn't verify, the settings read before the first change are
+    /// restored, saved ones too if any change saves.
+    pub fn apply(&self, device: &mut Device) -> Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+
+        let identify = device.identify()?;
+        let snapshot = if identify.epc_supported {
+            Some(Backup::take(device)?)
+        } else {
+            None
+        };
+
+        let result = self.changes.iter().try_for_each(|change| {
+            change
+                .apply(device)
+                .with_context(|| format!("{} failed", change))?;
+            verify(device, change).with_context(|| format!("{} didn't verify", change))
+        });
+        let e = match result {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        match self.rollback(device, &identify, snapshot.as_ref()) {
+            Ok(()) => Err(e.context("changes were rolled back")),
+            Err(rollback) => Err(e.context(format!("rollback failed: {:#}", rollback))),
+        }
+    }
+
+    /// Restore settings read before the first change
+    fn rollback(
+        &self,
+        device: &mut Device,
+        identify: &Identify,
+        snapshot: Option<&Backup>,
+    ) -> Result<()> {
+        // enabling EPC disables APM, so EPC changes touch APM too
+        let apm = identify.apm_supported
+            && self.changes.iter().any(|it| {
+                matches!(
+                    it,
+                    Change::SetApm(_) | Change::EnableEpc | Change::DisableEpc
+                )
+            });
+
+        if let Some(snapshot) = snapshot {
+            self.rollback_epc(device, snapshot)?;
+        }
+        // after EPC, as enabling it again would disable APM
+        if apm {
+            device.set_apm(identify.apm_level)?;
+            let current = device.identify()?.apm_level;
+            anyhow::ensure!(
+                current == identify.apm_level,
+                "APM level is {:?} instead of {:?}",
+                current,
+                identify.apm_level
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Restore EPC state and power conditions of `snapshot`
+    fn rollback_epc(&self, device: &mut Device, snapshot: &Backup) -> Result<()> {
+        let save = self.changes.iter().any(|it| match *it {
+            Change::SetTimer { save, .. } | Change::SetState { save, .. } => save,
+            _ => false,
+        });
+
+        let current = device.identify()?;
+        let setting = device.query_epc_setting()?;
+        for change in snapshot.plan(&current, &setting, save)? {
+            change
+                .apply(device)
+                .with_context(|| format!("{} failed", change))?;
+        }
+
+        let current = device.identify()?;
+        let setting = device.query_epc_setting()?;
+        let differences = diff::diff(
+            &State::of(snapshot.epc_enabled, &snapshot.setting),
+            &State::of(current.epc_enabled, &setting),
+        );
+        if let Some(difference) = differences.first() {
+            anyhow::bail!(
+                "{} {} is {} instead of {}",
+                difference
+                    .condition
+                    .map_or("EPC".to_string(), |it| it.to_string()),
+                difference.setting,
+                difference.right,
+                difference.left
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the drive reports what `change` set
+fn verify(device: &Device, change: &Change) -> Result<()> {
+    match *change {
+        Change::EnableEpc | Change::DisableEpc => {
+            let enabled = device.identify()?.epc_enabled;
+            anyhow::ensure!(
+                enabled == (*change == Change::EnableEpc),
+                "EPC is {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+        Change::SetApm(level) => {
+            let current = device.identify()?.apm_level;
+            anyhow::ensure!(
+                current == level,
+                "APM level is {:?} instead of {:?}",
+                current,
+                level
+            );
+        }
+        Change::SetTimer {
+            mode,
+            timer,
+            enable,
+            save,
+        } => {
+            let setting = device.query_epc_setting()?;
+            let desc = setting.condition(mode).unwrap();
+            let timer = timer as u32;
+            anyhow::ensure!(
+                (desc.current_timer, desc.current_enable) == (timer, enable),
+                "current timer is {}, enabled {}",
+                desc.current_timer,
+                desc.current_enable
+            );
+            anyhow::ensure!(
+                !save || (desc.saved_timer, desc.saved_enable) == (timer, enable),
+                "saved timer is {}, enabled {}",
+                desc.saved_timer,
+                desc.saved_enable
+            );
+        }
+        Change::SetState { mode, enable, save } => {
+            let setting = device.query_epc_setting()?;
+            let desc = setting.condition(mode).unwrap();
+            anyhow::ensure!(
+                desc.current_enable == enable,
+                "current enabled is {}",
+                desc.current_enable
+            );
+            anyhow::ensure!(
+                !save || desc.saved_enable == enable,
+                "saved enabled is {}",
+                desc.saved_enable
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::PowerMode;
+    use crate::fake::FakeDrive;
+
+    fn set_timer(mode: PowerMode, timer: u16, save: bool) -> Change {
+        Change::SetTimer {
+            mode,
+            timer,
+            enable: true,
+            save,
+        }
+    }
+
+    #[test]
+    fn applies_and_verifies() {
+        let drive = FakeDrive::new();
+        let changes = vec![
+            set_timer(PowerMode::IdleB, 1200, true),
+            set_timer(PowerMode::StandbyZ, 18000, false),
+        ];
+
+        ChangeSet::new(changes).apply(&mut drive.device()).unwrap();
+
+        let mut state = drive.state();
+        let idle_b = *state.condition(PowerMode::IdleB);
+        assert!(idle_b.current_enable && idle_b.saved_enable);
+        let standby_z = *state.condition(PowerMode::StandbyZ);
+        assert_eq!(
+            (standby_z.current_timer, standby_z.saved_timer),
+            (18000, 9000)
+        );
+    }
+
+    #[test]
+    fn verify_mismatch_rolls_back() {
+        let drive = FakeDrive::new();
+        // set state takes effect, set timer is accepted but ignored
+        drive.state().ignored_subcommand = Some(0x02);
+        let before = drive.state().conditions;
+        let changes = vec![
+            Change::SetState {
+                mode: PowerMode::IdleC,
+                enable: true,
+                save: true,
+            },
+            set_timer(PowerMode::StandbyZ, 18000, false),
+        ];
+
+        let e = ChangeSet::new(changes)
+            .apply(&mut drive.device())
+            .unwrap_err();
+        let message = format!("{:#}", e);
+        assert!(
+            message.starts_with("changes were rolled back"),
+            "{}",
+            message
+        );
+        assert!(message.contains("didn't verify"), "{}", message);
+
+        let state = drive.state();
+        for (before, after) in before.iter().zip(state.conditions.iter()) {
+            assert_eq!(
+                (before.current_enable, before.saved_enable),
+                (after.current_enable, after.saved_enable)
+            );
+        }
+    }
+
+    #[test]
+    fn rollback_after_partial_apply() {
+        let drive = FakeDrive::new();
+        // the second change fails, after the first took effect
+        drive.state().failing_set_features = Some(1);
+        let changes = vec![
+            set_timer(PowerMode::IdleA, 50, true),
+            set_timer(PowerMode::IdleB, 600, true),
+            set_timer(PowerMode::StandbyZ, 18000, true),
+        ];
+
+        let e = ChangeSet::new(changes)
+            .apply(&mut drive.device())
+            .unwrap_err();
+        assert!(format!("{:#}", e).contains("failed"));
+
+        let mut state = drive.state();
+        let idle_a = *state.condition(PowerMode::IdleA);
+        assert_eq!((idle_a.current_timer, idle_a.saved_timer), (20, 20));
+        let idle_b = *state.condition(PowerMode::IdleB);
+        assert!(!idle_b.current_enable && !idle_b.saved_enable);
+        // never reached
+        let standby_z = *state.condition(PowerMode::StandbyZ);
+        assert_eq!(standby_z.current_timer, 9000);
+    }
+
+    #[test]
+    fn rollback_restores_apm_disabled_by_enabling_epc() {
+        let drive = FakeDrive::new();
+        {
+            let mut state = drive.state();
+            state.epc_enabled = false;
+            state.apm_level = Some(128);
+            // enable EPC, then the set timer fails
+            state.failing_set_features = Some(1);
+        }
+        let changes = vec![
+            Change::EnableEpc,
+            set_timer(PowerMode::StandbyZ, 18000, true),
+        ];
+
+        let e = ChangeSet::new(changes)
+            .apply(&mut drive.device())
+            .unwrap_err();
+        assert!(format!("{:#}", e).starts_with("changes were rolled back"));
+
+        let state = drive.state();
+        assert!(!state.epc_enabled);
+        assert_eq!(state.apm_level, Some(128));
+    }
+}
@@ -1,20 +1,103 @@
-use std::convert::TryInto;
-use std::ffi::CString;
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::Path;
+use std::str::FromStr;
 
 use anyhow::{Context, Result};
-use libc::c_int;
-use libc::{ioctl, open, O_RDONLY};
+use libc::{c_int, O_RDONLY};
+use once_cell::sync::OnceCell;
 
 use crate::ffi::{
-    build_ata_passthrough12, build_ata_passthrough16, AtaCmd, Protocol, SgIoHdr, SG_DXFER_FROM_DEV,
-    SG_DXFER_NONE, SG_DXFER_TO_DEV, SG_IO,
+    build_cypress, build_cypress_registers, build_jmicron, build_jmicron_registers, build_sunplus,
+    build_sunplus_registers, AtaCmd, AtaTaskfile, Protocol, CYPRESS_REGISTERS_LEN,
+    JMICRON_REGISTERS_LEN, SUNPLUS_REGISTERS_LEN,
 };
+use crate::transport::{open_fd, MegaRaid, ScsiStatus, SgIo, SgIoV4, Transport, TransportType};
 
 pub struct Device {
-    fd: c_int,
+    transport: Box<dyn Transport>,
+    passthrough: PassthroughType,
+    /// General Purpose Log directory, read once per device
+    general_log: OnceCell<[u8; 512]>,
+    /// Commands sent that saved settings to non-volatile memory
+    saves: u32,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// How ATA commands are wrapped into SCSI commands
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PassthroughType {
+    /// Probe a working variant when opening device
+    Auto,
+    /// SAT ATA PASS-THROUGH(12)
+    Sat12,
+    /// SAT ATA PASS-THROUGH(16)
+    Sat16,
+    /// JMicron USB bridge vendor command
+    JMicron,
+    /// Sunplus USB bridge vendor command
+    Sunplus,
+    /// Cypress USB bridge ATACB
+    Cypress,
+}
+
+impl FromStr for PassthroughType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(PassthroughType::Auto),
+            "sat" | "sat,16" => Ok(PassthroughType::Sat16),
+            "sat,12" => Ok(PassthroughType::Sat12),
+            "jmicron" => Ok(PassthroughType::JMicron),
+            "sunplus" => Ok(PassthroughType::Sunplus),
+            "cypress" => Ok(PassthroughType::Cypress),
+            _ => anyhow::bail!("unknown device type {}", s),
+        }
+    }
+}
+
+/// Pass-through variant and transport, in `smartctl -d` syntax
+///
+/// eg `sat,12`, `megaraid,3`, `sat+megaraid,3` or `sat+sgv4`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceType {
+    pub passthrough: PassthroughType,
+    pub transport: TransportType,
+}
+
+impl FromStr for DeviceType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (passthrough, transport) = match s.rsplit_once('+') {
+            Some((passthrough, transport)) => (passthrough, transport),
+            None if s.starts_with("megaraid,") || s == "sgv4" => ("auto", s),
+            None => (s, ""),
+        };
+
+        let transport = match transport {
+            "" => TransportType::SgIo,
+            "sgv4" => TransportType::SgIoV4,
+            transport => {
+                let id = transport
+                    .strip_prefix("megaraid,")
+                    .and_then(|it| it.parse().ok())
+                    .with_context(|| format!("unknown transport {}", transport))?;
+                TransportType::MegaRaid(id)
+            }
+        };
+
+        Ok(DeviceType {
+            passthrough: passthrough.parse()?,
+            transport,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum PowerMode {
     Active,
     IdleA,
@@ -26,20 +109,140 @@ pub enum PowerMode {
 }
 
 impl PowerMode {
+    /// Power conditions with a timer, from idle a to standby z
+    pub const CONDITIONS: [PowerMode; 5] = [
+        PowerMode::IdleA,
+        PowerMode::IdleB,
+        PowerMode::IdleC,
+        PowerMode::StandbyY,
+        PowerMode::StandbyZ,
+    ];
+
+    /// Power mode reported by CHECK POWER MODE in sector count
+    pub fn from_sector_count(sector_count: u16) -> PowerMode {
+        match sector_count {
+            0xff => PowerMode::Active,
+            0x81 => PowerMode::IdleA,
+            0x82 => PowerMode::IdleB,
+            0x83 => PowerMode::IdleC,
+            0x01 => PowerMode::StandbyY,
+            0x00 => PowerMode::StandbyZ,
+            _ => PowerMode::Unknown,
+        }
+    }
+}
+
+/// Snake case name as on the command line, eg `idle_a`
+impl FromStr for PowerMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "active" => Ok(PowerMode::Active),
+            "idle_a" => Ok(PowerMode::IdleA),
+            "idle_b" => Ok(PowerMode::IdleB),
+            "idle_c" => Ok(PowerMode::IdleC),
+            "standby_y" => Ok(PowerMode::StandbyY),
+            "standby_z" => Ok(PowerMode::StandbyZ),
+            _ => anyhow::bail!("unknown power mode {}", s),
+        }
+    }
+}
+
+impl std::fmt::Display for PowerMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PowerMode::Active => "active",
+            PowerMode::IdleA => "idle_a",
+            PowerMode::IdleB => "idle_b",
+            PowerMode::IdleC => "idle_c",
+            PowerMode::StandbyY => "standby_y",
+            PowerMode::StandbyZ => "standby_z",
+            PowerMode::Unknown => "unknown",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Power condition targeted by EPC commands, `All` targets every supported one at once
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PowerCondition {
+    IdleA,
+    IdleB,
+    IdleC,
+    StandbyY,
+    StandbyZ,
+    All,
+}
+
+impl PowerCondition {
+    /// Power condition ID of EPC subcommands
     pub fn id(&self) -> u8 {
         match self {
-            PowerMode::Active => 0x81,
-            PowerMode::IdleA => 0x81,
-            PowerMode::IdleB => 0x82,
-            PowerMode::IdleC => 0x83,
-            PowerMode::StandbyY => 0x01,
-            PowerMode::StandbyZ => 0x00,
-            PowerMode::Unknown => panic!("unknown power mode"),
+            PowerCondition::IdleA => 0x81,
+            PowerCondition::IdleB => 0x82,
+            PowerCondition::IdleC => 0x83,
+            PowerCondition::StandbyY => 0x01,
+            PowerCondition::StandbyZ => 0x00,
+            PowerCondition::All => 0xff,
+        }
+    }
+
+    /// Power mode the drive is in under this condition, None for `All`
+    pub fn mode(&self) -> Option<PowerMode> {
+        match self {
+            PowerCondition::IdleA => Some(PowerMode::IdleA),
+            PowerCondition::IdleB => Some(PowerMode::IdleB),
+            PowerCondition::IdleC => Some(PowerMode::IdleC),
+            PowerCondition::StandbyY => Some(PowerMode::StandbyY),
+            PowerCondition::StandbyZ => Some(PowerMode::StandbyZ),
+            PowerCondition::All => None,
+        }
+    }
+}
+
+/// Active and unknown are not power conditions
+impl TryFrom<PowerMode> for PowerCondition {
+    type Error = anyhow::Error;
+
+    fn try_from(mode: PowerMode) -> Result<Self> {
+        match mode {
+            PowerMode::IdleA => Ok(PowerCondition::IdleA),
+            PowerMode::IdleB => Ok(PowerCondition::IdleB),
+            PowerMode::IdleC => Ok(PowerCondition::IdleC),
+            PowerMode::StandbyY => Ok(PowerCondition::StandbyY),
+            PowerMode::StandbyZ => Ok(PowerCondition::StandbyZ),
+            PowerMode::Active | PowerMode::Unknown => {
+                anyhow::bail!("{} is not a power condition", mode)
+            }
+        }
+    }
+}
+
+/// Snake case name as on the command line, eg `idle_a` or `all`
+impl FromStr for PowerCondition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(PowerCondition::All),
+            _ => s.parse::<PowerMode>()?.try_into(),
+        }
+    }
+}
+
+impl std::fmt::Display for PowerCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.mode() {
+            Some(mode) => write!(f, "{}", mode),
+            None => write!(f, "all"),
         }
     }
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PowerCondDescriptor {
     pub supported: bool,
     pub savable: bool,
@@ -60,6 +263,7 @@ pub struct PowerCondDescriptor {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EPCSetting {
     pub idle_a: PowerCondDescriptor,
     pub idle_b: PowerCondDescriptor,
@@ -69,48 +273,208 @@ pub struct EPCSetting {
     pub standby_z: PowerCondDescriptor,
 }
 
+impl EPCSetting {
+    /// Descriptor of power condition `mode`, None for active and unknown
+    pub fn condition(&self, mode: PowerMode) -> Option<&PowerCondDescriptor> {
+        match mode {
+            PowerMode::IdleA => Some(&self.idle_a),
+            PowerMode::IdleB => Some(&self.idle_b),
+            PowerMode::IdleC => Some(&self.idle_c),
+            PowerMode::StandbyY => Some(&self.standby_y),
+            PowerMode::StandbyZ => Some(&self.standby_z),
+            PowerMode::Active | PowerMode::Unknown => None,
+        }
+    }
+}
+
+/// Drive identity and EPC state from IDENTIFY DEVICE
+#[derive(Debug, Clone)]
+pub struct Identify {
+    pub model: String,
+    pub serial: String,
+    pub firmware: String,
+    /// World Wide Name, if reported
+    pub wwn: Option<u64>,
+
+    pub epc_supported: bool,
+    pub epc_enabled: bool,
+
+    pub apm_supported: bool,
+    /// APM level, None if APM is disabled
+    pub apm_level: Option<u8>,
+}
+
 impl Device {
     /// Open device with given path
     ///
     /// **Require root**
     pub fn open(device: impl AsRef<str>) -> Result<Device> {
+        Device::open_with_type(
+            device,
+            DeviceType {
+                passthrough: PassthroughType::Auto,
+                transport: TransportType::SgIo,
+            },
+        )
+    }
+
+    /// Open device with given path and type
+    ///
+    /// if pass-through is `Auto`, probe SAT variants with CHECK POWER MODE,
+    /// then fall back to the vendor command of a known USB bridge
+    ///
+    /// for MegaRAID, `device` is any disk on the controller
+    ///
+    /// bsg nodes under `/dev/bsg` always use SG_IO v4
+    ///
+    /// **Require root**
+    pub fn open_with_type(device: impl AsRef<str>, ty: DeviceType) -> Result<Device> {
+        Device::open_with_flags(device, ty, O_RDONLY)
+    }
+
+    /// Open device with given path, type and `open(2)` flags, eg `O_RDWR | O_EXCL`
+    ///
+    /// flags are ignored for MegaRAID, which always opens the controller node read write
+    ///
+    /// **Require root**
+    pub fn open_with_flags(
+        device: impl AsRef<str>,
+        ty: DeviceType,
+        flags: c_int,
+    ) -> Result<Device> {
         let device = device.as_ref();
 
-        let device_ffi = CString::new(device)?;
+        let transport_type = match ty.transport {
+            TransportType::SgIo if device.starts_with("/dev/bsg/") => TransportType::SgIoV4,
+            transport => transport,
+        };
 
-        let fd = unsafe { open(device_ffi.as_ptr(), O_RDONLY) };
+        let transport: Box<dyn Transport> = match transport_type {
+            TransportType::SgIo => Box::new(SgIo::new(open_fd(device, flags)?)),
+            TransportType::SgIoV4 => Box::new(SgIoV4::new(open_fd(device, flags)?)),
+            TransportType::MegaRaid(target_id) => Box::new(MegaRaid::open(device, target_id)?),
+        };
 
-        anyhow::ensure!(
-            fd > 0,
-            "open {} failed: {}",
-            device,
-            std::io::Error::last_os_error()
-        );
+        Device::with_transport_for(transport, ty.passthrough, device)
+    }
+
+    /// Build device over an already opened block, sg or bsg node, take ownership of `fd`
+    ///
+    /// MegaRAID transport is not supported, as the controller is found by device path
+    pub fn from_fd(fd: OwnedFd, ty: DeviceType) -> Result<Device> {
+        // resolve to the device node, for USB bridge lookup when probing
+        let device = format!("/proc/self/fd/{}", fd.as_raw_fd());
+
+        let transport: Box<dyn Transport> = match ty.transport {
+            TransportType::SgIo => Box::new(SgIo::new(fd)),
+            TransportType::SgIoV4 => Box::new(SgIoV4::new(fd)),
+            TransportType::MegaRaid(_) => {
+                anyhow::bail!("MegaRAID device can not be built from fd")
+            }
+        };
+
+        Device::with_transport_for(transport, ty.passthrough, &device)
+    }
+
+    /// Build device over an already opened file, see [`Device::from_fd`]
+    pub fn from_file(file: File, ty: DeviceType) -> Result<Device> {
+        Device::from_fd(file.into(), ty)
+    }
+
+    /// Build device over any SCSI transport, eg a fake one answering commands in process
+    ///
+    /// if pass-through is `Auto`, probe SAT variants
+    pub fn with_transport(
+        transport: Box<dyn Transport>,
+        passthrough: PassthroughType,
+    ) -> Result<Device> {
+        Device::with_transport_for(transport, passthrough, "transport")
+    }
 
-        Ok(Device { fd })
+    /// `device` is the node behind `transport`, to find the USB bridge when probing
+    fn with_transport_for(
+        transport: Box<dyn Transport>,
+        passthrough: PassthroughType,
+        device: &str,
+    ) -> Result<Device> {
+        let mut dev = Device {
+            transport,
+            passthrough,
+            general_log: OnceCell::new(),
+            saves: 0,
+        };
+        if passthrough == PassthroughType::Auto {
+            dev.passthrough = dev.probe(device)?;
+        }
+
+        Ok(dev)
+    }
+
+    fn probe(&mut self, device: &str) -> Result<PassthroughType> {
+        for passthrough in [PassthroughType::Sat16, PassthroughType::Sat12] {
+            self.passthrough = passthrough;
+            if self.query_mode().is_ok() {
+                return Ok(passthrough);
+            }
+        }
+
+        self.probe_bridge(usb_vendor_id(device), device)
+    }
+
+    /// Pick the vendor pass-through of USB bridge `vendor_id`, checked with CHECK POWER MODE
+    fn probe_bridge(&mut self, vendor_id: Option<u16>, device: &str) -> Result<PassthroughType> {
+        let bridge = match vendor_id {
+            Some(0x152d) => PassthroughType::JMicron,
+            Some(0x04fc) => PassthroughType::Sunplus,
+            Some(0x04b4) => PassthroughType::Cypress,
+            _ => anyhow::bail!(
+                "no working ATA pass-through found for {}, select one with --type",
+                device
+            ),
+        };
+
+        // the vendor id is the bridge maker's, its firmware may speak another protocol
+        self.passthrough = bridge;
+        self.query_mode().with_context(|| {
+            format!(
+                "no working ATA pass-through found for {}, {:?} bridge doesn't answer, \
+                 select one with --type",
+                device, bridge
+            )
+        })?;
+
+        Ok(bridge)
     }
 
     /// Query current power mode
     pub fn query_mode(&self) -> Result<PowerMode> {
         // todo: check EPC enable
-        let mut cdb = build_ata_passthrough12(AtaCmd::CheckPowerMode, Protocol::None, 0, 0, 0, 0);
-        let (_hdr, sense) = self.sg_io(&mut cdb, None, None)?;
-
-        let sense = parse_sense(&sense)?;
-        match sense.sector_count {
-            0xff => Ok(PowerMode::Active),
-            0x81 => Ok(PowerMode::IdleA),
-            0x82 => Ok(PowerMode::IdleB),
-            0x83 => Ok(PowerMode::IdleC),
-            0x01 => Ok(PowerMode::StandbyY),
-            0x00 => Ok(PowerMode::StandbyZ),
-            _ => Ok(PowerMode::Unknown),
-        }
+        let sense = self
+            .ata(
+                ata_taskfile(AtaCmd::CheckPowerMode, Protocol::None, 0, 0, 0),
+                None,
+            )?
+            .context("no output registers returned")?;
+
+        Ok(PowerMode::from_sector_count(sense.sector_count))
+    }
+
+    /// Query drive identity and EPC state
+    pub fn identify(&self) -> Result<Identify> {
+        let mut buffer = [0u8; 512];
+        self.ata(
+            ata_taskfile(AtaCmd::IdentifyDevice, Protocol::PioIn, 0, 1, 0),
+            Some(&mut buffer),
+        )?;
+
+        Ok(parse_identify(&buffer))
     }
 
     /// Query device EPC setting
     pub fn query_epc_setting(&self) -> Result<EPCSetting> {
         let pcl = self.read_log_dma_ext(0x08)?;
+        // drives without EPC may list the log with less than its 2 pages
+        anyhow::ensure!(pcl.len() >= 1024, "power conditions log not supported");
 
         let idle_power_cond = &pcl[0..512];
         let standby_power_cond = &pcl[512..];
@@ -132,146 +496,214 @@ impl Device {
     }
 
     fn read_log_dma_ext(&self, page: u8) -> Result<Vec<u8>> {
-        let general_log = self.read_general_log();
+        let general_log = self.read_general_log()?;
         let max_size = general_log[page as usize * 2] as u16
             | (general_log[page as usize * 2 + 1] as u16) << 8;
+        // a count of 0 would read 65536 pages
+        if max_size == 0 {
+            return Ok(Vec::new());
+        }
 
-        let mut buffer = Vec::with_capacity(512 * max_size as usize);
-        buffer.resize(512 * max_size as usize, 0);
-        let mut cdb = build_ata_passthrough16(
-            AtaCmd::ReadLogExtDma,
-            Protocol::InDma,
-            0,
-            max_size,
-            page as u16,
-            0,
-        );
-
-        let (_hdr, _sense) = self.sg_io(&mut cdb, None, Some(&mut buffer))?;
+        let mut buffer = vec![0; 512 * max_size as usize];
+        self.ata(self.read_log_taskfile(max_size, page), Some(&mut buffer))?;
 
         Ok(buffer)
     }
 
-    fn sg_io(
-        &self,
-        cdb: &mut [u8],
-        in_data: Option<&[u8]>,
-        out_data: Option<&mut [u8]>,
-    ) -> Result<(SgIoHdr, [u8; 32])> {
-        let mut hdr = SgIoHdr::default();
-        let mut sense = [0u8; 32];
+    fn read_log_taskfile(&self, sector_count: u16, page: u8) -> AtaTaskfile {
+        match self.passthrough {
+            PassthroughType::Sat12 | PassthroughType::Sat16 => ata_taskfile(
+                AtaCmd::ReadLogExtDma,
+                Protocol::InDma,
+                0,
+                sector_count,
+                page as u64,
+            ),
+            // bridges handle DMA commands inconsistently, use PIO
+            _ => ata_taskfile(
+                AtaCmd::ReadLogExt,
+                Protocol::PioIn,
+                0,
+                sector_count,
+                page as u64,
+            ),
+        }
+    }
 
-        hdr.cmd_len = cdb.len() as u8;
+    /// Issue ATA command with selected pass-through, `out_data` receives data from device
+    ///
+    /// Return output registers for commands with `ck_cond`
+    fn ata(&self, tf: AtaTaskfile, out_data: Option<&mut [u8]>) -> Result<Option<SenseData>> {
+        let data_len = out_data.as_ref().map_or(0, |it| it.len());
+
+        match self.passthrough {
+            PassthroughType::Sat16 => {
+                let mut cdb = tf.to_passthrough16();
+                let (status, sense) = self.transport.sg_io(&mut cdb, None, out_data)?;
+                check_ata_status(status, &sense, tf.cmd as u8)?;
+                if tf.cmd.ck_cond() {
+                    return parse_sense(&sense).map(Some);
+                }
+            }
+            PassthroughType::Sat12 => {
+                anyhow::ensure!(
+                    tf.fits_28bit(),
+                    "command {:#04x} doesn't fit ATA PASS-THROUGH(12)",
+                    tf.cmd as u8
+                );
+                let mut cdb = tf.to_passthrough12();
+                let (status, sense) = self.transport.sg_io(&mut cdb, None, out_data)?;
+                check_ata_status(status, &sense, tf.cmd as u8)?;
+                if tf.cmd.ck_cond() {
+                    return parse_sense(&sense).map(Some);
+                }
+            }
+            PassthroughType::JMicron | PassthroughType::Sunplus | PassthroughType::Cypress => {
+                anyhow::ensure!(
+                    tf.fits_28bit(),
+                    "command {:#04x} not supported by {:?} bridge",
+                    tf.cmd as u8,
+                    self.passthrough
+                );
+                return self.ata_bridge(tf, data_len, out_data);
+            }
+            PassthroughType::Auto => unreachable!("pass-through not probed"),
+        }
 
-        hdr.mx_sb_len = sense.len() as u8;
-        hdr.cmdp = cdb.as_mut_ptr();
-        hdr.sbp = sense.as_mut_ptr();
+        Ok(None)
+    }
 
-        match (in_data, out_data) {
-            (Some(in_data), None) => {
-                hdr.dxfer_direction = SG_DXFER_TO_DEV;
-                hdr.dxfer_len = in_data.len() as u32;
-                hdr.dxferp = in_data.as_ptr() as *mut _; // safe, no write to in_data
-            }
-            (None, Some(out_data)) => {
-                hdr.dxfer_direction = SG_DXFER_FROM_DEV;
-                hdr.dxfer_len = out_data.len() as u32;
-                hdr.dxferp = out_data.as_mut_ptr() as *mut _;
+    fn ata_bridge(
+        &self,
+        tf: AtaTaskfile,
+        data_len: usize,
+        out_data: Option<&mut [u8]>,
+    ) -> Result<Option<SenseData>> {
+        match self.passthrough {
+            PassthroughType::JMicron => {
+                let mut cdb = build_jmicron(&tf, data_len);
+                self.sg_io_checked(&mut cdb, out_data)?;
+                if !tf.cmd.ck_cond() {
+                    return Ok(None);
+                }
+
+                let mut registers = [0u8; JMICRON_REGISTERS_LEN];
+                self.sg_io_checked(&mut build_jmicron_registers(), Some(&mut registers))?;
+
+                Ok(Some(SenseData {
+                    sector_count: registers[0] as u16,
+                }))
             }
-            (None, None) => {
-                hdr.dxfer_direction = SG_DXFER_NONE;
+            PassthroughType::Sunplus => {
+                let mut cdb = build_sunplus(&tf, data_len);
+                self.sg_io_checked(&mut cdb, out_data)?;
+                if !tf.cmd.ck_cond() {
+                    return Ok(None);
+                }
+
+                let mut registers = [0u8; SUNPLUS_REGISTERS_LEN];
+                self.sg_io_checked(&mut build_sunplus_registers(), Some(&mut registers))?;
+
+                Ok(Some(SenseData {
+                    sector_count: registers[2] as u16,
+                }))
             }
-            (Some(_), Some(_)) => {
-                anyhow::bail!("only one direction allowed");
+            PassthroughType::Cypress => {
+                let mut cdb = build_cypress(&tf, data_len);
+                self.sg_io_checked(&mut cdb, out_data)?;
+                if !tf.cmd.ck_cond() {
+                    return Ok(None);
+                }
+
+                let mut registers = [0u8; CYPRESS_REGISTERS_LEN];
+                self.sg_io_checked(&mut build_cypress_registers(), Some(&mut registers))?;
+
+                Ok(Some(SenseData {
+                    sector_count: registers[2] as u16,
+                }))
             }
+            _ => unreachable!(),
         }
+    }
 
-        let _r = unsafe { ioctl(self.fd, SG_IO, &mut hdr) };
-        // todo check r
+    /// Vendor commands report errors with SCSI status only
+    fn sg_io_checked(&self, cdb: &mut [u8], out_data: Option<&mut [u8]>) -> Result<()> {
+        let (status, _sense) = self.transport.sg_io(cdb, None, out_data)?;
 
-        Ok((hdr, sense))
-    }
+        anyhow::ensure!(
+            status.status == 0 && status.host_status == 0,
+            "command {:#04x} failed with status {:#x}, host status {:#x}",
+            cdb[0],
+            status.status,
+            status.host_status
+        );
 
-    fn read_general_log(&self) -> &[u8] {
-        use once_cell::sync::OnceCell;
-        static GENERAL_LOG: OnceCell<[u8; 512]> = OnceCell::new();
+        Ok(())
+    }
 
-        GENERAL_LOG.get_or_init(|| {
+    fn read_general_log(&self) -> Result<&[u8]> {
+        let general_log = self.general_log.get_or_try_init(|| {
             let mut buffer = [0u8; 512];
-            let mut cdb =
-                build_ata_passthrough16(AtaCmd::ReadLogExtDma, Protocol::InDma, 0, 1, 0, 0);
-            let (_hdr, _sense) = self
-                .sg_io(&mut cdb, None, Some(&mut buffer))
-                .context("unable do sg_io")
-                .unwrap();
-
-            buffer
-        })
+            self.ata(self.read_log_taskfile(1, 0), Some(&mut buffer))
+                .context("unable do sg_io")?;
+
+            Ok::<_, anyhow::Error>(buffer)
+        })?;
+
+        Ok(general_log)
     }
 
-    /// Set device to specific power mode
-    pub fn goto_cond(&mut self, mode: PowerMode) -> Result<()> {
-        let mut cdb = build_ata_passthrough12(
-            AtaCmd::SetFeature,
-            Protocol::None,
-            0b0100_1010,
-            mode.id() as u16,
-            1,
-            0,
+    /// Set device to specific power condition, not `All`
+    pub fn goto_cond(&mut self, condition: PowerCondition) -> Result<()> {
+        anyhow::ensure!(
+            condition != PowerCondition::All,
+            "drive can't go to all power conditions"
         );
-        let (_hdr, _sense) = self.sg_io(&mut cdb, None, None)?;
+        self.set_epc_feature(condition.id(), 0x01)?;
 
         Ok(())
     }
 
-    /// Set specific power mode timer
+    /// Set specific power condition timer
     ///
     /// if `enable` set true, enable current timer else disable
     ///
-    /// if `save` set true, save current timer setting
+    /// if `save` set true, save current timer setting, unless it is already saved
     pub fn set_timer(
         &mut self,
-        mode: PowerMode,
+        condition: PowerCondition,
         timer: u16,
         enable: bool,
         save: bool,
     ) -> Result<()> {
-        let enable = if enable { 1 } else { 0 };
-        let save = if save { 1 } else { 0 };
-        let sector_number = enable << 5 | save << 4 | 0x02;
-        let mut cdb = build_ata_passthrough12(
-            AtaCmd::SetFeature,
-            Protocol::None,
-            0b0100_1010,
-            mode.id() as u16,
-            sector_number,
-            timer,
-        );
+        let save = save
+            && self
+                .query_conditions(condition)?
+                .iter()
+                .any(|desc| (desc.saved_timer, desc.saved_enable) != (timer as u32, enable));
 
-        let (_hdr, _sense) = self.sg_io(&mut cdb, None, None)?;
+        let sector_number = (enable as u64) << 5 | (save as u64) << 4 | 0x02;
+        self.set_epc_feature(condition.id(), (timer as u64) << 8 | sector_number)?;
+        self.saves += save as u32;
 
         Ok(())
     }
 
-    /// Set specific power mode state
+    /// Set specific power condition state
     ///
-    /// if `enable` set to true, enable specific power mode
+    /// if `enable` set to true, enable specific power condition
     ///
-    /// if `save` set to true, save setting
-    pub fn set_state(&mut self, mode: PowerMode, enable: bool, save: bool) -> Result<()> {
-        let enable = if enable { 1 } else { 0 };
-        let save = if save { 1 } else { 0 };
-        let sector_number = enable << 5 | save << 4 | 0x02;
-        let mut cdb = build_ata_passthrough12(
-            AtaCmd::SetFeature,
-            Protocol::None,
-            0b0100_1010,
-            mode.id() as u16,
-            sector_number,
-            0,
-        );
-
-        let (_hdr, _sense) = self.sg_io(&mut cdb, None, None)?;
+    /// if `save` set to true, save setting, unless it is already saved
+    pub fn set_state(&mut self, condition: PowerCondition, enable: bool, save: bool) -> Result<()> {
+        let save = save
+            && self
+                .query_conditions(condition)?
+                .iter()
+                .any(|desc| desc.saved_enable != enable);
+
+        let sector_number = (enable as u64) << 5 | (save as u64) << 4 | 0x03;
+        self.set_epc_feature(condition.id(), sector_number)?;
+        self.saves += save as u32;
 
         Ok(())
     }
@@ -280,10 +712,7 @@ impl Device {
     ///
     /// **This will disable APM**
     pub fn enable_epc(&mut self) -> Result<()> {
-        let mut cdb =
-            build_ata_passthrough12(AtaCmd::SetFeature, Protocol::None, 0b0100_1010, 0, 0x04, 0);
-
-        let (_hdr, _sense) = self.sg_io(&mut cdb, None, None)?;
+        self.set_epc_feature(0, 0x04)?;
 
         Ok(())
     }
@@ -292,31 +721,121 @@ impl Device {
     ///
     /// **This doesn't re-enable APM, you must enable APM manually on demand**
     pub fn disable_epc(&mut self) -> Result<()> {
-        let mut cdb =
-            build_ata_passthrough12(AtaCmd::SetFeature, Protocol::None, 0b0100_1010, 0, 0x05, 0);
+        self.set_epc_feature(0, 0x05)?;
 
-        let (_hdr, _sense) = self.sg_io(&mut cdb, None, None)?;
+        Ok(())
+    }
+
+    /// Restore current timer and state from default if `default`, else from saved
+    ///
+    /// if `save` set true, save restored setting, unless it is already saved
+    ///
+    /// `restore(PowerCondition::All, true, true)` resets the drive to factory EPC settings
+    pub fn restore(&mut self, condition: PowerCondition, default: bool, save: bool) -> Result<()> {
+        // restoring saved settings and saving them again changes nothing
+        let save = save
+            && default
+            && self.query_conditions(condition)?.iter().any(|desc| {
+                (desc.saved_timer, desc.saved_enable) != (desc.default_timer, desc.default_enable)
+            });
+
+        let sector_number = (default as u64) << 6 | (save as u64) << 4;
+        self.set_epc_feature(condition.id(), sector_number)?;
+        self.saves += save as u32;
 
         Ok(())
     }
 
-    pub fn restore(&mut self, mode: PowerMode, default: bool, save: bool) -> Result<()> {
-        let default = if default { 1 } else { 0 };
-        let save = if save { 1 } else { 0 };
-        let sector_number = default << 6 | save << 4;
+    /// Commands sent through this device that saved settings to non-volatile memory
+    ///
+    /// Saves of settings already saved are skipped and not counted.
+    pub fn saves(&self) -> u32 {
+        self.saves
+    }
 
-        let mut cdb = build_ata_passthrough12(
-            AtaCmd::SetFeature,
-            Protocol::None,
-            0b0100_1010,
-            mode.id() as u16,
-            sector_number,
-            0,
-        );
-        let (_hdr, _sense) = self.sg_io(&mut cdb, None, None)?;
+    /// Descriptors of supported conditions `condition` targets
+    fn query_conditions(&self, condition: PowerCondition) -> Result<Vec<PowerCondDescriptor>> {
+        let setting = self.query_epc_setting()?;
+        let modes = match condition.mode() {
+            Some(mode) => vec![mode],
+            None => PowerMode::CONDITIONS.to_vec(),
+        };
+
+        Ok(modes
+            .into_iter()
+            .filter_map(|mode| setting.condition(mode).copied())
+            .filter(|desc| desc.supported)
+            .collect())
+    }
+
+    /// Set APM level, 1 to 254, lower saves more power, None disables APM
+    ///
+    /// **Enabling EPC disables APM**
+    pub fn set_apm(&mut self, level: Option<u8>) -> Result<()> {
+        let (feature, level) = match level {
+            Some(level) => {
+                anyhow::ensure!((1..=254).contains(&level), "invalid APM level {}", level);
+                (0x05, level)
+            }
+            None => (0x85, 0),
+        };
+        self.ata(
+            ata_taskfile(AtaCmd::SetFeature, Protocol::None, feature, level as u16, 0),
+            None,
+        )?;
 
         Ok(())
     }
+
+    /// SET FEATURES, Extended Power Conditions subcommand
+    fn set_epc_feature(&mut self, sector_count: u8, lba: u64) -> Result<()> {
+        self.ata(
+            ata_taskfile(
+                AtaCmd::SetFeature,
+                Protocol::None,
+                0b0100_1010,
+                sector_count as u16,
+                lba,
+            ),
+            None,
+        )?;
+
+        Ok(())
+    }
+}
+
+fn ata_taskfile(
+    cmd: AtaCmd,
+    protocol: Protocol,
+    feature: u16,
+    sector_count: u16,
+    lba: u64,
+) -> AtaTaskfile {
+    AtaTaskfile {
+        cmd,
+        protocol,
+        feature,
+        sector_count,
+        lba,
+    }
+}
+
+/// USB vendor id of the bridge `device` sits behind
+fn usb_vendor_id(device: &str) -> Option<u16> {
+    let name = Path::new(device).canonicalize().ok()?;
+    let name = name.file_name()?.to_str()?;
+
+    let sysfs = if name.starts_with("sg") {
+        format!("/sys/class/scsi_generic/{}/device", name)
+    } else {
+        format!("/sys/block/{}/device", name)
+    };
+    let sysfs = Path::new(&sysfs).canonicalize().ok()?;
+
+    sysfs.ancestors().find_map(|dir| {
+        let id = std::fs::read_to_string(dir.join("idVendor")).ok()?;
+        u16::from_str_radix(id.trim(), 16).ok()
+    })
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -324,10 +843,60 @@ pub struct SenseData {
     pub sector_count: u16,
 }
 
-fn parse_sense(sense: &[u8]) -> Result<SenseData> {
+/// Fail a command the drive aborted or completed with an error
+///
+/// SAT reports both as CHECK CONDITION, with ABORTED COMMAND sense or the ERR bit set in
+/// the ATA status returned. Commands with `ck_cond` get CHECK CONDITION on success too.
+fn check_ata_status(status: ScsiStatus, sense: &[u8], command: u8) -> Result<()> {
+    // CHECK CONDITION
+    if status.status != 0x02 {
+        return Ok(());
+    }
+
+    let (sense_key, ata_status, error) = match sense[0] & 0x7f {
+        0x72 | 0x73 => {
+            let desc = &sense[8..];
+            // ATA Status Return descriptor
+            let (status, error) = if desc[0] == 0x09 {
+                (desc[13], desc[3])
+            } else {
+                (0, 0)
+            };
+            (sense[1] & 0b1111, status, error)
+        }
+        0x70 | 0x71 => {
+            // INFORMATION holds error and status with ATA PASS THROUGH INFORMATION AVAILABLE,
+            // libata fills it for aborted commands too
+            let sense_key = sense[2] & 0b1111;
+            let (status, error) = if (sense[12], sense[13]) == (0x00, 0x1d) || sense_key == 0x0b {
+                (sense[4], sense[3])
+            } else {
+                (0, 0)
+            };
+            (sense_key, status, error)
+        }
+        _ => return Ok(()),
+    };
+    // ABORTED COMMAND
+    anyhow::ensure!(
+        sense_key != 0x0b && ata_status & 1 == 0,
+        "command {:#04x} aborted, ATA status {:#04x}, error {:#04x}",
+        command,
+        ata_status,
+        error
+    );
+
+    Ok(())
+}
+
+/// Parse ATA output registers from sense data of a command with `ck_cond`
+///
+/// Both descriptor format, with an ATA Status Return descriptor, and fixed format, as
+/// returned by libata with D_SENSE=0, are accepted
+pub fn parse_sense(sense: &[u8]) -> Result<SenseData> {
     assert!(sense.len() >= 18);
 
-    let code = sense[0];
+    let code = sense[0] & 0x7f;
 
     match code {
         0x72 | 0x73 => {
@@ -339,18 +908,73 @@ fn parse_sense(sense: &[u8]) -> Result<SenseData> {
 
             let sense_desc = &sense[8..];
 
-            let _desc_code = sense_desc[0];
-            // todo check descriptor code
+            anyhow::ensure!(
+                sense_desc[0] == 0x09,
+                "unexpected sense descriptor {:#04x}",
+                sense_desc[0]
+            );
             let sector_count = sense_desc[5] as u16 | (sense_desc[4] as u16) << 8;
 
-            return Ok(SenseData { sector_count });
+            Ok(SenseData { sector_count })
         }
-        0x70 | 0x71 => {}
-        _ => {
-            unreachable!();
+        0x70 | 0x71 => {
+            let asc = sense[12];
+            let ascq = sense[13];
+            // ATA PASS THROUGH INFORMATION AVAILABLE
+            anyhow::ensure!(
+                (asc, ascq) == (0x00, 0x1d),
+                "no ATA registers returned, sense key {:#x}, asc {:#04x}, ascq {:#04x}",
+                sense[2] & 0b1111,
+                asc,
+                ascq
+            );
+
+            // INFORMATION holds error, status, device and sector count 7:0, the upper byte
+            // is only flagged as non zero in COMMAND-SPECIFIC INFORMATION
+            let count_upper_nonzero = sense[8] & 1 << 6 != 0;
+            anyhow::ensure!(
+                !count_upper_nonzero,
+                "sector count truncated in fixed sense"
+            );
+
+            Ok(SenseData {
+                sector_count: sense[6] as u16,
+            })
         }
+        _ => anyhow::bail!("no sense data returned"),
+    }
+}
+
+fn parse_identify(raw: &[u8]) -> Identify {
+    let word = |n: usize| u16::from_le_bytes([raw[n * 2], raw[n * 2 + 1]]);
+    // ATA strings are stored with bytes swapped in every word
+    let string = |from: usize, to: usize| {
+        let bytes: Vec<u8> = (from..=to)
+            .flat_map(|n| word(n).to_be_bytes().to_vec())
+            .collect();
+        String::from_utf8_lossy(&bytes).trim().to_string()
+    };
+
+    let wwn = (108..=111).fold(0u64, |wwn, n| wwn << 16 | word(n) as u64);
+
+    Identify {
+        model: string(27, 46),
+        serial: string(10, 19),
+        firmware: string(23, 26),
+        wwn: if word(87) & 1 << 8 != 0 && wwn != 0 {
+            Some(wwn)
+        } else {
+            None
+        },
+        epc_supported: word(119) & 1 << 7 != 0,
+        epc_enabled: word(120) & 1 << 7 != 0,
+        apm_supported: word(83) & 1 << 3 != 0,
+        apm_level: if word(86) & 1 << 3 != 0 {
+            Some(word(91) as u8)
+        } else {
+            None
+        },
     }
-    anyhow::bail!("unexpected error");
 }
 
 fn parse_power_cond_desc(raw: &[u8]) -> PowerCondDescriptor {
@@ -377,3 +1001,328 @@ fn parse_power_cond_desc(raw: &[u8]) -> PowerCondDescriptor {
         max_timer,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::fake::{ata_abort, ata_return, FakeDrive, Registers};
+    use crate::ffi::{build_cypress_registers, build_jmicron_registers, build_sunplus_registers};
+    use crate::transport::ScsiStatus;
+
+    /// USB bridge answering CHECK POWER MODE in vendor commands of `passthrough` only
+    struct BridgeDrive {
+        passthrough: PassthroughType,
+        sector_count: u8,
+        commands: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl BridgeDrive {
+        fn new(passthrough: PassthroughType) -> (BridgeDrive, Arc<Mutex<Vec<Vec<u8>>>>) {
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let drive = BridgeDrive {
+                passthrough,
+                sector_count: 0x82,
+                commands: commands.clone(),
+            };
+
+            (drive, commands)
+        }
+    }
+
+    impl Transport for BridgeDrive {
+        fn sg_io(
+            &self,
+            cdb: &mut [u8],
+            _in_data: Option<&[u8]>,
+            out_data: Option<&mut [u8]>,
+        ) -> Result<(ScsiStatus, [u8; 32])> {
+            self.commands.lock().unwrap().push(cdb.to_vec());
+            let status = |status| ScsiStatus {
+                status,
+                host_status: 0,
+            };
+
+            // register readback, the sector count at the offset of the vendor layout
+            let offset = match (self.passthrough, cdb[0]) {
+                (PassthroughType::JMicron, 0xdf) if cdb[11] == 0xfd => Some(0),
+                (PassthroughType::Sunplus, 0xf8) if cdb[2] == 0x21 => Some(2),
+                (PassthroughType::Cypress, 0x24) if cdb[2] == 0x01 => Some(2),
+                (PassthroughType::JMicron, 0xdf)
+                | (PassthroughType::Sunplus, 0xf8)
+                | (PassthroughType::Cypress, 0x24) => None,
+                // CHECK CONDITION, INVALID COMMAND OPERATION CODE
+                _ => return Ok((status(0x02), [0; 32])),
+            };
+            if let Some(offset) = offset {
+                let out = out_data.unwrap();
+                out.iter_mut().for_each(|it| *it = 0);
+                out[offset] = self.sector_count;
+            }
+
+            Ok((status(0), [0; 32]))
+        }
+    }
+
+    #[test]
+    fn sense_descriptor_format() {
+        let sense = parse_sense(&ata_return(0x82, false)).unwrap();
+        assert_eq!(sense.sector_count, 0x82);
+
+        // sector count 15:8 in descriptor
+        let mut raw = ata_return(0x34, false);
+        raw[12] = 0x12;
+        assert_eq!(parse_sense(&raw).unwrap().sector_count, 0x1234);
+    }
+
+    #[test]
+    fn sense_fixed_format() {
+        let sense = parse_sense(&ata_return(0x81, true)).unwrap();
+        assert_eq!(sense.sector_count, 0x81);
+
+        // VALID bit set
+        let mut raw = ata_return(0x00, true);
+        raw[0] |= 0x80;
+        assert_eq!(parse_sense(&raw).unwrap().sector_count, 0x00);
+
+        // ILLEGAL REQUEST, INVALID FIELD IN CDB
+        let mut raw = [0u8; 32];
+        raw[0] = 0x70;
+        raw[2] = 0x05;
+        raw[12] = 0x24;
+        assert!(parse_sense(&raw).is_err());
+    }
+
+    #[test]
+    fn no_sense() {
+        assert!(parse_sense(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn check_power_mode() {
+        let drive = FakeDrive::new();
+        drive.state().mode = PowerMode::StandbyY;
+
+        assert_eq!(drive.device().query_mode().unwrap(), PowerMode::StandbyY);
+    }
+
+    #[test]
+    fn probe_fixed_sense() {
+        let drive = FakeDrive::new();
+        drive.state().fixed_sense = true;
+        drive.state().mode = PowerMode::IdleB;
+
+        let device =
+            Device::with_transport(Box::new(drive.clone()), PassthroughType::Auto).unwrap();
+        assert_eq!(device.passthrough, PassthroughType::Sat16);
+        assert_eq!(device.query_mode().unwrap(), PowerMode::IdleB);
+    }
+
+    #[test]
+    fn probe_falls_back_to_sat12() {
+        let drive = FakeDrive::new();
+        drive.state().sat12_only = true;
+
+        let device =
+            Device::with_transport(Box::new(drive.clone()), PassthroughType::Auto).unwrap();
+        assert_eq!(device.passthrough, PassthroughType::Sat12);
+        assert_eq!(device.query_mode().unwrap(), PowerMode::Active);
+
+        let commands = &drive.state().commands;
+        assert_eq!(commands[0][0], 0x85);
+        assert_eq!(commands[1][0], 0xa1);
+    }
+
+    #[test]
+    fn sat12_set_timer() {
+        let drive = FakeDrive::new();
+        drive.state().sat12_only = true;
+        let mut device =
+            Device::with_transport(Box::new(drive.clone()), PassthroughType::Sat12).unwrap();
+
+        device
+            .set_timer(PowerCondition::StandbyZ, 0x1234, true, false)
+            .unwrap();
+        let set_features = drive.state().set_features();
+        assert_eq!(set_features[0].lba, 0x12_3422);
+        assert_eq!(drive.state().conditions[4].current_timer, 0x1234);
+    }
+
+    #[test]
+    fn saving_saved_settings_is_skipped() {
+        let drive = FakeDrive::new();
+        let mut device = drive.device();
+
+        // idle a and standby z saved as enabled, saved settings equal defaults
+        device
+            .set_timer(PowerCondition::IdleA, 20, true, true)
+            .unwrap();
+        device
+            .set_state(PowerCondition::StandbyZ, true, true)
+            .unwrap();
+        device.restore(PowerCondition::All, true, true).unwrap();
+
+        let set_features = drive.state().set_features();
+        assert_eq!(set_features.len(), 3);
+        assert!(set_features.iter().all(|it| it.lba & 1 << 4 == 0));
+        assert_eq!(device.saves(), 0);
+    }
+
+    #[test]
+    fn saving_changed_settings_is_counted() {
+        let drive = FakeDrive::new();
+        let mut device = drive.device();
+
+        device
+            .set_timer(PowerCondition::IdleA, 50, true, true)
+            .unwrap();
+        device.set_state(PowerCondition::IdleB, true, true).unwrap();
+        // saved settings now differ from defaults
+        device.restore(PowerCondition::All, true, true).unwrap();
+
+        let set_features = drive.state().set_features();
+        assert!(set_features.iter().all(|it| it.lba & 1 << 4 != 0));
+        assert_eq!(device.saves(), 3);
+        assert_eq!(drive.state().conditions[0].saved_timer, 20);
+    }
+
+    #[test]
+    fn all_conditions_id() {
+        let drive = FakeDrive::new();
+        let mut device = drive.device();
+
+        device
+            .set_timer(PowerCondition::All, 600, true, false)
+            .unwrap();
+        device.set_state(PowerCondition::All, false, false).unwrap();
+        device.restore(PowerCondition::All, false, false).unwrap();
+        assert!(device.goto_cond(PowerCondition::All).is_err());
+
+        let set_features = drive.state().set_features();
+        assert_eq!(set_features.len(), 3);
+        assert!(set_features.iter().all(|it| it.sector_count == 0xff));
+        assert_eq!(drive.state().commands.last().unwrap()[6], 0xff);
+    }
+
+    #[test]
+    fn bridge_registers_readback() {
+        let bridges = [
+            (PassthroughType::JMicron, build_jmicron_registers().to_vec()),
+            (PassthroughType::Sunplus, build_sunplus_registers().to_vec()),
+            (PassthroughType::Cypress, build_cypress_registers().to_vec()),
+        ];
+        for (passthrough, registers) in bridges {
+            let (drive, commands) = BridgeDrive::new(passthrough);
+            let device = Device::with_transport(Box::new(drive), passthrough).unwrap();
+
+            assert_eq!(device.query_mode().unwrap(), PowerMode::IdleB);
+            let commands = commands.lock().unwrap();
+            assert_eq!(commands.len(), 2);
+            assert_eq!(commands[1], registers);
+        }
+    }
+
+    #[test]
+    fn probe_bridge_is_checked() {
+        let (drive, _) = BridgeDrive::new(PassthroughType::JMicron);
+        let mut device = Device::with_transport(Box::new(drive), PassthroughType::Sat16).unwrap();
+        assert_eq!(
+            device.probe_bridge(Some(0x152d), "/dev/sdx").unwrap(),
+            PassthroughType::JMicron
+        );
+
+        // Cypress firmware behind a JMicron vendor id
+        let (drive, commands) = BridgeDrive::new(PassthroughType::Cypress);
+        let mut device = Device::with_transport(Box::new(drive), PassthroughType::Sat16).unwrap();
+        let e = device.probe_bridge(Some(0x152d), "/dev/sdx").unwrap_err();
+        assert!(format!("{}", e).contains("JMicron bridge doesn't answer"));
+        assert_eq!(commands.lock().unwrap()[0][0], 0xdf);
+
+        assert!(device.probe_bridge(None, "/dev/sdx").is_err());
+    }
+
+    #[test]
+    fn power_conditions_log_too_small() {
+        for pages in [0, 1] {
+            let drive = FakeDrive::new();
+            drive.state().power_conditions_log_pages = pages;
+
+            let e = drive.device().query_epc_setting().unwrap_err();
+            assert_eq!(e.to_string(), "power conditions log not supported");
+        }
+    }
+
+    #[test]
+    fn aborted_command_fails() {
+        for fixed in [false, true] {
+            let drive = FakeDrive::new();
+            drive.state().fixed_sense = fixed;
+            drive.state().epc_enabled = false;
+
+            // power conditions can't be set while EPC is disabled
+            let mut device = drive.device();
+            let e = device
+                .set_timer(PowerCondition::IdleA, 50, true, false)
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "command 0xef aborted, ATA status 0x51, error 0x04"
+            );
+            assert_eq!(drive.state().condition(PowerMode::IdleA).current_timer, 20);
+
+            drive.state().apm_supported = false;
+            assert!(device.set_apm(Some(128)).is_err());
+        }
+    }
+
+    #[test]
+    fn ata_error_status() {
+        let check_condition = ScsiStatus {
+            status: 0x02,
+            host_status: 0,
+        };
+        let good = ScsiStatus {
+            status: 0,
+            host_status: 0,
+        };
+
+        // registers returned by ck_cond
+        for fixed in [false, true] {
+            let sense = ata_return(0x81, fixed);
+            assert!(check_ata_status(check_condition, &sense, 0xe5).is_ok());
+            assert!(check_ata_status(good, &ata_abort(fixed), 0xef).is_ok());
+        }
+
+        // ERR without ABORTED COMMAND
+        let mut sense = ata_return(0, false);
+        sense[21] = 0x51;
+        let e = check_ata_status(check_condition, &sense, 0xef).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "command 0xef aborted, ATA status 0x51, error 0x00"
+        );
+        let mut sense = ata_return(0, true);
+        sense[4] = 0x51;
+        assert!(check_ata_status(check_condition, &sense, 0xef).is_err());
+
+        // INFORMATION of other fixed sense isn't ATA status
+        let mut sense = [0u8; 32];
+        sense[0] = 0x70;
+        sense[2] = 0x03; // MEDIUM ERROR
+        sense[4] = 0x01;
+        sense[12] = 0x11;
+        assert!(check_ata_status(check_condition, &sense, 0xef).is_ok());
+    }
+
+    #[test]
+    fn registers_of_sat16() {
+        let cdb = ata_taskfile(AtaCmd::SetFeature, Protocol::None, 0x4a, 0x83, 0x0012_3402)
+            .to_passthrough16();
+        let registers = Registers::of(&cdb).unwrap();
+        assert_eq!(
+            (registers.feature, registers.sector_count, registers.lba),
+            (0x4a, 0x83, 0x12_3402)
+        );
+    }
+}
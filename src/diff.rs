@@ -0,0 +1,284 @@
+//! Compare EPC settings of drives, backups and profiles
+
+use std::fmt;
+
+use crate::device::{EPCSetting, PowerCondDescriptor, PowerMode};
+use crate::profile::DriveProfile;
+
+/// Value of one setting, timers are in 100 milliseconds
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Setting {
+    Flag(bool),
+    Timer(u32),
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Setting::Flag(flag) => write!(f, "{}", flag),
+            Setting::Timer(timer) => write!(f, "{}", timer),
+        }
+    }
+}
+
+/// Settings of one condition, None where the source says nothing
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ConditionState {
+    pub supported: Option<bool>,
+    pub current_enable: Option<bool>,
+    pub current_timer: Option<u32>,
+    pub saved_enable: Option<bool>,
+    pub saved_timer: Option<u32>,
+    pub default_enable: Option<bool>,
+    pub default_timer: Option<u32>,
+}
+
+impl ConditionState {
+    fn settings(&self) -> [(&'static str, Option<Setting>); 7] {
+        [
+            ("supported", self.supported.map(Setting::Flag)),
+            ("current_enable", self.current_enable.map(Setting::Flag)),
+            ("current_timer", self.current_timer.map(Setting::Timer)),
+            ("saved_enable", self.saved_enable.map(Setting::Flag)),
+            ("saved_timer", self.saved_timer.map(Setting::Timer)),
+            ("default_enable", self.default_enable.map(Setting::Flag)),
+            ("default_timer", self.default_timer.map(Setting::Timer)),
+        ]
+    }
+}
+
+/// EPC state of a drive, as read from a drive or a backup, or as wanted by a profile
+pub struct State {
+    pub epc_enabled: Option<bool>,
+    /// from idle a to standby z
+    pub conditions: [ConditionState; 5],
+}
+
+impl State {
+    /// State read from a drive or a backup, settings of unsupported conditions are left out
+    pub fn of(epc_enabled: bool, setting: &EPCSetting) -> State {
+        let condition = |desc: &PowerCondDescriptor| {
+            if !desc.supported {
+                return ConditionState {
+                    supported: Some(false),
+                    ..ConditionState::default()
+                };
+            }
+
+            ConditionState {
+                supported: Some(true),
+                current_enable: Some(desc.current_enable),
+                current_timer: Some(desc.current_timer),
+                saved_enable: Some(desc.saved_enable),
+                saved_timer: Some(desc.saved_timer),
+                default_enable: Some(desc.default_enable),
+                default_timer: Some(desc.default_timer),
+            }
+        };
+
+        State {
+            epc_enabled: Some(epc_enabled),
+            conditions: PowerMode::CONDITIONS
+                .map(|mode| condition(setting.condition(mode).unwrap())),
+        }
+    }
+
+    /// State wanted by a drive section of a profile for a drive with `setting`
+    pub fn wanted(profile: &DriveProfile, setting: &EPCSetting) -> State {
+        let mut conditions = [ConditionState::default(); 5];
+        for (mode, condition) in profile.conditions(setting) {
+            let i = PowerMode::CONDITIONS
+                .iter()
+                .position(|it| *it == mode)
+                .unwrap();
+            let timer = condition.timer.map(|it| it as u32);
+
+            conditions[i] = ConditionState {
+                supported: Some(true),
+                current_enable: Some(condition.enabled),
+                current_timer: timer,
+                saved_enable: Some(condition.enabled).filter(|_| profile.save),
+                saved_timer: timer.filter(|_| profile.save),
+                ..ConditionState::default()
+            };
+        }
+
+        State {
+            epc_enabled: profile.epc(),
+            conditions,
+        }
+    }
+}
+
+/// Setting differing between two states
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Difference {
+    /// None for the EPC feature itself
+    pub condition: Option<PowerMode>,
+    /// eg `current_timer`
+    pub setting: &'static str,
+    pub left: Setting,
+    pub right: Setting,
+}
+
+/// Settings known to both states that differ
+pub fn diff(left: &State, right: &State) -> Vec<Difference> {
+    let mut differences = Vec::new();
+
+    if let (Some(l), Some(r)) = (left.epc_enabled, right.epc_enabled) {
+        if l != r {
+            differences.push(Difference {
+                condition: None,
+                setting: "enabled",
+                left: Setting::Flag(l),
+                right: Setting::Flag(r),
+            });
+        }
+    }
+
+    for (i, mode) in PowerMode::CONDITIONS.iter().enumerate() {
+        let left_settings = left.conditions[i].settings();
+        let right_settings = right.conditions[i].settings();
+        for ((setting, l), (_, r)) in left_settings.iter().zip(right_settings.iter()) {
+            if let (Some(l), Some(r)) = (*l, *r) {
+                if l != r {
+                    differences.push(Difference {
+                        condition: Some(*mode),
+                        setting,
+                        left: l,
+                        right: r,
+                    });
+                }
+            }
+        }
+    }
+
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeDrive;
+    use crate::profile::Profile;
+
+    fn state(drive: &FakeDrive) -> State {
+        let device = drive.device();
+        let identify = device.identify().unwrap();
+        State::of(identify.epc_enabled, &device.query_epc_setting().unwrap())
+    }
+
+    #[test]
+    fn equal_states() {
+        let drive = FakeDrive::new();
+        assert_eq!(diff(&state(&drive), &state(&drive)), []);
+    }
+
+    #[test]
+    fn one_difference() {
+        let drive = FakeDrive::new();
+        let left = state(&drive);
+
+        drive.state().condition(PowerMode::StandbyZ).current_timer = 3000;
+        assert_eq!(
+            diff(&left, &state(&drive)),
+            [Difference {
+                condition: Some(PowerMode::StandbyZ),
+                setting: "current_timer",
+                left: Setting::Timer(9000),
+                right: Setting::Timer(3000),
+            }]
+        );
+
+        let drive = FakeDrive::new();
+        drive.state().condition(PowerMode::IdleB).saved_enable = true;
+        assert_eq!(
+            diff(&left, &state(&drive)),
+            [Difference {
+                condition: Some(PowerMode::IdleB),
+                setting: "saved_enable",
+                left: Setting::Flag(false),
+                right: Setting::Flag(true),
+            }]
+        );
+
+        let drive = FakeDrive::new();
+        drive.state().epc_enabled = false;
+        assert_eq!(
+            diff(&left, &state(&drive)),
+            [Difference {
+                condition: None,
+                setting: "enabled",
+                left: Setting::Flag(true),
+                right: Setting::Flag(false),
+            }]
+        );
+    }
+
+    #[test]
+    fn unsupported_ignored() {
+        let drive = FakeDrive::new();
+        drive.state().condition(PowerMode::IdleC).supported = false;
+        let left = state(&drive);
+
+        drive.state().condition(PowerMode::IdleC).current_timer = 100;
+        drive.state().condition(PowerMode::IdleC).saved_enable = true;
+        assert_eq!(diff(&left, &state(&drive)), []);
+
+        // only support itself differs
+        let right = state(&FakeDrive::new());
+        assert_eq!(
+            diff(&left, &right),
+            [Difference {
+                condition: Some(PowerMode::IdleC),
+                setting: "supported",
+                left: Setting::Flag(false),
+                right: Setting::Flag(true),
+            }]
+        );
+    }
+
+    #[test]
+    fn wanted_by_profile() {
+        let drive = FakeDrive::new();
+        let setting = drive.device().query_epc_setting().unwrap();
+        drive.state().condition(PowerMode::IdleC).saved_timer = 3000;
+        let profile = |text: &str| Profile::parse(text).unwrap().drives.remove(0);
+
+        // conditions the profile leaves out and saved settings it doesn't save are unknown
+        let wanted = State::wanted(
+            &profile("[[drive]]\n[drive.idle_a]\ntimer = \"2s\"\n"),
+            &setting,
+        );
+        assert_eq!(wanted.epc_enabled, None);
+        assert_eq!(diff(&state(&drive), &wanted), []);
+
+        let wanted = State::wanted(
+            &profile("[[drive]]\nepc = true\nsave = true\n[drive.idle_c]\ntimer = \"10m\"\n"),
+            &setting,
+        );
+        assert_eq!(
+            diff(&state(&drive), &wanted),
+            [
+                Difference {
+                    condition: Some(PowerMode::IdleC),
+                    setting: "current_enable",
+                    left: Setting::Flag(false),
+                    right: Setting::Flag(true),
+                },
+                Difference {
+                    condition: Some(PowerMode::IdleC),
+                    setting: "saved_enable",
+                    left: Setting::Flag(false),
+                    right: Setting::Flag(true),
+                },
+                Difference {
+                    condition: Some(PowerMode::IdleC),
+                    setting: "saved_timer",
+                    left: Setting::Timer(3000),
+                    right: Setting::Timer(6000),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,612 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// SCSI peripheral device type of a direct access block device
+const TYPE_DISK: &str = "0";
+
+/// Disk found in sysfs
+#[derive(Debug, Clone)]
+pub struct Disk {
+    /// block node, eg /dev/sda
+    pub block: Option<String>,
+    /// sg node, eg /dev/sg0
+    pub sg: Option<String>,
+    pub vendor: String,
+    pub model: String,
+    /// serial from VPD page 80h, if the kernel exposes it
+    pub serial: Option<String>,
+    /// NAA World Wide Name from device identification VPD page
+    pub wwn: Option<u64>,
+    /// sata, sas, usb, megaraid or scsi
+    pub transport: &'static str,
+    /// dm-multipath map holding the disk, eg mpatha
+    pub multipath: Option<String>,
+}
+
+impl Disk {
+    /// Node to send commands to, block node preferred
+    pub fn node(&self) -> &str {
+        self.block
+            .as_deref()
+            .or(self.sg.as_deref())
+            .expect("disk without device node")
+    }
+}
+
+/// sysfs tree, normally mounted at /sys
+pub struct Sysfs {
+    root: PathBuf,
+}
+
+impl Sysfs {
+    /// Walk sysfs mounted at `root`, eg a fixture tree
+    pub fn new(root: impl Into<PathBuf>) -> Sysfs {
+        Sysfs { root: root.into() }
+    }
+
+    /// List physical SCSI disks, skip partitions, loop and other virtual devices
+    ///
+    /// Disks only exposed as sg node are listed after block disks
+    pub fn disks(&self) -> Result<Vec<Disk>> {
+        let mut disks = Vec::new();
+
+        let block = self.root.join("block");
+        for name in
+            sorted_entries(&block).with_context(|| format!("unable to read {}", block.display()))?
+        {
+            let dir = block.join(&name);
+            if dir.join("partition").exists() || self.is_virtual(&dir) {
+                continue;
+            }
+
+            let device = dir.join("device");
+            if !is_scsi_disk(&device) {
+                continue;
+            }
+
+            let sg = sorted_entries(&device.join("scsi_generic"))
+                .ok()
+                .and_then(|it| it.into_iter().next());
+
+            disks.push(self.disk(Some(&name), sg.as_deref(), &device));
+        }
+
+        // sg class is missing without sg driver
+        let scsi_generic = self.root.join("class/scsi_generic");
+        for name in sorted_entries(&scsi_generic).unwrap_or_default() {
+            let device = scsi_generic.join(&name).join("device");
+            if !is_scsi_disk(&device) {
+                continue;
+            }
+
+            let mapped = disks
+                .iter()
+                .any(|it| it.sg.as_deref() == Some(name.as_str()));
+            if mapped || device.join("block").exists() {
+                continue;
+            }
+
+            disks.push(self.disk(None, Some(&name), &device));
+        }
+
+        Ok(disks)
+    }
+
+    fn disk(&self, block: Option<&str>, sg: Option<&str>, device: &Path) -> Disk {
+        Disk {
+            block: block.map(|it| format!("/dev/{}", it)),
+            sg: sg.map(|it| format!("/dev/{}", it)),
+            vendor: read_attr(&device.join("vendor")).unwrap_or_default(),
+            model: read_attr(&device.join("model")).unwrap_or_default(),
+            serial: read_vpd_serial(&device.join("vpd_pg80")),
+            wwn: read_attr(&device.join("wwid")).and_then(|it| parse_wwid(&it)),
+            transport: self.transport(device),
+            multipath: block.and_then(|it| self.multipath(it)),
+        }
+    }
+
+    /// Name of dm-multipath map holding block device `name`
+    fn multipath(&self, name: &str) -> Option<String> {
+        let holders = self.root.join("class/block").join(name).join("holders");
+
+        sorted_entries(&holders)
+            .ok()?
+            .into_iter()
+            .map(|it| holders.join(it).join("dm"))
+            .find(|dm| read_attr(&dm.join("uuid")).is_some_and(|uuid| uuid.starts_with("mpath-")))
+            .and_then(|dm| read_attr(&dm.join("name")))
+    }
+
+    fn is_virtual(&self, dir: &Path) -> bool {
+        let virtual_devices = self.root.join("devices/virtual").canonicalize();
+
+        match (dir.canonicalize(), virtual_devices) {
+            (Ok(dir), Ok(virtual_devices)) => dir.starts_with(virtual_devices),
+            (Ok(_), Err(_)) => false,
+            // dangling link
+            (Err(_), _) => true,
+        }
+    }
+
+    /// Classify by the bus path of SCSI device
+    fn transport(&self, device: &Path) -> &'static str {
+        let path = match device.canonicalize() {
+            Ok(path) => path,
+            Err(_) => return "scsi",
+        };
+        let path = path.to_string_lossy();
+
+        if path.contains("/usb") {
+            return "usb";
+        }
+        if path.contains("/ata") {
+            return "sata";
+        }
+        if path.contains("/end_device-") || path.contains("/expander-") {
+            return "sas";
+        }
+
+        let host = path.split('/').find(|it| it.starts_with("host"));
+        let proc_name = host.and_then(|host| {
+            read_attr(
+                &self
+                    .root
+                    .join("class/scsi_host")
+                    .join(host)
+                    .join("proc_name"),
+            )
+        });
+        match proc_name.as_deref() {
+            Some("megaraid_sas") => "megaraid",
+            _ => "scsi",
+        }
+    }
+}
+
+fn is_scsi_disk(device: &Path) -> bool {
+    read_attr(&device.join("type")).as_deref() == Some(TYPE_DISK)
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<String>> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|it| it.ok())
+        .filter_map(|it| it.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+
+    // natural order, sdb before sdaa
+    entries.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+
+    Ok(entries)
+}
+
+fn read_attr(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|it| it.trim().to_string())
+}
+
+/// Unit Serial Number VPD page, serial starts at byte 4
+fn read_vpd_serial(path: &Path) -> Option<String> {
+    let page = fs::read(path).ok()?;
+    let len = *page.get(3)? as usize;
+    let serial = page.get(4..4 + len)?;
+
+    let serial = String::from_utf8_lossy(serial).trim().to_string();
+    if serial.is_empty() {
+        None
+    } else {
+        Some(serial)
+    }
+}
+
+/// `naa.5000cca26fd0b2e4` style wwid, other designators have no WWN
+fn parse_wwid(wwid: &str) -> Option<u64> {
+    let naa = wwid.strip_prefix("naa.")?;
+
+    // NAA 6 identifiers are 128 bit, keep the leading 64 bit like ATA WWN
+    u64::from_str_radix(naa.get(..16)?, 16).ok()
+}
+
+impl Sysfs {
+    /// Physical disks under block device `name`, following `slaves/` of md, dm and
+    /// other stacked devices, and partitions to their disk
+    ///
+    /// eg `md0` gives `/dev/sda` and `/dev/sdb`
+    pub fn physical_disks(&self, name: &str) -> Result<Vec<String>> {
+        let dir = self.root.join("class/block").join(name);
+        anyhow::ensure!(dir.exists(), "block device {} not found", name);
+
+        let mut disks = Vec::new();
+
+        let slaves = sorted_entries(&dir.join("slaves")).unwrap_or_default();
+        if slaves.is_empty() {
+            let disk = if dir.join("partition").exists() {
+                let dir = dir.canonicalize()?;
+                dir.parent()
+                    .and_then(|it| it.file_name())
+                    .and_then(|it| it.to_str())
+                    .with_context(|| format!("no disk for partition {}", name))?
+                    .to_string()
+            } else {
+                name.to_string()
+            };
+            disks.push(format!("/dev/{}", disk));
+        }
+
+        for slave in slaves {
+            for disk in self.physical_disks(&slave)? {
+                if !disks.contains(&disk) {
+                    disks.push(disk);
+                }
+            }
+        }
+
+        Ok(disks)
+    }
+
+    /// Block device name of device number, eg `8:1` gives `sda1`
+    pub fn block_by_devno(&self, devno: &str) -> Result<String> {
+        let link = self
+            .root
+            .join("dev/block")
+            .join(devno)
+            .canonicalize()
+            .with_context(|| format!("no block device {}", devno))?;
+
+        Ok(link
+            .file_name()
+            .and_then(|it| it.to_str())
+            .with_context(|| format!("no block device {}", devno))?
+            .to_string())
+    }
+
+    /// Block device name of device mapper device, eg `vg-lv` gives `dm-3`
+    ///
+    /// `dm-N` names are returned as is
+    pub fn dm_by_name(&self, name: &str) -> Result<String> {
+        if name.starts_with("dm-") {
+            return Ok(name.to_string());
+        }
+
+        sorted_entries(&self.root.join("block"))?
+            .into_iter()
+            .filter(|it| it.starts_with("dm-"))
+            .find(|it| {
+                read_attr(&self.root.join("block").join(it).join("dm/name")).as_deref()
+                    == Some(name)
+            })
+            .with_context(|| format!("device mapper device {} not found", name))
+    }
+}
+
+/// Mount containing a path, from mountinfo
+#[derive(Debug, Clone)]
+pub struct Mount {
+    /// device number, eg `8:1`, major is 0 for filesystems without a single block device
+    pub devno: String,
+    pub fstype: String,
+    /// mount source, eg `/dev/sda1` or `tank/data`
+    pub source: String,
+}
+
+/// Find the mount containing `path` in `/proc/self/mountinfo` formatted `mountinfo`
+pub fn find_mount(mountinfo: &str, path: &Path) -> Option<Mount> {
+    let mut found: Option<(PathBuf, Mount)> = None;
+
+    for line in mountinfo.lines() {
+        let (mount, optional) = match line.split_once(" - ") {
+            Some(it) => it,
+            None => continue,
+        };
+        let fields: Vec<&str> = mount.split(' ').collect();
+        let optional: Vec<&str> = optional.split(' ').collect();
+        if fields.len() < 5 || optional.len() < 2 {
+            continue;
+        }
+
+        let mount_point = PathBuf::from(unescape_mountinfo(fields[4]));
+        if !path.starts_with(&mount_point) {
+            continue;
+        }
+
+        // later mounts on the same mount point shadow earlier ones
+        let deeper = match &found {
+            Some((found, _)) => mount_point.components().count() >= found.components().count(),
+            None => true,
+        };
+        if deeper {
+            let mount = Mount {
+                devno: fields[2].to_string(),
+                fstype: optional[0].to_string(),
+                source: unescape_mountinfo(optional[1]),
+            };
+            found = Some((mount_point, mount));
+        }
+    }
+
+    found.map(|(_, mount)| mount)
+}
+
+/// Spaces and other special characters are octal escaped, eg `\040`
+fn unescape_mountinfo(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        let code = rest
+            .get(i + 1..i + 4)
+            .and_then(|it| u8::from_str_radix(it, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// sysfs fixture in a temporary directory, removed on drop
+    struct Fixture {
+        root: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let root = std::env::temp_dir().join(format!("wdepc-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+
+            Fixture { root }
+        }
+
+        fn write(&self, path: &str, content: &[u8]) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        fn link(&self, path: &str, target: &str) {
+            let path = self.root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            symlink(self.root.join(target), path).unwrap();
+        }
+
+        /// SCSI disk at `path` with block node `block` and sg node `sg`
+        fn scsi_disk(&self, path: &str, block: Option<&str>, sg: &str, model: &str) {
+            self.write(&format!("{}/type", path), b"0\n");
+            self.write(&format!("{}/vendor", path), b"ATA     \n");
+            self.write(
+                &format!("{}/model", path),
+                format!("{}\n", model).as_bytes(),
+            );
+            self.write(&format!("{}/scsi_generic/{}/dev", path, sg), b"21:0\n");
+            self.link(&format!("{}/scsi_generic/{}/device", path, sg), path);
+            self.link(
+                &format!("class/scsi_generic/{}", sg),
+                &format!("{}/scsi_generic/{}", path, sg),
+            );
+
+            if let Some(block) = block {
+                let dir = format!("{}/block/{}", path, block);
+                self.write(&format!("{}/removable", dir), b"0\n");
+                self.link(&format!("{}/device", dir), path);
+                self.link(&format!("block/{}", block), &dir);
+                self.link(&format!("class/block/{}", block), &dir);
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    const PCI: &str = "devices/pci0000:00";
+
+    #[test]
+    fn disks_from_fixture() {
+        let fixture = Fixture::new("discovery");
+
+        let sata = format!("{}/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0", PCI);
+        fixture.scsi_disk(&sata, Some("sda"), "sg0", "WDC WUH721818AL");
+        fixture.write(&format!("{}/wwid", sata), b"naa.5000cca29ac12345\n");
+        let mut vpd = vec![0x00, 0x80, 0x00, 0x0c];
+        vpd.extend_from_slice(b"    3WJ0ABCD");
+        fixture.write(&format!("{}/vpd_pg80", sata), &vpd);
+        // partition of sda
+        let sda1 = format!("{}/block/sda/sda1", sata);
+        fixture.write(&format!("{}/partition", sda1), b"1\n");
+        fixture.link("block/sda1", &sda1);
+
+        let usb = format!(
+            "{}/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0",
+            PCI
+        );
+        fixture.scsi_disk(&usb, Some("sdb"), "sg1", "Elements 25A3");
+        fixture.write(&format!("{}/block/sdb/removable", usb), b"1\n");
+
+        let megaraid = format!("{}/0000:01:00.0/host3/target3:2:0/3:2:0:0", PCI);
+        fixture.scsi_disk(&megaraid, Some("sdc"), "sg2", "PERC H730P Mini");
+        fixture.write("class/scsi_host/host3/proc_name", b"megaraid_sas\n");
+
+        // physical drive behind the controller, only exposed as sg node
+        let hidden = format!("{}/0000:01:00.0/host3/target3:0:4/3:0:4:0", PCI);
+        fixture.scsi_disk(&hidden, None, "sg3", "WDC WUH721818AL");
+
+        // optical drive
+        let cdrom = format!("{}/0000:00:17.0/ata2/host1/target1:0:0/1:0:0:0", PCI);
+        fixture.scsi_disk(&cdrom, Some("sr0"), "sg4", "DVD-ROM");
+        fixture.write(&format!("{}/type", cdrom), b"5\n");
+
+        fixture.write("devices/virtual/block/loop0/removable", b"0\n");
+        fixture.link("block/loop0", "devices/virtual/block/loop0");
+
+        let disks = Sysfs::new(&fixture.root).disks().unwrap();
+        let summary: Vec<_> = disks
+            .iter()
+            .map(|it| (it.block.as_deref(), it.sg.as_deref(), it.transport))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Some("/dev/sda"), Some("/dev/sg0"), "sata"),
+                (Some("/dev/sdb"), Some("/dev/sg1"), "usb"),
+                (Some("/dev/sdc"), Some("/dev/sg2"), "megaraid"),
+                (None, Some("/dev/sg3"), "megaraid"),
+            ]
+        );
+
+        let sda = &disks[0];
+        assert_eq!(sda.vendor, "ATA");
+        assert_eq!(sda.model, "WDC WUH721818AL");
+        assert_eq!(sda.serial.as_deref(), Some("3WJ0ABCD"));
+        assert_eq!(sda.wwn, Some(0x5000_cca2_9ac1_2345));
+        assert_eq!(disks[1].serial, None);
+        assert_eq!(disks[3].node(), "/dev/sg3");
+    }
+
+    #[test]
+    fn physical_disks_of_partition() {
+        let fixture = Fixture::new("physical-disks");
+
+        let sata = format!("{}/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0", PCI);
+        fixture.scsi_disk(&sata, Some("sda"), "sg0", "WDC WUH721818AL");
+        let sda1 = format!("{}/block/sda/sda1", sata);
+        fixture.write(&format!("{}/partition", sda1), b"1\n");
+        fixture.link("class/block/sda1", &sda1);
+
+        let sysfs = Sysfs::new(&fixture.root);
+        assert_eq!(sysfs.physical_disks("sda1").unwrap(), ["/dev/sda"]);
+        assert_eq!(sysfs.physical_disks("sda").unwrap(), ["/dev/sda"]);
+    }
+
+    #[test]
+    fn physical_disks_of_stacked_devices() {
+        let fixture = Fixture::new("stacked");
+
+        for (n, name) in ["sda", "sdb", "sdc"].iter().enumerate() {
+            let path = format!(
+                "{}/0000:00:17.0/ata{}/host{}/target{}:0:0/{}:0:0:0",
+                PCI,
+                n + 1,
+                n,
+                n,
+                n
+            );
+            fixture.scsi_disk(&path, Some(name), &format!("sg{}", n), "WDC WUH721818AL");
+            if *name == "sdc" {
+                let sdc1 = format!("{}/block/sdc/sdc1", path);
+                fixture.write(&format!("{}/partition", sdc1), b"1\n");
+                fixture.link("class/block/sdc1", &sdc1);
+            }
+        }
+
+        // md0 over LVM volume vg-lv on sda and sdb, dm-1 on sdb again, and sdc1
+        let dm = |name: &str, dm_name: &str, slaves: &[&str]| {
+            let dir = format!("devices/virtual/block/{}", name);
+            fixture.write(
+                &format!("{}/dm/name", dir),
+                format!("{}\n", dm_name).as_bytes(),
+            );
+            for slave in slaves {
+                fixture.link(
+                    &format!("{}/slaves/{}", dir, slave),
+                    &format!("class/block/{}", slave),
+                );
+            }
+            fixture.link(&format!("block/{}", name), &dir);
+            fixture.link(&format!("class/block/{}", name), &dir);
+        };
+        dm("dm-0", "vg-lv", &["sda", "sdb"]);
+        dm("dm-1", "vg-cache", &["sdb"]);
+        for slave in ["dm-0", "dm-1", "sdc1"] {
+            fixture.link(
+                &format!("devices/virtual/block/md0/slaves/{}", slave),
+                &format!("class/block/{}", slave),
+            );
+        }
+        fixture.link("class/block/md0", "devices/virtual/block/md0");
+
+        let sysfs = Sysfs::new(&fixture.root);
+        assert_eq!(
+            sysfs.physical_disks("md0").unwrap(),
+            ["/dev/sda", "/dev/sdb", "/dev/sdc"]
+        );
+        assert_eq!(sysfs.physical_disks("dm-1").unwrap(), ["/dev/sdb"]);
+        assert_eq!(sysfs.dm_by_name("vg-lv").unwrap(), "dm-0");
+        assert!(sysfs.physical_disks("md1").is_err());
+    }
+
+    const MOUNTINFO: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+30 22 8:17 / /srv rw,relatime shared:2 - xfs /dev/sdb1 rw
+31 22 8:33 / /srvx rw,relatime shared:3 - ext4 /dev/sdc1 rw
+32 30 0:45 / /srv/my\\040data rw,relatime shared:4 - btrfs /dev/sdd rw
+33 22 0:50 / /tank rw,relatime shared:5 - zfs tank/data rw,xattr
+";
+
+    fn devno(mountinfo: &str, path: &str) -> String {
+        find_mount(mountinfo, Path::new(path)).unwrap().devno
+    }
+
+    #[test]
+    fn mount_longest_prefix() {
+        assert_eq!(devno(MOUNTINFO, "/"), "8:2");
+        assert_eq!(devno(MOUNTINFO, "/srv"), "8:17");
+        assert_eq!(devno(MOUNTINFO, "/srv/archive"), "8:17");
+        // path components, not string prefix
+        assert_eq!(devno(MOUNTINFO, "/srvx/archive"), "8:33");
+        assert_eq!(devno(MOUNTINFO, "/srvfoo"), "8:2");
+
+        let mount = find_mount(MOUNTINFO, Path::new("/srv/my data/2024")).unwrap();
+        assert_eq!(
+            (
+                mount.devno.as_str(),
+                mount.fstype.as_str(),
+                mount.source.as_str()
+            ),
+            ("0:45", "btrfs", "/dev/sdd")
+        );
+        let mount = find_mount(MOUNTINFO, Path::new("/tank")).unwrap();
+        assert_eq!(
+            (mount.fstype.as_str(), mount.source.as_str()),
+            ("zfs", "tank/data")
+        );
+
+        assert!(find_mount("", Path::new("/srv")).is_none());
+    }
+
+    #[test]
+    fn mount_shadowed() {
+        let mountinfo = format!("{}40 30 8:49 / /srv rw - ext4 /dev/sde1 rw\n", MOUNTINFO);
+        assert_eq!(devno(&mountinfo, "/srv/archive"), "8:49");
+        assert_eq!(devno(&mountinfo, "/srv/my data"), "0:45");
+    }
+
+    #[test]
+    fn unescape() {
+        assert_eq!(unescape_mountinfo("/srv/my\\040data"), "/srv/my data");
+        assert_eq!(unescape_mountinfo("a\\011b\\134c"), "a\tb\\c");
+        // not an octal escape, kept as is
+        assert_eq!(unescape_mountinfo("a\\09b"), "a\\09b");
+        assert_eq!(unescape_mountinfo("a\\"), "a\\");
+        assert_eq!(unescape_mountinfo("plain"), "plain");
+    }
+}
@@ -0,0 +1,415 @@
+//! In-process drive answering ATA PASS-THROUGH commands, for tests
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::Result;
+
+use crate::device::{Device, PassthroughType, PowerCondDescriptor, PowerMode};
+use crate::transport::{ScsiStatus, Transport};
+
+/// Drive state, shared by every clone of a [`FakeDrive`]
+pub struct DriveState {
+    pub model: String,
+    pub serial: String,
+    pub wwn: Option<u64>,
+    pub mode: PowerMode,
+
+    pub epc_supported: bool,
+    pub epc_enabled: bool,
+    pub apm_supported: bool,
+    pub apm_level: Option<u8>,
+
+    /// idle a to standby z
+    pub conditions: [PowerCondDescriptor; 5],
+    /// size of the power conditions log in the log directory
+    pub power_conditions_log_pages: u8,
+
+    /// return fixed format sense, as libata does with D_SENSE=0
+    pub fixed_sense: bool,
+    /// reject ATA PASS-THROUGH(16), as some USB bridges do
+    pub sat12_only: bool,
+    /// EPC subcommand accepted without effect
+    pub ignored_subcommand: Option<u8>,
+    /// SET FEATURES failing, counted from 0 over every SET FEATURES received
+    pub failing_set_features: Option<usize>,
+
+    /// CDBs received, in order
+    pub commands: Vec<Vec<u8>>,
+}
+
+/// ATA registers of a pass-through CDB
+#[derive(Debug, Copy, Clone)]
+pub struct Registers {
+    pub feature: u8,
+    pub sector_count: u8,
+    pub lba: u32,
+    pub command: u8,
+}
+
+impl Registers {
+    pub fn of(cdb: &[u8]) -> Option<Registers> {
+        match cdb[0] {
+            0x85 => Some(Registers {
+                feature: cdb[4],
+                sector_count: cdb[6],
+                lba: u32::from_le_bytes([cdb[8], cdb[10], cdb[12], cdb[7]]),
+                command: cdb[14],
+            }),
+            0xa1 => Some(Registers {
+                feature: cdb[3],
+                sector_count: cdb[4],
+                lba: u32::from_le_bytes([cdb[5], cdb[6], cdb[7], cdb[8] & 0x0f]),
+                command: cdb[9],
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl DriveState {
+    /// Registers of SET FEATURES commands received, in order
+    pub fn set_features(&self) -> Vec<Registers> {
+        self.commands
+            .iter()
+            .filter_map(|cdb| Registers::of(cdb))
+            .filter(|it| it.command == 0xef)
+            .collect()
+    }
+
+    pub fn condition(&mut self, mode: PowerMode) -> &mut PowerCondDescriptor {
+        let index = PowerMode::CONDITIONS
+            .iter()
+            .position(|it| *it == mode)
+            .unwrap();
+        &mut self.conditions[index]
+    }
+}
+
+#[derive(Clone)]
+pub struct FakeDrive(Arc<Mutex<DriveState>>);
+
+impl FakeDrive {
+    /// Drive with EPC enabled, idle a and standby z enabled, every condition savable
+    pub fn new() -> FakeDrive {
+        let condition = |timer: u32, enable: bool| PowerCondDescriptor {
+            supported: true,
+            savable: true,
+            changeable: true,
+            default_enable: enable,
+            saved_enable: enable,
+            current_enable: enable,
+            default_timer: timer,
+            saved_timer: timer,
+            current_timer: timer,
+            recovery_time: 10,
+            min_timer: 1,
+            max_timer: 0xffff,
+        };
+
+        FakeDrive(Arc::new(Mutex::new(DriveState {
+            model: "WDC WUH721818ALE6L4".to_string(),
+            serial: "3WJ0ABCD".to_string(),
+            wwn: Some(0x5000_cca2_9ac1_2345),
+            mode: PowerMode::Active,
+            epc_supported: true,
+            epc_enabled: true,
+            apm_supported: true,
+            apm_level: None,
+            conditions: [
+                condition(20, true),
+                condition(1200, false),
+                condition(6000, false),
+                condition(6000, false),
+                condition(9000, true),
+            ],
+            power_conditions_log_pages: 2,
+            fixed_sense: false,
+            sat12_only: false,
+            ignored_subcommand: None,
+            failing_set_features: None,
+            commands: Vec::new(),
+        })))
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, DriveState> {
+        self.0.lock().unwrap()
+    }
+
+    /// Device over this drive with ATA PASS-THROUGH(16)
+    pub fn device(&self) -> Device {
+        Device::with_transport(Box::new(self.clone()), PassthroughType::Sat16).unwrap()
+    }
+}
+
+impl Transport for FakeDrive {
+    fn sg_io(
+        &self,
+        cdb: &mut [u8],
+        _in_data: Option<&[u8]>,
+        out_data: Option<&mut [u8]>,
+    ) -> Result<(ScsiStatus, [u8; 32])> {
+        let mut state = self.state();
+        state.commands.push(cdb.to_vec());
+        anyhow::ensure!(!(state.sat12_only && cdb[0] == 0x85), "invalid opcode");
+        let registers = Registers::of(cdb).ok_or_else(|| anyhow::anyhow!("invalid opcode"))?;
+
+        let good = ScsiStatus {
+            status: 0,
+            host_status: 0,
+        };
+        let mut sense = [0u8; 32];
+        match registers.command {
+            // CHECK POWER MODE
+            0xe5 => {
+                sense = ata_return(sector_count(state.mode), state.fixed_sense);
+            }
+            0xec => identify(&state, out_data.unwrap()),
+            0x2f | 0x47 => read_log(&state, registers.lba as u8, out_data.unwrap()),
+            0xef => {
+                if !set_features(&mut state, registers)? {
+                    let check_condition = ScsiStatus {
+                        status: 0x02,
+                        host_status: 0,
+                    };
+                    return Ok((check_condition, ata_abort(state.fixed_sense)));
+                }
+            }
+            command => anyhow::bail!("unsupported command {:#04x}", command),
+        }
+
+        Ok((good, sense))
+    }
+}
+
+fn sector_count(mode: PowerMode) -> u8 {
+    match mode {
+        PowerMode::Active | PowerMode::Unknown => 0xff,
+        PowerMode::IdleA => 0x81,
+        PowerMode::IdleB => 0x82,
+        PowerMode::IdleC => 0x83,
+        PowerMode::StandbyY => 0x01,
+        PowerMode::StandbyZ => 0x00,
+    }
+}
+
+/// Sense data with ATA output registers, status 0x50 and sector count `count`
+pub fn ata_return(count: u8, fixed: bool) -> [u8; 32] {
+    let mut sense = [0u8; 32];
+    if fixed {
+        sense[0] = 0x70;
+        sense[2] = 0x01; // RECOVERED ERROR
+        sense[4] = 0x50; // status
+        sense[6] = count;
+        sense[7] = 10;
+        sense[13] = 0x1d; // ATA PASS THROUGH INFORMATION AVAILABLE
+    } else {
+        sense[0] = 0x72;
+        sense[1] = 0x01;
+        sense[3] = 0x1d;
+        sense[7] = 14;
+        // ATA Status Return descriptor
+        sense[8] = 0x09;
+        sense[9] = 0x0c;
+        sense[13] = count;
+        sense[21] = 0x50;
+    }
+
+    sense
+}
+
+/// Sense data of a command the drive aborted, as libata returns it: ABORTED COMMAND with
+/// status 0x51 and error 0x04 in the ATA output registers
+pub fn ata_abort(fixed: bool) -> [u8; 32] {
+    let mut sense = [0u8; 32];
+    if fixed {
+        sense[0] = 0x70;
+        sense[2] = 0x0b; // ABORTED COMMAND
+        sense[3] = 0x04; // error
+        sense[4] = 0x51; // status
+        sense[7] = 10;
+    } else {
+        sense[0] = 0x72;
+        sense[1] = 0x0b;
+        sense[7] = 14;
+        // ATA Status Return descriptor
+        sense[8] = 0x09;
+        sense[9] = 0x0c;
+        sense[11] = 0x04;
+        sense[21] = 0x51;
+    }
+
+    sense
+}
+
+fn identify(state: &DriveState, out: &mut [u8]) {
+    let mut words = [0u16; 256];
+    let mut string = |from: usize, to: usize, value: &str| {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize((to - from + 1) * 2, b' ');
+        for (n, pair) in bytes.chunks(2).enumerate() {
+            words[from + n] = u16::from_be_bytes([pair[0], pair[1]]);
+        }
+    };
+    string(10, 19, &state.serial);
+    string(23, 26, "81.00A81");
+    string(27, 46, &state.model);
+
+    if state.apm_supported {
+        words[83] |= 1 << 3;
+    }
+    if let Some(level) = state.apm_level {
+        words[86] |= 1 << 3;
+        words[91] = level as u16;
+    }
+    if let Some(wwn) = state.wwn {
+        words[87] |= 1 << 8;
+        for n in 0..4 {
+            words[108 + n] = (wwn >> (48 - 16 * n)) as u16;
+        }
+    }
+    if state.epc_supported {
+        words[119] |= 1 << 7;
+    }
+    if state.epc_enabled {
+        words[120] |= 1 << 7;
+    }
+
+    for (raw, word) in out.chunks_mut(2).zip(words.iter()) {
+        raw.copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+fn read_log(state: &DriveState, page: u8, out: &mut [u8]) {
+    out.iter_mut().for_each(|it| *it = 0);
+    match page {
+        // general purpose log directory
+        0x00 => out[0x08 * 2] = state.power_conditions_log_pages,
+        0x08 if out.len() >= 1024 => {
+            let offsets = [0, 64, 128, 512 + 384, 512 + 448];
+            for (offset, desc) in offsets.iter().zip(state.conditions.iter()) {
+                power_cond_desc(desc, &mut out[*offset..*offset + 64]);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn power_cond_desc(desc: &PowerCondDescriptor, raw: &mut [u8]) {
+    let flags = [
+        desc.supported,
+        desc.savable,
+        desc.changeable,
+        desc.default_enable,
+        desc.saved_enable,
+        desc.current_enable,
+    ];
+    raw[1] = flags
+        .iter()
+        .enumerate()
+        .fold(0, |flag, (n, set)| flag | (*set as u8) << (7 - n));
+
+    let timers = [
+        desc.default_timer,
+        desc.saved_timer,
+        desc.current_timer,
+        desc.recovery_time,
+        desc.min_timer,
+        desc.max_timer,
+    ];
+    for (n, timer) in timers.iter().enumerate() {
+        raw[4 + n * 4..8 + n * 4].copy_from_slice(&timer.to_le_bytes());
+    }
+}
+
+/// Run SET FEATURES, false if the drive aborts it
+fn set_features(state: &mut DriveState, registers: Registers) -> Result<bool> {
+    let index = state.set_features().len() - 1;
+    if state.failing_set_features == Some(index) {
+        return Ok(false);
+    }
+
+    match registers.feature {
+        0x05 => {
+            if !state.apm_supported {
+                return Ok(false);
+            }
+            state.apm_level = Some(registers.sector_count);
+        }
+        0x85 => state.apm_level = None,
+        0x4a => return epc(state, registers),
+        feature => anyhow::bail!("unsupported feature {:#04x}", feature),
+    }
+
+    Ok(true)
+}
+
+fn epc(state: &mut DriveState, registers: Registers) -> Result<bool> {
+    let subcommand = registers.lba as u8 & 0x0f;
+    if state.ignored_subcommand == Some(subcommand) {
+        return Ok(true);
+    }
+
+    // power condition commands are aborted while EPC is disabled
+    if !(state.epc_enabled || subcommand == 0x04 || subcommand == 0x05) {
+        return Ok(false);
+    }
+
+    let lba = registers.lba;
+    let enable = lba & 1 << 5 != 0;
+    let save = lba & 1 << 4 != 0;
+    let modes: Vec<PowerMode> = match registers.sector_count {
+        0xff => PowerMode::CONDITIONS.to_vec(),
+        id => vec![PowerMode::from_sector_count(id as u16)],
+    };
+
+    match subcommand {
+        // restore
+        0x00 => {
+            for mode in modes {
+                let desc = state.condition(mode);
+                let (timer, enable) = if lba & 1 << 6 != 0 {
+                    (desc.default_timer, desc.default_enable)
+                } else {
+                    (desc.saved_timer, desc.saved_enable)
+                };
+                desc.current_timer = timer;
+                desc.current_enable = enable;
+                if save {
+                    desc.saved_timer = timer;
+                    desc.saved_enable = enable;
+                }
+            }
+        }
+        0x01 => state.mode = modes[0],
+        // set timer
+        0x02 => {
+            for mode in modes {
+                let desc = state.condition(mode);
+                desc.current_timer = lba >> 8 & 0xffff;
+                desc.current_enable = enable;
+                if save {
+                    desc.saved_timer = desc.current_timer;
+                    desc.saved_enable = enable;
+                }
+            }
+        }
+        // set state
+        0x03 => {
+            for mode in modes {
+                let desc = state.condition(mode);
+                desc.current_enable = enable;
+                if save {
+                    desc.saved_enable = enable;
+                }
+            }
+        }
+        0x04 => {
+            state.epc_enabled = true;
+            state.apm_level = None;
+        }
+        0x05 => state.epc_enabled = false,
+        subcommand => anyhow::bail!("unsupported EPC subcommand {:#04x}", subcommand),
+    }
+
+    Ok(true)
+}
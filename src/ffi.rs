@@ -13,6 +13,14 @@ pub const SG_DXFER_NONE: c_int = -1;
 pub const SG_DXFER_TO_DEV: c_int = -2;
 pub const SG_DXFER_FROM_DEV: c_int = -3;
 
+/// `sg_io_v4` guard
+pub const SG_IO_V4_GUARD: c_int = 'Q' as c_int;
+pub const BSG_PROTOCOL_SCSI: c_uint = 0;
+pub const BSG_SUB_PROTOCOL_SCSI_CMD: c_uint = 0;
+
+/// `_IOWR('N', 0x41, struct nvme_admin_cmd)`
+pub const NVME_IOCTL_ADMIN_CMD: c_ulong = 0xc048_4e41;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct SgIoHdr {
@@ -69,11 +77,130 @@ impl Default for SgIoHdr {
     }
 }
 
+/// SG_IO v4 header, used by bsg nodes and sg nodes
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SgIoV4 {
+    pub guard: c_int,
+    pub protocol: c_uint,
+    pub subprotocol: c_uint,
+    pub request_len: c_uint,
+    pub request: u64,
+    pub request_tag: u64,
+    pub request_attr: c_uint,
+    pub request_priority: c_uint,
+    pub request_extra: c_uint,
+    pub max_response_len: c_uint,
+    pub response: u64,
+    pub dout_iovec_count: c_uint,
+    pub dout_xfer_len: c_uint,
+    pub din_iovec_count: c_uint,
+    pub din_xfer_len: c_uint,
+    pub dout_xferp: u64,
+    pub din_xferp: u64,
+    pub timeout: c_uint,
+    pub flags: c_uint,
+    pub usr_ptr: u64,
+    pub spare_in: c_uint,
+    pub driver_status: c_uint,
+    pub transport_status: c_uint,
+    pub device_status: c_uint,
+    pub retry_delay: c_uint,
+    pub info: c_uint,
+    pub duration: c_uint,
+    pub response_len: c_uint,
+    pub din_resid: c_int,
+    pub dout_resid: c_int,
+    pub generated_tag: u64,
+    pub spare_out: c_uint,
+    pub padding: c_uint,
+}
+
+const _: () = assert!(std::mem::size_of::<SgIoV4>() == 160);
+
+impl Default for SgIoV4 {
+    fn default() -> Self {
+        SgIoV4 {
+            guard: SG_IO_V4_GUARD,
+            protocol: BSG_PROTOCOL_SCSI,
+            subprotocol: BSG_SUB_PROTOCOL_SCSI_CMD,
+            request_len: 0,
+            request: 0,
+            request_tag: 0,
+            request_attr: 0,
+            request_priority: 0,
+            request_extra: 0,
+            max_response_len: 0,
+            response: 0,
+            dout_iovec_count: 0,
+            dout_xfer_len: 0,
+            din_iovec_count: 0,
+            din_xfer_len: 0,
+            dout_xferp: 0,
+            din_xferp: 0,
+            timeout: 0,
+            flags: 0,
+            usr_ptr: 0,
+            spare_in: 0,
+            driver_status: 0,
+            transport_status: 0,
+            device_status: 0,
+            retry_delay: 0,
+            info: 0,
+            duration: 0,
+            response_len: 0,
+            din_resid: 0,
+            dout_resid: 0,
+            generated_tag: 0,
+            spare_out: 0,
+            padding: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NvmeAdminCmd {
+    pub opcode: c_uchar,
+    pub flags: c_uchar,
+    pub rsvd1: c_ushort,
+    pub nsid: c_uint,
+    pub cdw2: c_uint,
+    pub cdw3: c_uint,
+    pub metadata: u64,
+    pub addr: u64,
+    pub metadata_len: c_uint,
+    pub data_len: c_uint,
+    pub cdw10: c_uint,
+    pub cdw11: c_uint,
+    pub cdw12: c_uint,
+    pub cdw13: c_uint,
+    pub cdw14: c_uint,
+    pub cdw15: c_uint,
+    pub timeout_ms: c_uint,
+    pub result: c_uint,
+}
+
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum NvmeAdminOpcode {
+    SetFeatures = 0x09,
+    GetFeatures = 0x0a,
+    Identify = 0x06,
+}
+
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum NvmeFeature {
+    PowerManagement = 0x02,
+    AutonomousPowerStateTransition = 0x0c,
+}
+
 #[derive(Copy, Clone)]
 #[repr(u8)]
-#[allow(dead_code)]
 pub enum AtaCmd {
     CheckPowerMode = 0xe5,
+    IdentifyDevice = 0xec,
     ReadLogExt = 0x2f,
     ReadLogExtDma = 0x47,
     SetFeature = 0xef,
@@ -83,6 +210,7 @@ impl AtaCmd {
     pub fn ck_cond(&self) -> bool {
         match self {
             AtaCmd::CheckPowerMode => true,
+            AtaCmd::IdentifyDevice => false,
             AtaCmd::ReadLogExt => false,
             AtaCmd::ReadLogExtDma => false,
             AtaCmd::SetFeature => false,
@@ -92,7 +220,6 @@ impl AtaCmd {
 
 #[derive(Copy, Clone)]
 #[repr(u8)]
-#[allow(dead_code)]
 pub enum Protocol {
     InDma = 10 << 1,
     OutDma = 11 << 1,
@@ -127,8 +254,7 @@ pub fn build_ata_passthrough16(
     cdb[0] = ATA_16; // opcode
     cdb[1] = protocol as u8 | 1; // proto, extend
                                  // off_line = 0, ck_cond = ?, t_dir = ?, byt_blok = 1, t_length = 02h(sector count)
-    cdb[2] =
-        0b000 << 6 | if cmd.ck_cond() { 1 << 5 } else { 0 } | protocol.t_dir() << 3 | 1 << 2 | 0x2;
+    cdb[2] = if cmd.ck_cond() { 1 << 5 } else { 0 } | protocol.t_dir() << 3 | 1 << 2 | 0x2;
 
     cdb[3] = (feature >> 8) as u8;
     cdb[4] = feature as u8;
@@ -173,8 +299,7 @@ pub fn build_ata_passthrough12(
     cdb[0] = ATA_12; // opcode
     cdb[1] = protocol as u8; // proto, extend = 0
                              // off_line = 0, ck_cond = ?, t_dir = ?, byt_blok = 1, t_length = 02h(sector count)
-    cdb[2] =
-        0b000 << 6 | if cmd.ck_cond() { 1 << 5 } else { 0 } | protocol.t_dir() << 3 | 1 << 2 | 0x2;
+    cdb[2] = if cmd.ck_cond() { 1 << 5 } else { 0 } | protocol.t_dir() << 3 | 1 << 2 | 0x2;
 
     // features
     cdb[3] = feature as u8;
@@ -205,3 +330,503 @@ pub fn build_ata_passthrough12(
 
     cdb
 }
+
+/// ATA command registers, independent of the pass-through encoding
+#[derive(Copy, Clone)]
+pub struct AtaTaskfile {
+    pub cmd: AtaCmd,
+    pub protocol: Protocol,
+    pub feature: c_ushort,
+    pub sector_count: c_ushort,
+    /// 48 bit LBA, bits 7:0 are sector number, bits 23:8 are cylinder
+    pub lba: u64,
+}
+
+impl AtaTaskfile {
+    /// Whether registers fit into a 28 bit command, as required by 12 bytes CDB
+    pub fn fits_28bit(&self) -> bool {
+        self.feature <= 0xff && self.sector_count <= 0xff && self.lba <= 0x0fff_ffff
+    }
+
+    pub fn to_passthrough12(self) -> [u8; ATA_12_LEN] {
+        let mut cdb = build_ata_passthrough12(
+            self.cmd,
+            self.protocol,
+            self.feature,
+            self.sector_count,
+            self.lba as c_ushort & 0xff,
+            (self.lba >> 8) as c_ushort,
+        );
+        // LBA bits 27:24 are in the device register
+        cdb[8] |= (self.lba >> 24) as u8 & 0x0f;
+
+        cdb
+    }
+
+    pub fn to_passthrough16(self) -> [u8; ATA_16_LEN] {
+        let byte = |n: u32| (self.lba >> (n * 8)) as u8;
+
+        build_ata_passthrough16(
+            self.cmd,
+            self.protocol,
+            self.feature,
+            self.sector_count,
+            c_ushort::from_le_bytes([byte(0), byte(3)]),
+            c_uint::from_le_bytes([byte(1), byte(4), byte(2), byte(5)]),
+        )
+    }
+}
+
+pub const JMICRON_LEN: usize = 12;
+pub const SUNPLUS_LEN: usize = 12;
+pub const CYPRESS_LEN: usize = 16;
+
+pub const JMICRON: u8 = 0xdf;
+pub const SUNPLUS: u8 = 0xf8;
+pub const CYPRESS: u8 = 0x24;
+
+/// JMicron register block address of port 0
+pub const JMICRON_REGISTERS: c_ushort = 0x8000;
+pub const JMICRON_REGISTERS_LEN: usize = 16;
+pub const SUNPLUS_REGISTERS_LEN: usize = 8;
+pub const CYPRESS_REGISTERS_LEN: usize = 8;
+
+/// JMicron vendor pass-through, `data_len` is the transfer length in bytes
+pub fn build_jmicron(tf: &AtaTaskfile, data_len: usize) -> [u8; JMICRON_LEN] {
+    let mut cdb: [u8; JMICRON_LEN] = [0; JMICRON_LEN];
+    cdb[0] = JMICRON;
+    // read from device
+    cdb[1] = tf.protocol.t_dir() << 4;
+
+    // transfer length
+    cdb[3] = (data_len >> 8) as u8;
+    cdb[4] = data_len as u8;
+
+    cdb[5] = tf.feature as u8;
+    cdb[6] = tf.sector_count as u8;
+    cdb[7] = tf.lba as u8;
+    cdb[8] = (tf.lba >> 8) as u8;
+    cdb[9] = (tf.lba >> 16) as u8;
+
+    // device, port 0
+    cdb[10] = 0xa0;
+    cdb[11] = tf.cmd as u8;
+
+    cdb
+}
+
+/// Read JMicron output registers after a command
+pub fn build_jmicron_registers() -> [u8; JMICRON_LEN] {
+    let mut cdb: [u8; JMICRON_LEN] = [0; JMICRON_LEN];
+    cdb[0] = JMICRON;
+    cdb[1] = 0x10;
+
+    cdb[3] = (JMICRON_REGISTERS_LEN >> 8) as u8;
+    cdb[4] = JMICRON_REGISTERS_LEN as u8;
+
+    cdb[6] = (JMICRON_REGISTERS >> 8) as u8;
+    cdb[7] = JMICRON_REGISTERS as u8;
+
+    // read registers
+    cdb[11] = 0xfd;
+
+    cdb
+}
+
+/// Sunplus vendor pass-through, `data_len` is the transfer length in bytes
+pub fn build_sunplus(tf: &AtaTaskfile, data_len: usize) -> [u8; SUNPLUS_LEN] {
+    let mut cdb: [u8; SUNPLUS_LEN] = [0; SUNPLUS_LEN];
+    cdb[0] = SUNPLUS;
+    // subcommand, pass through
+    cdb[2] = 0x22;
+
+    cdb[3] = match (data_len, tf.protocol.t_dir()) {
+        (0, _) => 0x00,
+        (_, 1) => 0x10,
+        _ => 0x11,
+    };
+    // transfer length in sectors
+    cdb[4] = (data_len >> 9) as u8;
+
+    cdb[5] = tf.feature as u8;
+    cdb[6] = tf.sector_count as u8;
+    cdb[7] = tf.lba as u8;
+    cdb[8] = (tf.lba >> 8) as u8;
+    cdb[9] = (tf.lba >> 16) as u8;
+
+    cdb[10] = 0xa0;
+    cdb[11] = tf.cmd as u8;
+
+    cdb
+}
+
+/// Read Sunplus output registers after a command
+pub fn build_sunplus_registers() -> [u8; SUNPLUS_LEN] {
+    let mut cdb: [u8; SUNPLUS_LEN] = [0; SUNPLUS_LEN];
+    cdb[0] = SUNPLUS;
+    // subcommand, get status
+    cdb[2] = 0x21;
+
+    cdb
+}
+
+/// Cypress ATACB pass-through, `data_len` is the transfer length in bytes
+pub fn build_cypress(tf: &AtaTaskfile, data_len: usize) -> [u8; CYPRESS_LEN] {
+    let mut cdb: [u8; CYPRESS_LEN] = [0; CYPRESS_LEN];
+    cdb[0] = CYPRESS;
+    cdb[1] = 0x24;
+    // identify packet device, required for IDENTIFY DEVICE
+    if let AtaCmd::IdentifyDevice = tf.cmd {
+        cdb[2] = 1 << 7;
+    }
+
+    // register select, skip device control and device
+    cdb[3] = 0xff - (1 << 0) - (1 << 6);
+    // transfer block count
+    cdb[4] = ((data_len + 511) >> 9) as u8;
+
+    cdb[6] = tf.feature as u8;
+    cdb[7] = tf.sector_count as u8;
+    cdb[8] = tf.lba as u8;
+    cdb[9] = (tf.lba >> 8) as u8;
+    cdb[10] = (tf.lba >> 16) as u8;
+
+    cdb[12] = tf.cmd as u8;
+
+    cdb
+}
+
+/// Read Cypress ATACB output registers after a command
+pub fn build_cypress_registers() -> [u8; CYPRESS_LEN] {
+    let mut cdb: [u8; CYPRESS_LEN] = [0; CYPRESS_LEN];
+    cdb[0] = CYPRESS;
+    cdb[1] = 0x24;
+    // taskfile read
+    cdb[2] = 1;
+
+    cdb
+}
+
+/// `_IOWR('M', 1, struct megasas_iocpacket)`
+pub const MEGASAS_IOC_FIRMWARE: c_ulong = 0xc194_4d01;
+pub const MEGASAS_IOCTL_NODE: &str = "/dev/megaraid_sas_ioctl_node";
+
+pub const MEGASAS_FRAME_LEN: usize = 128;
+pub const MEGASAS_MAX_IOCTL_SGE: usize = 16;
+
+/// physical device SCSI I/O
+pub const MFI_CMD_PD_SCSI_IO: u8 = 0x04;
+pub const MFI_FRAME_DIR_NONE: c_ushort = 0x0000;
+pub const MFI_FRAME_DIR_WRITE: c_ushort = 0x0008;
+pub const MFI_FRAME_DIR_READ: c_ushort = 0x0010;
+
+pub const MFI_STAT_OK: u8 = 0x00;
+pub const MFI_STAT_SCSI_DONE_WITH_ERROR: u8 = 0x2d;
+
+/// offset of `sense_buf_phys_addr_lo` in pass-through frame
+pub const MEGASAS_PTHRU_SENSE_OFF: usize = 0x18;
+/// offset of `sgl` in pass-through frame
+pub const MEGASAS_PTHRU_SGL_OFF: usize = 0x30;
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct MegasasIocPacket {
+    pub host_no: c_ushort,
+    pub pad1: c_ushort,
+    pub sgl_off: c_uint,
+    pub sge_count: c_uint,
+    pub sense_off: c_uint,
+    pub sense_len: c_uint,
+    pub frame: [u8; MEGASAS_FRAME_LEN],
+    pub sgl: [libc::iovec; MEGASAS_MAX_IOCTL_SGE],
+}
+
+/// Build MegaRAID physical device pass-through frame wrapping `cdb`
+///
+/// `data` and `sense` are user space addresses, the driver copies data from/to them
+pub fn build_megaraid_pthru(
+    target_id: u8,
+    cdb: &[u8],
+    flags: c_ushort,
+    data: u64,
+    data_len: u32,
+    sense: u64,
+    sense_len: u8,
+) -> [u8; MEGASAS_FRAME_LEN] {
+    let mut frame = [0u8; MEGASAS_FRAME_LEN];
+    frame[0x00] = MFI_CMD_PD_SCSI_IO;
+    frame[0x01] = sense_len;
+    // cmd_status, overwritten by firmware
+    frame[0x02] = 0xff;
+    frame[0x04] = target_id;
+    frame[0x06] = cdb.len() as u8;
+    frame[0x07] = if data_len > 0 { 1 } else { 0 };
+
+    frame[0x10..0x12].copy_from_slice(&flags.to_le_bytes());
+    frame[0x14..0x18].copy_from_slice(&data_len.to_le_bytes());
+    frame[MEGASAS_PTHRU_SENSE_OFF..MEGASAS_PTHRU_SENSE_OFF + 8]
+        .copy_from_slice(&sense.to_le_bytes());
+
+    frame[0x20..0x20 + cdb.len()].copy_from_slice(cdb);
+
+    // sge32, address is replaced by the driver
+    if data_len > 0 {
+        frame[MEGASAS_PTHRU_SGL_OFF..MEGASAS_PTHRU_SGL_OFF + 4]
+            .copy_from_slice(&(data as u32).to_le_bytes());
+        frame[MEGASAS_PTHRU_SGL_OFF + 4..MEGASAS_PTHRU_SGL_OFF + 8]
+            .copy_from_slice(&data_len.to_le_bytes());
+    }
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passthrough12_lba_28bit() {
+        let tf = AtaTaskfile {
+            cmd: AtaCmd::SetFeature,
+            protocol: Protocol::None,
+            feature: 0x4a,
+            sector_count: 0x81,
+            lba: 0x0abc_def2,
+        };
+        assert!(tf.fits_28bit());
+
+        let cdb = tf.to_passthrough12();
+        assert_eq!(
+            cdb,
+            [0xa1, 0x06, 0x06, 0x4a, 0x81, 0xf2, 0xde, 0xbc, 0xaa, 0xef, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn passthrough16_lba_48bit() {
+        let tf = AtaTaskfile {
+            cmd: AtaCmd::ReadLogExtDma,
+            protocol: Protocol::InDma,
+            feature: 0,
+            sector_count: 0x0102,
+            lba: 0x6655_4433_2211,
+        };
+
+        let cdb = tf.to_passthrough16();
+        assert_eq!(
+            cdb,
+            [
+                0x85, 0x15, 0x0e, 0x00, 0x00, 0x01, 0x02, 0x44, 0x11, 0x55, 0x22, 0x66, 0x33, 0xa0,
+                0x47, 0x00
+            ]
+        );
+    }
+
+    /// READ LOG EXT of the power conditions log, 2 pages by PIO
+    const READ_LOG: AtaTaskfile = AtaTaskfile {
+        cmd: AtaCmd::ReadLogExt,
+        protocol: Protocol::PioIn,
+        feature: 0,
+        sector_count: 2,
+        lba: 0x08,
+    };
+
+    /// SET FEATURES EPC, set timer of idle a, no data
+    const SET_TIMER: AtaTaskfile = AtaTaskfile {
+        cmd: AtaCmd::SetFeature,
+        protocol: Protocol::None,
+        feature: 0x4a,
+        sector_count: 0x81,
+        lba: 0x12_3402,
+    };
+
+    #[test]
+    fn jmicron_cdb() {
+        assert_eq!(
+            build_jmicron(&READ_LOG, 1024),
+            [0xdf, 0x10, 0x00, 0x04, 0x00, 0x00, 0x02, 0x08, 0x00, 0x00, 0xa0, 0x2f]
+        );
+        assert_eq!(
+            build_jmicron(&SET_TIMER, 0),
+            [0xdf, 0x00, 0x00, 0x00, 0x00, 0x4a, 0x81, 0x02, 0x34, 0x12, 0xa0, 0xef]
+        );
+        // read 16 bytes at 0x8000
+        assert_eq!(
+            build_jmicron_registers(),
+            [0xdf, 0x10, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0xfd]
+        );
+    }
+
+    #[test]
+    fn sunplus_cdb() {
+        assert_eq!(
+            build_sunplus(&READ_LOG, 1024),
+            [0xf8, 0x00, 0x22, 0x10, 0x02, 0x00, 0x02, 0x08, 0x00, 0x00, 0xa0, 0x2f]
+        );
+        assert_eq!(
+            build_sunplus(&SET_TIMER, 0),
+            [0xf8, 0x00, 0x22, 0x00, 0x00, 0x4a, 0x81, 0x02, 0x34, 0x12, 0xa0, 0xef]
+        );
+        assert_eq!(
+            build_sunplus_registers(),
+            [0xf8, 0x00, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn cypress_cdb() {
+        let identify = AtaTaskfile {
+            cmd: AtaCmd::IdentifyDevice,
+            protocol: Protocol::PioIn,
+            feature: 0,
+            sector_count: 1,
+            lba: 0,
+        };
+        assert_eq!(
+            build_cypress(&identify, 512),
+            [
+                0x24, 0x24, 0x80, 0xbe, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xec, 0x00,
+                0x00, 0x00
+            ]
+        );
+        assert_eq!(
+            build_cypress(&READ_LOG, 1024),
+            [
+                0x24, 0x24, 0x00, 0xbe, 0x02, 0x00, 0x00, 0x02, 0x08, 0x00, 0x00, 0x00, 0x2f, 0x00,
+                0x00, 0x00
+            ]
+        );
+        assert_eq!(
+            build_cypress(&SET_TIMER, 0),
+            [
+                0x24, 0x24, 0x00, 0xbe, 0x00, 0x00, 0x4a, 0x81, 0x02, 0x34, 0x12, 0x00, 0xef, 0x00,
+                0x00, 0x00
+            ]
+        );
+        assert_eq!(
+            build_cypress_registers(),
+            [
+                0x24, 0x24, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00
+            ]
+        );
+    }
+
+    /// IDENTIFY DEVICE as ATA PASS-THROUGH(16), reading 512 bytes
+    const IDENTIFY_CDB: [u8; 16] = [
+        0x85, 0x09, 0x0e, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa0, 0xec,
+        0x00,
+    ];
+
+    fn expected_frame(target_id: u8, cdb: &[u8], flags: u8, data_len: u32) -> Vec<u8> {
+        let mut frame = vec![0u8; MEGASAS_FRAME_LEN];
+        let len = data_len.to_le_bytes();
+        // cmd, sense_len, cmd_status, scsi_status, target_id, lun, cdb_len, sge_count
+        frame[0..8].copy_from_slice(&[
+            0x04,
+            32,
+            0xff,
+            0x00,
+            target_id,
+            0x00,
+            cdb.len() as u8,
+            (data_len > 0) as u8,
+        ]);
+        // flags, timeout
+        frame[0x10] = flags;
+        frame[0x14..0x18].copy_from_slice(&len);
+        // sense buffer address
+        frame[0x18..0x20].copy_from_slice(&[0x00, 0x20, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00]);
+        frame[0x20..0x20 + cdb.len()].copy_from_slice(cdb);
+        // sge32 address and length
+        if data_len > 0 {
+            frame[0x30..0x34].copy_from_slice(&[0x00, 0x10, 0x34, 0x12]);
+            frame[0x34..0x38].copy_from_slice(&len);
+        }
+
+        frame
+    }
+
+    #[test]
+    fn megaraid_frame_data_in() {
+        let frame = build_megaraid_pthru(
+            4,
+            &IDENTIFY_CDB,
+            MFI_FRAME_DIR_READ,
+            0x1234_1000,
+            512,
+            0x1234_2000,
+            32,
+        );
+
+        assert_eq!(frame.to_vec(), expected_frame(4, &IDENTIFY_CDB, 0x10, 512));
+    }
+
+    #[test]
+    fn megaraid_frame_data_out() {
+        let cdb = [0x8a, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0, 0];
+        let frame = build_megaraid_pthru(
+            11,
+            &cdb,
+            MFI_FRAME_DIR_WRITE,
+            0x1234_1000,
+            4096,
+            0x1234_2000,
+            32,
+        );
+
+        assert_eq!(frame.to_vec(), expected_frame(11, &cdb, 0x08, 4096));
+    }
+
+    #[test]
+    fn megaraid_frame_no_data() {
+        let cdb = [
+            0xa1, 0x06, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa0, 0xe5, 0x00, 0x00,
+        ];
+        let frame = build_megaraid_pthru(0, &cdb, MFI_FRAME_DIR_NONE, 0, 0, 0x1234_2000, 32);
+
+        assert_eq!(frame.to_vec(), expected_frame(0, &cdb, 0x00, 0));
+    }
+
+    #[test]
+    fn megaraid_packet_layout() {
+        use std::mem::{offset_of, size_of};
+
+        assert_eq!(size_of::<MegasasIocPacket>(), 404);
+        assert_eq!(offset_of!(MegasasIocPacket, host_no), 0);
+        assert_eq!(offset_of!(MegasasIocPacket, sgl_off), 4);
+        assert_eq!(offset_of!(MegasasIocPacket, sge_count), 8);
+        assert_eq!(offset_of!(MegasasIocPacket, sense_off), 12);
+        assert_eq!(offset_of!(MegasasIocPacket, sense_len), 16);
+        assert_eq!(offset_of!(MegasasIocPacket, frame), 20);
+        assert_eq!(offset_of!(MegasasIocPacket, sgl), 148);
+
+        // _IOWR('M', 1, struct megasas_iocpacket)
+        let ioc =
+            3 << 30 | (size_of::<MegasasIocPacket>() as c_ulong) << 16 | (b'M' as c_ulong) << 8 | 1;
+        assert_eq!(MEGASAS_IOC_FIRMWARE, ioc);
+    }
+
+    #[test]
+    fn sg_io_v4_layout() {
+        use std::mem::offset_of;
+
+        assert_eq!(offset_of!(SgIoV4, guard), 0);
+        assert_eq!(offset_of!(SgIoV4, protocol), 4);
+        assert_eq!(offset_of!(SgIoV4, subprotocol), 8);
+        assert_eq!(offset_of!(SgIoV4, request_len), 12);
+        assert_eq!(offset_of!(SgIoV4, request), 16);
+        assert_eq!(offset_of!(SgIoV4, max_response_len), 44);
+        assert_eq!(offset_of!(SgIoV4, response), 48);
+        assert_eq!(offset_of!(SgIoV4, dout_xfer_len), 60);
+        assert_eq!(offset_of!(SgIoV4, din_xfer_len), 68);
+        assert_eq!(offset_of!(SgIoV4, dout_xferp), 72);
+        assert_eq!(offset_of!(SgIoV4, din_xferp), 80);
+        assert_eq!(offset_of!(SgIoV4, timeout), 88);
+        assert_eq!(offset_of!(SgIoV4, driver_status), 108);
+        assert_eq!(offset_of!(SgIoV4, transport_status), 112);
+        assert_eq!(offset_of!(SgIoV4, device_status), 116);
+        assert_eq!(offset_of!(SgIoV4, response_len), 132);
+        assert_eq!(offset_of!(SgIoV4, generated_tag), 144);
+    }
+}
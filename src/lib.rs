@@ -0,0 +1,45 @@
+//! Control EPC (Extended Power Condition) of ATA drives, and power states of NVMe drives
+//!
+//! ```no_run
+//! use wdepc::{Device, PowerCondition, PowerMode};
+//!
+//! let mut device = Device::open("/dev/sda")?;
+//! if device.query_mode()? != PowerMode::StandbyZ {
+//!     device.set_timer(PowerCondition::StandbyZ, 36000, true, false)?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Commands need root, or `CAP_SYS_RAWIO` on the device node.
+
+#[cfg(feature = "async")]
+pub mod async_device;
+#[cfg(feature = "profiles")]
+pub mod backup;
+pub mod batch;
+pub mod capi;
+#[cfg(feature = "profiles")]
+pub mod changeset;
+pub mod device;
+#[cfg(feature = "profiles")]
+pub mod diff;
+pub mod discovery;
+#[cfg(test)]
+mod fake;
+pub mod ffi;
+pub mod nvme;
+#[cfg(feature = "profiles")]
+pub mod preset;
+#[cfg(feature = "profiles")]
+pub mod profile;
+#[cfg(feature = "python")]
+mod python;
+pub mod selector;
+pub mod transport;
+pub mod zfs;
+
+pub use device::{
+    parse_sense, Device, DeviceType, EPCSetting, Identify, PassthroughType, PowerCondDescriptor,
+    PowerCondition, PowerMode, SenseData,
+};
+pub use nvme::NvmeDevice;
@@ -1,12 +1,35 @@
-use crate::device::{Device, PowerMode};
-use anyhow::Result;
-use clap::{App, AppSettings, Arg, SubCommand};
-use device::EPCSetting;
+use crate::output::{Format, Report};
+use anyhow::{Context, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use wdepc::backup::Backup;
+use wdepc::changeset::ChangeSet;
+use wdepc::diff::{self, State};
+use wdepc::discovery::{Disk, Sysfs};
+use wdepc::nvme::{ApstSetting, NvmeDevice, NvmePowerStates};
+use wdepc::preset::{Preset, PRESETS};
+use wdepc::profile::{format_timer, Change, Profile};
+use wdepc::selector::{self, Selector};
+use wdepc::{batch, Device, DeviceType, EPCSetting, Identify, PowerCondition, PowerMode};
 
-mod device;
-mod ffi;
+mod output;
+mod saves;
+
+/// Side of `diff`
+enum Source {
+    Device(String),
+    Backups(Vec<Backup>),
+    Profile(Profile),
+}
+
+/// File loaded once and used on every device
+enum Input {
+    Profile(Profile),
+    Backups(Vec<Backup>),
+}
 
 fn main() -> Result<()> {
+    let presets: Vec<&str> = PRESETS.iter().map(|it| it.name).collect();
+
     let args = App::new("wdepc")
         .about("Western Digital EPC(Extended Power Condition) control tools")
         .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -14,6 +37,10 @@ fn main() -> Result<()> {
         .author("tyanboot <tyanboot@outlook.com>")
         .subcommand(SubCommand::with_name("check").about("Check device power mode"))
         .subcommand(SubCommand::with_name("info").about("Show device EPC settings"))
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List disks and their EPC state, doesn't require --device"),
+        )
         .subcommand(SubCommand::with_name("enable").about("Enable EPC and disable APM"))
         .subcommand(SubCommand::with_name("disable").about("Disable EPC, doesn't enable APM"))
         .subcommand(
@@ -36,9 +63,16 @@ fn main() -> Result<()> {
                 )
                 .arg(
                     Arg::with_name("mode")
-                        .help("power mode to set")
+                        .help("power condition to set, all sets every supported one")
                         .takes_value(true)
-                        .possible_values(&["idle_a", "idle_b", "idle_c", "standby_y", "standby_z"])
+                        .possible_values(&[
+                            "idle_a",
+                            "idle_b",
+                            "idle_c",
+                            "standby_y",
+                            "standby_z",
+                            "all",
+                        ])
                         .required(true),
                 )
                 .arg(
@@ -68,9 +102,16 @@ fn main() -> Result<()> {
                 )
                 .arg(
                     Arg::with_name("mode")
-                        .help("power mode to set")
+                        .help("power condition to set, all sets every supported one")
                         .takes_value(true)
-                        .possible_values(&["idle_a", "idle_b", "idle_c", "standby_y", "standby_z"])
+                        .possible_values(&[
+                            "idle_a",
+                            "idle_b",
+                            "idle_c",
+                            "standby_y",
+                            "standby_z",
+                            "all",
+                        ])
                         .required(true),
                 ),
         )
@@ -87,188 +128,485 @@ fn main() -> Result<()> {
         )
         .subcommand(
             SubCommand::with_name("restore")
-                .about(r#"Restore EPC settings
+                .about(
+                    r#"Restore EPC settings
 if default is set, set current timer to default, else set current timer to saved timer.
 if save is set, save current timer
-                "#)
+                "#,
+                )
                 .arg(
                     Arg::with_name("default")
                         .long("default")
                         .short("d")
-                        .help("Restore from default")
+                        .help("Restore from default"),
                 )
                 .arg(
                     Arg::with_name("save")
                         .long("save")
                         .short("s")
-                        .help("Save current EPC settings(after restore)")
+                        .help("Save current EPC settings(after restore)"),
                 )
                 .arg(
                     Arg::with_name("mode")
-                        .help("power mode to set")
+                        .help("power condition to set, all sets every supported one")
+                        .takes_value(true)
+                        .possible_values(&[
+                            "idle_a",
+                            "idle_b",
+                            "idle_c",
+                            "standby_y",
+                            "standby_z",
+                            "all",
+                        ])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Print all EPC settings and drive identity as JSON, for restore-from"),
+        )
+        .subcommand(
+            SubCommand::with_name("restore-from")
+                .about(
+                    "Restore EPC settings from a backup, only needed commands are sent, \
+                     refuses a backup of another model unless forced",
+                )
+                .arg(
+                    Arg::with_name("save")
+                        .help("restore saved settings too")
+                        .long("save")
+                        .short("s"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .help("restore a backup of another model")
+                        .long("force")
+                        .short("f"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .help("show planned changes, don't send them")
+                        .long("dry-run")
+                        .short("n"),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .help("output of backup, a single backup applies to any selected drive")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about(
+                    "Compare EPC settings of drives, backups and *.toml profiles, with one source \
+                     each selected disk is compared with it, exit code is 0 if they are the same, \
+                     1 if they differ and 2 if comparing failed",
+                )
+                .arg(
+                    Arg::with_name("left")
+                        .help("device or selector, backup file or profile")
                         .takes_value(true)
-                        .possible_values(&["idle_a", "idle_b", "idle_c", "standby_y", "standby_z"])
                         .required(true),
                 )
+                .arg(
+                    Arg::with_name("right")
+                        .help("device or selector, backup file or profile")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about(
+                    "Bring disks to the state described in a TOML profile, only needed commands \
+                     are sent, runs on every disk if no disk is selected",
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .help("show planned changes, don't send them")
+                        .long("dry-run")
+                        .short("n"),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .help("profile file, eg /etc/wdepc.toml")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("preset")
+                .about(
+                    "Apply built-in power preset, timers are clamped to the drive's range, \
+                     requires a selected disk or --all",
+                )
+                .arg(
+                    Arg::with_name("save")
+                        .help("save setting")
+                        .long("save")
+                        .short("s"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .help("show planned changes, don't send them")
+                        .long("dry-run")
+                        .short("n"),
+                )
+                .arg(
+                    Arg::with_name("show")
+                        .help("show preset timers, doesn't require --device")
+                        .long("show"),
+                )
+                .arg(
+                    Arg::with_name("name")
+                        .help("preset name")
+                        .takes_value(true)
+                        .possible_values(&presets)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-ps")
+                .about("Set NVMe power state")
+                .arg(
+                    Arg::with_name("save")
+                        .help("save setting")
+                        .long("save")
+                        .short("s"),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .help("power state number, eg 3 for PS3")
+                        .takes_value(true)
+                        .required(true),
+                ),
         )
         .arg(
             Arg::with_name("device")
                 .long("device")
                 .short("d")
-                .help("device path, eg /dev/sda")
+                .help(
+                    "device path, eg /dev/sda, /dev/nvme0, /dev/disk/by-id/... or /dev/sd[b-m], \
+                     or serial:WD-XXXX, wwn:0x5000cca... or model:HUH721212*, \
+                     repeat to run on several devices",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("all")
+                .long("all")
+                .short("a")
+                .help("run on every disk supporting EPC")
+                .conflicts_with_all(&["device", "path", "md", "dm", "zpool"]),
+        )
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .help("disks backing the filesystem containing path, eg /srv/archive")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("md")
+                .long("md")
+                .help("member disks of md array, eg md0")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("dm")
+                .long("dm")
+                .help("disks under device mapper or LVM device, eg vg-lv or dm-3")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("zpool")
+                .long("zpool")
+                .help("disks of ZFS pool, from zpool status, eg tank")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("type")
+                .long("type")
+                .short("t")
+                .help(
+                    "device type: auto, sat, sat,12, sat,16, jmicron, sunplus, cypress \
+                     or megaraid,N, append +sgv4 to use SG_IO v4, pass-through is probed if auto",
+                )
+                .takes_value(true)
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("output format, json and yaml documents carry a schema version")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .possible_values(&["text", "json", "yaml"])
+                .global(true)
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("sysfs")
+                .help("sysfs mount point, to discover and select disks")
+                .long("sysfs")
+                .takes_value(true)
+                .global(true)
+                .default_value("/sys"),
+        )
+        .arg(
+            Arg::with_name("save-counter")
+                .help("file counting settings saves sent to each drive")
+                .long("save-counter")
+                .takes_value(true)
+                .global(true)
+                .default_value("/var/lib/wdepc/saves.json"),
+        )
+        .arg(
+            Arg::with_name("save-warn")
+                .help("warn when a drive got more settings saves than this")
+                .long("save-warn")
                 .takes_value(true)
-                .required(true),
+                .global(true)
+                .validator(|it| it.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .default_value("100"),
         )
         .get_matches();
 
-    let device = args.value_of("device").unwrap();
-    let mut device = Device::open(device)?;
+    // as diff(1): 0 if same, 1 if different, 2 if comparing failed
+    if let ("diff", Some(sub)) = args.subcommand() {
+        let code = match diff_main(&args, sub) {
+            Ok(false) => 0,
+            Ok(true) => 1,
+            Err(e) => {
+                eprintln!("Error: {:?}", e);
+                2
+            }
+        };
+        std::process::exit(code);
+    }
+
+    let ty: DeviceType = args.value_of("type").unwrap().parse()?;
+
+    let format: Format = args.value_of("output").unwrap().parse()?;
+    // a backup is a document to restore from
+    let format = match (args.subcommand_name(), format) {
+        (Some("backup"), Format::Text) => Format::Json,
+        (_, format) => format,
+    };
+
+    let sysfs = Sysfs::new(args.value_of("sysfs").unwrap());
+
+    if args.subcommand_name() == Some("list") {
+        return list(&sysfs, ty, format);
+    }
+
+    let input = match args.subcommand() {
+        ("apply", Some(args)) => Some(Input::Profile(Profile::load(
+            args.value_of("profile").unwrap(),
+        )?)),
+        ("preset", Some(args)) => {
+            let preset = Preset::by_name(args.value_of("name").unwrap()).unwrap();
+            if args.is_present("show") {
+                print_preset(preset);
+                return Ok(());
+            }
+            Some(Input::Profile(Profile::preset(
+                preset,
+                args.is_present("save"),
+            )))
+        }
+        ("restore-from", Some(args)) => Some(Input::Backups(output::load_backups(
+            args.value_of("file").unwrap(),
+        )?)),
+        _ => None,
+    };
+
+    // a profile matches drives itself, a preset applies to whatever is selected
+    let every_disk = args.subcommand_name() == Some("apply");
+    let devices = select_devices(&args, &sysfs, ty, every_disk)?;
+    let devices: Vec<&str> = devices.iter().map(|it| it.as_str()).collect();
+
+    let command = args.subcommand_name().unwrap();
+
+    if devices.len() == 1 && format == Format::Text {
+        let report = run(devices[0], &args, ty, input.as_ref())?;
+        print_report(&report);
+        return Ok(());
+    }
+
+    // all devices are checked at once
+    let mut checked = if command == "check" && devices.len() > 1 {
+        Some(check_many(&devices, ty).into_iter())
+    } else {
+        None
+    };
+
+    let mut entries = Vec::new();
+    let mut failed = 0;
+    for device in &devices {
+        let result = match &mut checked {
+            Some(checked) => checked.next().unwrap(),
+            None => run(device, &args, ty, input.as_ref()),
+        };
+
+        if format != Format::Text {
+            entries.push(output::entry(device, &result));
+            failed += result.is_err() as usize;
+            continue;
+        }
+
+        match result {
+            Ok(Report::Done) => println!("{}: ok", device),
+            Ok(Report::Mode(mode)) => println!("{}: {}", device, mode_name(mode)),
+            Ok(Report::NvmeState(state)) => println!("{}: ps {}", device, state),
+            Ok(report) => {
+                println!("{}:", device);
+                print_report(&report);
+                println!();
+            }
+            Err(e) => {
+                eprintln!("{}: {:#}", device, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if format != Format::Text {
+        output::print(format, &output::document(command, entries))?;
+    }
+
+    anyhow::ensure!(
+        failed == 0,
+        "{} of {} devices failed",
+        failed,
+        devices.len()
+    );
+
+    Ok(())
+}
+
+/// Devices selected with `--device`, `--all` and the like, one path per drive
+///
+/// Every disk if none is selected and `every_disk`
+fn select_devices(
+    args: &ArgMatches,
+    sysfs: &Sysfs,
+    ty: DeviceType,
+    every_disk: bool,
+) -> Result<Vec<String>> {
+    let selectors: Vec<Selector> = if args.is_present("all") {
+        vec![Selector::All]
+    } else {
+        let mut selectors = args
+            .values_of("device")
+            .into_iter()
+            .flatten()
+            .map(|it| it.parse())
+            .collect::<Result<Vec<Selector>>>()?;
+        let values = |name| args.values_of(name).into_iter().flatten();
+        selectors.extend(values("path").map(|it| Selector::Mount(it.to_string())));
+        selectors.extend(values("md").map(|it| Selector::Md(it.to_string())));
+        selectors.extend(values("dm").map(|it| Selector::Dm(it.to_string())));
+        selectors.extend(values("zpool").map(|it| Selector::Zpool(it.to_string())));
+
+        anyhow::ensure!(
+            !selectors.is_empty() || every_disk,
+            "no device selected, pass --device, --path, --md, --dm, --zpool or --all"
+        );
+        selectors
+    };
+
+    let mut devices = Vec::new();
+    if selectors.is_empty() {
+        devices.extend(sysfs.disks()?.iter().map(|it| it.node().to_string()));
+        anyhow::ensure!(!devices.is_empty(), "no disks found");
+    }
+    for selector in selectors {
+        for device in selector.resolve(sysfs, ty)? {
+            if !devices.contains(&device) {
+                devices.push(device);
+            }
+        }
+    }
+    let dedup = selector::dedup_paths(devices, sysfs, ty)?;
+    for (device, first) in &dedup.skipped {
+        eprintln!("skip {}, same drive as {}", device, first);
+    }
+
+    Ok(dedup.devices)
+}
+
+/// Print report as text
+fn print_report(report: &Report) {
+    match report {
+        Report::Done => {}
+        Report::Epc(setting) => print_epc_setting(setting),
+        Report::Backup(_) => unreachable!("backup is printed as a document"),
+        Report::Diff { .. } => unreachable!("diff is printed by diff_main"),
+        Report::Mode(mode) => println!("{}", mode_name(*mode)),
+        Report::NvmeInfo {
+            states,
+            current,
+            apst,
+        } => print_nvme_info(states, *current, apst.as_ref()),
+        Report::NvmeState(state) => println!("ps {}", state),
+        Report::Plan {
+            matched,
+            changes,
+            dry_run,
+        } => {
+            if !matched {
+                println!("no drive section of the profile matches");
+            } else if changes.is_empty() {
+                println!("up to date");
+            } else {
+                println!(
+                    "{}",
+                    if *dry_run {
+                        "Planned changes:"
+                    } else {
+                        "Applied changes:"
+                    }
+                );
+                for change in changes {
+                    println!("  {}", change);
+                }
+            }
+        }
+    }
+}
+
+/// Run subcommand on a single device, `input` is the file loaded for the subcommand
+fn run(device: &str, args: &ArgMatches, ty: DeviceType, input: Option<&Input>) -> Result<Report> {
+    if is_nvme(device) {
+        return nvme_main(device, args);
+    }
+
+    let mut device = Device::open_with_type(device, ty)?;
+    let report = epc_main(&mut device, args, input);
+    if device.saves() > 0 {
+        count_saves(&device, args);
+    }
+
+    report
+}
 
+fn epc_main(device: &mut Device, args: &ArgMatches, input: Option<&Input>) -> Result<Report> {
     match args.subcommand() {
         ("info", _) => {
-            let setting = device.query_epc_setting()?;
-
-            let EPCSetting {
-                idle_a,
-                idle_b,
-                idle_c,
-                standby_y,
-                standby_z,
-                ..
-            } = setting;
-
-            println!("* = enabled");
-            println!("All times are in 100 milliseconds");
-            println!();
-
-            println!(
-                "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
-                "Name",
-                "Current Timer",
-                "Default Timer",
-                "Saved Timer",
-                "Recovery Time",
-                "Changeable",
-                "Savable"
-            );
-
-            println!(
-                "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
-                "Idle A",
-                if idle_a.current_enable {
-                    format!("*{}", idle_a.current_timer)
-                } else {
-                    idle_a.current_timer.to_string()
-                },
-                if idle_a.default_enable {
-                    format!("*{}", idle_a.default_timer)
-                } else {
-                    idle_a.default_timer.to_string()
-                },
-                if idle_a.saved_enable {
-                    format!("*{}", idle_a.saved_timer)
-                } else {
-                    idle_a.saved_timer.to_string()
-                },
-                idle_a.recovery_time,
-                idle_a.changeable,
-                idle_a.savable
-            );
-
-            println!(
-                "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
-                "Idle B",
-                if idle_b.current_enable {
-                    format!("*{}", idle_b.current_timer)
-                } else {
-                    idle_b.current_timer.to_string()
-                },
-                if idle_b.default_enable {
-                    format!("*{}", idle_b.default_timer)
-                } else {
-                    idle_b.default_timer.to_string()
-                },
-                if idle_b.saved_enable {
-                    format!("*{}", idle_b.saved_timer)
-                } else {
-                    idle_b.saved_timer.to_string()
-                },
-                idle_b.recovery_time,
-                idle_b.changeable,
-                idle_b.savable
-            );
-
-            println!(
-                "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
-                "Idle C",
-                if idle_c.current_enable {
-                    format!("*{}", idle_c.current_timer)
-                } else {
-                    idle_c.current_timer.to_string()
-                },
-                if idle_c.default_enable {
-                    format!("*{}", idle_c.default_timer)
-                } else {
-                    idle_c.default_timer.to_string()
-                },
-                if idle_c.saved_enable {
-                    format!("*{}", idle_c.saved_timer)
-                } else {
-                    idle_c.saved_timer.to_string()
-                },
-                idle_c.recovery_time,
-                idle_c.changeable,
-                idle_c.savable
-            );
-
-            println!(
-                "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
-                "Standby Y",
-                if standby_y.current_enable {
-                    format!("*{}", standby_y.current_timer)
-                } else {
-                    standby_y.current_timer.to_string()
-                },
-                if standby_y.default_enable {
-                    format!("*{}", standby_y.default_timer)
-                } else {
-                    standby_y.default_timer.to_string()
-                },
-                if standby_y.saved_enable {
-                    format!("*{}", standby_y.saved_timer)
-                } else {
-                    standby_y.saved_timer.to_string()
-                },
-                standby_y.recovery_time,
-                standby_y.changeable,
-                standby_y.savable
-            );
-
-            println!(
-                "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
-                "Standby Z",
-                if standby_z.current_enable {
-                    format!("*{}", standby_z.current_timer)
-                } else {
-                    standby_z.current_timer.to_string()
-                },
-                if standby_z.default_enable {
-                    format!("*{}", standby_z.default_timer)
-                } else {
-                    standby_z.default_timer.to_string()
-                },
-                if standby_z.saved_enable {
-                    format!("*{}", standby_z.saved_timer)
-                } else {
-                    standby_z.saved_timer.to_string()
-                },
-                standby_z.recovery_time,
-                standby_z.changeable,
-                standby_z.savable
-            );
+            return Ok(Report::Epc(device.query_epc_setting()?));
         }
         ("set-timer", Some(args)) => {
-            let mode = args.value_of("mode").unwrap();
+            let condition: PowerCondition = args.value_of("mode").unwrap().parse()?;
             let timer: u16 = args
                 .value_of("timer")
                 .and_then(|it| it.parse().ok())
@@ -280,19 +618,10 @@ if save is set, save current timer
                 .and_then(|it| it.parse().ok())
                 .unwrap();
 
-            let mode = match mode {
-                "idle_a" => PowerMode::IdleA,
-                "idle_b" => PowerMode::IdleB,
-                "idle_c" => PowerMode::IdleC,
-                "standby_y" => PowerMode::StandbyY,
-                "standby_z" => PowerMode::StandbyZ,
-                _ => unreachable!(),
-            };
-
-            device.set_timer(mode, timer, enable, save)?;
+            device.set_timer(condition, timer, enable, save)?;
         }
         ("set-state", Some(args)) => {
-            let mode = args.value_of("mode").unwrap();
+            let condition: PowerCondition = args.value_of("mode").unwrap().parse()?;
 
             let save = args.is_present("save");
             let enable: bool = args
@@ -300,29 +629,12 @@ if save is set, save current timer
                 .and_then(|it| it.parse().ok())
                 .unwrap();
 
-            let mode = match mode {
-                "idle_a" => PowerMode::IdleA,
-                "idle_b" => PowerMode::IdleB,
-                "idle_c" => PowerMode::IdleC,
-                "standby_y" => PowerMode::StandbyY,
-                "standby_z" => PowerMode::StandbyZ,
-                _ => unreachable!(),
-            };
-
-            device.set_state(mode, enable, save)?;
+            device.set_state(condition, enable, save)?;
         }
         ("set", Some(args)) => {
-            let mode = args.value_of("mode").unwrap();
-            let mode = match mode {
-                "idle_a" => PowerMode::IdleA,
-                "idle_b" => PowerMode::IdleB,
-                "idle_c" => PowerMode::IdleC,
-                "standby_y" => PowerMode::StandbyY,
-                "standby_z" => PowerMode::StandbyZ,
-                _ => unreachable!(),
-            };
+            let condition: PowerCondition = args.value_of("mode").unwrap().parse()?;
 
-            device.goto_cond(mode)?;
+            device.goto_cond(condition)?;
         }
         ("enable", _) => {
             device.enable_epc()?;
@@ -331,37 +643,683 @@ if save is set, save current timer
             device.disable_epc()?;
         }
         ("restore", Some(args)) => {
-            let mode = args.value_of("mode").unwrap();
-            let mode = match mode {
-                "idle_a" => PowerMode::IdleA,
-                "idle_b" => PowerMode::IdleB,
-                "idle_c" => PowerMode::IdleC,
-                "standby_y" => PowerMode::StandbyY,
-                "standby_z" => PowerMode::StandbyZ,
-                _ => unreachable!(),
-            };
+            let condition: PowerCondition = args.value_of("mode").unwrap().parse()?;
 
             let default = args.is_present("default");
             let save = args.is_present("save");
 
-            device.restore(mode, default, save)?;
+            device.restore(condition, default, save)?;
         }
         ("check", _) => {
-            let mode = device.query_mode()?;
-            let mode = match mode {
-                PowerMode::Active => "active or idle",
-                PowerMode::IdleA => "idle a",
-                PowerMode::IdleB => "idle b",
-                PowerMode::IdleC => "idle c",
-                PowerMode::StandbyY => "standby y",
-                PowerMode::StandbyZ => "standby z",
-                PowerMode::Unknown => "unknown",
+            return Ok(Report::Mode(device.query_mode()?));
+        }
+        ("apply", Some(args)) | ("preset", Some(args)) => {
+            let dry_run = args.is_present("dry-run");
+            let identify = device.identify()?;
+            let profile = match input {
+                Some(Input::Profile(profile)) => profile,
+                _ => unreachable!(),
+            };
+            let drive = match profile.find(&identify) {
+                Some(drive) => drive,
+                None => {
+                    return Ok(Report::Plan {
+                        matched: false,
+                        changes: Vec::new(),
+                        dry_run,
+                    })
+                }
+            };
+
+            let setting = if identify.epc_supported {
+                Some(device.query_epc_setting()?)
+            } else {
+                None
+            };
+            let changes = drive.plan(&identify, setting.as_ref())?;
+
+            send(device, &changes, dry_run)?;
+
+            return Ok(Report::Plan {
+                matched: true,
+                changes,
+                dry_run,
+            });
+        }
+        ("backup", _) => {
+            return Ok(Report::Backup(Backup::take(device)?));
+        }
+        ("restore-from", Some(args)) => {
+            let backups = match input {
+                Some(Input::Backups(backups)) => backups,
+                _ => unreachable!(),
             };
+            let dry_run = args.is_present("dry-run");
+            let identify = device.identify()?;
+
+            let backup = Backup::find(backups, &identify, args.is_present("force"))?;
+
+            let setting = device.query_epc_setting()?;
+            let changes = backup.plan(&identify, &setting, args.is_present("save"))?;
+            send(device, &changes, dry_run)?;
 
-            println!("{}", mode);
+            return Ok(Report::Plan {
+                matched: true,
+                changes,
+                dry_run,
+            });
+        }
+        ("set-ps", _) => {
+            anyhow::bail!("set-ps is only supported on NVMe devices");
         }
         _ => {}
     }
 
+    Ok(Report::Done)
+}
+
+/// Add saves sent to `device` to the save counter, warn when the drive got too many
+///
+/// Counting doesn't fail the command, errors are printed
+fn count_saves(device: &Device, args: &ArgMatches) {
+    let result = device.identify().and_then(|identify| {
+        // key as selected with `--device`, drives reporting neither WWN nor serial aren't counted
+        let Some(key) = selector::drive_key(identify.wwn, Some(&identify.serial)) else {
+            return Ok(None);
+        };
+        let path = args.value_of("save-counter").unwrap();
+        let count = saves::record(path, &key, device.saves())?;
+        Ok(Some((key, count)))
+    });
+
+    match result {
+        Ok(None) => {}
+        Ok(Some((key, count))) => {
+            let warn: u64 = args.value_of("save-warn").unwrap().parse().unwrap();
+            if count > warn {
+                eprintln!(
+                    "warning: {} got {} settings saves, each wears its non-volatile memory",
+                    key, count
+                );
+            }
+        }
+        Err(e) => eprintln!("unable to count settings saves: {:#}", e),
+    }
+}
+
+/// Compare two sources, or each selected device with one, return whether any differ
+fn diff_main(args: &ArgMatches, sub: &ArgMatches) -> Result<bool> {
+    let ty: DeviceType = args.value_of("type").unwrap().parse()?;
+    let format: Format = args.value_of("output").unwrap().parse()?;
+    let sysfs = &Sysfs::new(args.value_of("sysfs").unwrap());
+
+    let open = |name: &str| -> Result<Source> {
+        if name.ends_with(".toml") {
+            return Ok(Source::Profile(Profile::load(name)?));
+        }
+        if std::path::Path::new(name).is_file() {
+            return Ok(Source::Backups(output::load_backups(name)?));
+        }
+
+        let selector: Selector = name.parse()?;
+        let mut devices = selector.resolve(sysfs, ty)?;
+        anyhow::ensure!(devices.len() == 1, "{} matches several drives", name);
+        Ok(Source::Device(devices.remove(0)))
+    };
+
+    let left = sub.value_of("left").unwrap();
+    let (lefts, right_name, right) = match sub.value_of("right") {
+        Some(right) => (vec![(left.to_string(), open(left)?)], right, open(right)?),
+        None => {
+            let devices = select_devices(args, sysfs, ty, false)?;
+            let lefts = devices
+                .into_iter()
+                .map(|it| (it.clone(), Source::Device(it)))
+                .collect();
+            (lefts, left, open(left)?)
+        }
+    };
+
+    let mut entries = Vec::new();
+    let mut failed = 0;
+    let mut differ = 0;
+    for (left_name, left) in &lefts {
+        let result = states(left, &right, ty).map(|(l, r)| Report::Diff {
+            against: right_name.to_string(),
+            differences: diff::diff(&l, &r),
+        });
+        if let Ok(Report::Diff { differences, .. }) = &result {
+            differ += !differences.is_empty() as usize;
+        }
+
+        if format != Format::Text {
+            entries.push(output::entry(left_name, &result));
+            failed += result.is_err() as usize;
+            continue;
+        }
+
+        match result {
+            Ok(Report::Diff { differences, .. }) if differences.is_empty() => {
+                println!("{}: same as {}", left_name, right_name)
+            }
+            Ok(Report::Diff { differences, .. }) => {
+                println!("{} differs from {}:", left_name, right_name);
+                println!(
+                    "{:<9}  {:<14} {:<13} {}",
+                    "Condition", "Setting", left_name, right_name
+                );
+                for difference in differences {
+                    println!(
+                        "{:<9}  {:<14} {:<13} {}",
+                        difference.condition.map_or("EPC", condition_name),
+                        difference.setting,
+                        difference.left.to_string(),
+                        difference.right
+                    );
+                }
+                println!();
+            }
+            Ok(_) => unreachable!(),
+            Err(e) => {
+                eprintln!("{}: {:#}", left_name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    if format != Format::Text {
+        output::print(format, &output::document("diff", entries))?;
+    }
+
+    anyhow::ensure!(
+        failed == 0,
+        "{} of {} comparisons failed",
+        failed,
+        lefts.len()
+    );
+
+    Ok(differ > 0)
+}
+
+/// States of both sides, a profile is resolved for the drive or backup on the other side
+fn states(left: &Source, right: &Source, ty: DeviceType) -> Result<(State, State)> {
+    let of =
+        |(identify, setting): &(Identify, EPCSetting)| State::of(identify.epc_enabled, setting);
+    let wanted = |profile: &Profile, (identify, setting): &(Identify, EPCSetting)| {
+        profile
+            .find(identify)
+            .map(|drive| State::wanted(drive, setting))
+            .context("no drive section of the profile matches")
+    };
+
+    match (left, right) {
+        (Source::Profile(_), Source::Profile(_)) => {
+            anyhow::bail!("compare a profile with a drive or a backup")
+        }
+        (Source::Profile(profile), other) => {
+            let other = snapshot(other, None, ty)?;
+            Ok((wanted(profile, &other)?, of(&other)))
+        }
+        (other, Source::Profile(profile)) => {
+            let other = snapshot(other, None, ty)?;
+            Ok((of(&other), wanted(profile, &other)?))
+        }
+        // a backup of the drive is picked from several
+        (Source::Backups(_), Source::Device(_)) => {
+            let r = snapshot(right, None, ty)?;
+            let l = snapshot(left, Some(&r.0), ty)?;
+            Ok((of(&l), of(&r)))
+        }
+        _ => {
+            let l = snapshot(left, None, ty)?;
+            let r = snapshot(right, Some(&l.0), ty)?;
+            Ok((of(&l), of(&r)))
+        }
+    }
+}
+
+/// Identity and settings of a drive or a backup, the backup of `other` if the file holds several
+fn snapshot(
+    source: &Source,
+    other: Option<&Identify>,
+    ty: DeviceType,
+) -> Result<(Identify, EPCSetting)> {
+    match source {
+        Source::Device(path) => {
+            let device = Device::open_with_type(path, ty)?;
+            let identify = device.identify()?;
+            anyhow::ensure!(identify.epc_supported, "{} doesn't support EPC", path);
+            let setting = device.query_epc_setting()?;
+            Ok((identify, setting))
+        }
+        Source::Backups(backups) => {
+            let backup = match (backups.len(), other) {
+                (1, _) => &backups[0],
+                (_, Some(other)) => backups
+                    .iter()
+                    .find(|it| it.is_of(other))
+                    .with_context(|| format!("no backup of drive {} in file", other.serial))?,
+                (_, None) => anyhow::bail!("file holds several backups, compare it with a drive"),
+            };
+            Ok((backup.identify(), backup.setting))
+        }
+        Source::Profile(_) => unreachable!(),
+    }
+}
+
+/// Send planned changes, verified and rolled back on failure, nothing on dry run
+fn send(device: &mut Device, changes: &[Change], dry_run: bool) -> Result<()> {
+    if dry_run {
+        return Ok(());
+    }
+
+    ChangeSet::new(changes.to_vec()).apply(device)
+}
+
+/// Print EPC settings table, `*` marks enabled timers
+fn print_epc_setting(setting: &EPCSetting) {
+    let EPCSetting {
+        idle_a,
+        idle_b,
+        idle_c,
+        standby_y,
+        standby_z,
+        ..
+    } = *setting;
+
+    println!("* = enabled");
+    println!("All times are in 100 milliseconds");
+    println!();
+
+    println!(
+        "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
+        "Name",
+        "Current Timer",
+        "Default Timer",
+        "Saved Timer",
+        "Recovery Time",
+        "Changeable",
+        "Savable"
+    );
+
+    println!(
+        "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
+        "Idle A",
+        if idle_a.current_enable {
+            format!("*{}", idle_a.current_timer)
+        } else {
+            idle_a.current_timer.to_string()
+        },
+        if idle_a.default_enable {
+            format!("*{}", idle_a.default_timer)
+        } else {
+            idle_a.default_timer.to_string()
+        },
+        if idle_a.saved_enable {
+            format!("*{}", idle_a.saved_timer)
+        } else {
+            idle_a.saved_timer.to_string()
+        },
+        idle_a.recovery_time,
+        idle_a.changeable,
+        idle_a.savable
+    );
+
+    println!(
+        "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
+        "Idle B",
+        if idle_b.current_enable {
+            format!("*{}", idle_b.current_timer)
+        } else {
+            idle_b.current_timer.to_string()
+        },
+        if idle_b.default_enable {
+            format!("*{}", idle_b.default_timer)
+        } else {
+            idle_b.default_timer.to_string()
+        },
+        if idle_b.saved_enable {
+            format!("*{}", idle_b.saved_timer)
+        } else {
+            idle_b.saved_timer.to_string()
+        },
+        idle_b.recovery_time,
+        idle_b.changeable,
+        idle_b.savable
+    );
+
+    println!(
+        "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
+        "Idle C",
+        if idle_c.current_enable {
+            format!("*{}", idle_c.current_timer)
+        } else {
+            idle_c.current_timer.to_string()
+        },
+        if idle_c.default_enable {
+            format!("*{}", idle_c.default_timer)
+        } else {
+            idle_c.default_timer.to_string()
+        },
+        if idle_c.saved_enable {
+            format!("*{}", idle_c.saved_timer)
+        } else {
+            idle_c.saved_timer.to_string()
+        },
+        idle_c.recovery_time,
+        idle_c.changeable,
+        idle_c.savable
+    );
+
+    println!(
+        "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
+        "Standby Y",
+        if standby_y.current_enable {
+            format!("*{}", standby_y.current_timer)
+        } else {
+            standby_y.current_timer.to_string()
+        },
+        if standby_y.default_enable {
+            format!("*{}", standby_y.default_timer)
+        } else {
+            standby_y.default_timer.to_string()
+        },
+        if standby_y.saved_enable {
+            format!("*{}", standby_y.saved_timer)
+        } else {
+            standby_y.saved_timer.to_string()
+        },
+        standby_y.recovery_time,
+        standby_y.changeable,
+        standby_y.savable
+    );
+
+    println!(
+        "{:<9}  {:<13} {:<13} {:<11} {:<13} {:<10} {:<7}",
+        "Standby Z",
+        if standby_z.current_enable {
+            format!("*{}", standby_z.current_timer)
+        } else {
+            standby_z.current_timer.to_string()
+        },
+        if standby_z.default_enable {
+            format!("*{}", standby_z.default_timer)
+        } else {
+            standby_z.default_timer.to_string()
+        },
+        if standby_z.saved_enable {
+            format!("*{}", standby_z.saved_timer)
+        } else {
+            standby_z.saved_timer.to_string()
+        },
+        standby_z.recovery_time,
+        standby_z.changeable,
+        standby_z.savable
+    );
+}
+
+/// List disks found in sysfs, IDENTIFY each for serial and EPC state
+///
+/// Paths to the same drive, eg dual ported SAS disks, are grouped in one row
+fn list(sysfs: &Sysfs, ty: DeviceType, format: Format) -> Result<()> {
+    let disks = sysfs.disks()?;
+
+    struct Row {
+        disk: Disk,
+        model: String,
+        serial: Option<String>,
+        /// None if IDENTIFY failed
+        epc: Option<&'static str>,
+        key: Option<String>,
+        paths: Vec<String>,
+    }
+
+    let mut rows: Vec<Row> = Vec::new();
+    for disk in disks {
+        let identify = Device::open_with_type(disk.node(), ty).and_then(|it| it.identify());
+
+        let (model, serial, epc, key) = match identify {
+            Ok(identify) => (
+                identify.model,
+                Some(identify.serial.clone()),
+                Some(match (identify.epc_supported, identify.epc_enabled) {
+                    (true, true) => "enabled",
+                    (true, false) => "disabled",
+                    (false, _) => "not supported",
+                }),
+                selector::drive_key(identify.wwn.or(disk.wwn), Some(&identify.serial)),
+            ),
+            // not ATA, or no permission
+            Err(_) => (
+                format!("{} {}", disk.vendor, disk.model),
+                disk.serial.clone(),
+                None,
+                selector::drive_key(disk.wwn, disk.serial.as_deref()),
+            ),
+        };
+
+        let group = rows.iter_mut().find(|row| key.is_some() && row.key == key);
+        match group {
+            Some(row) => row.paths.push(disk.node().to_string()),
+            None => rows.push(Row {
+                paths: vec![disk.node().to_string()],
+                disk,
+                model,
+                serial,
+                epc,
+                key,
+            }),
+        }
+    }
+
+    if format != Format::Text {
+        let entries = rows
+            .iter()
+            .map(|row| {
+                output::disk(
+                    &row.disk,
+                    &row.model,
+                    row.serial.as_deref(),
+                    row.epc,
+                    &row.paths,
+                )
+            })
+            .collect();
+        return output::print(format, &output::document("list", entries));
+    }
+
+    println!(
+        "{:<10} {:<9} {:<9} {:<24} {:<20} {:<13} Paths",
+        "Device", "SG", "Transport", "Model", "Serial", "EPC"
+    );
+
+    for row in rows {
+        let mut paths = if row.paths.len() > 1 {
+            row.paths.join(",")
+        } else {
+            "-".to_string()
+        };
+        if let Some(multipath) = &row.disk.multipath {
+            paths = format!("{} ({})", paths, multipath);
+        }
+
+        println!(
+            "{:<10} {:<9} {:<9} {:<24} {:<20} {:<13} {}",
+            row.disk.block.as_deref().unwrap_or("-"),
+            row.disk.sg.as_deref().unwrap_or("-"),
+            row.disk.transport,
+            row.model,
+            row.serial.as_deref().unwrap_or("-"),
+            row.epc.unwrap_or("-"),
+            paths
+        );
+    }
+
     Ok(())
 }
+
+/// Print preset timers
+fn print_preset(preset: &Preset) {
+    println!("{}: {}", preset.name, preset.about);
+    println!();
+
+    println!("{:<9}  Timer", "Name");
+    for (mode, timer) in PowerMode::CONDITIONS.iter().zip(preset.timers.iter()) {
+        println!(
+            "{:<9}  {}",
+            condition_name(*mode),
+            timer.map_or("disabled".to_string(), format_timer)
+        );
+    }
+    println!();
+
+    println!("APM level of drives without EPC: {}", preset.apm);
+    println!("Timers are clamped to the range of each drive");
+}
+
+fn condition_name(mode: PowerMode) -> &'static str {
+    match mode {
+        PowerMode::IdleA => "Idle A",
+        PowerMode::IdleB => "Idle B",
+        PowerMode::IdleC => "Idle C",
+        PowerMode::StandbyY => "Standby Y",
+        PowerMode::StandbyZ => "Standby Z",
+        PowerMode::Active | PowerMode::Unknown => unreachable!(),
+    }
+}
+
+fn mode_name(mode: PowerMode) -> &'static str {
+    match mode {
+        PowerMode::Active => "active or idle",
+        PowerMode::IdleA => "idle a",
+        PowerMode::IdleB => "idle b",
+        PowerMode::IdleC => "idle c",
+        PowerMode::StandbyY => "standby y",
+        PowerMode::StandbyZ => "standby z",
+        PowerMode::Unknown => "unknown",
+    }
+}
+
+/// Check power mode of all devices concurrently, one result per device
+fn check_many(devices: &[&str], ty: DeviceType) -> Vec<Result<Report>> {
+    let ata: Vec<&str> = devices.iter().copied().filter(|it| !is_nvme(it)).collect();
+    let mut ata_modes = batch::query_modes(&ata, ty).into_iter();
+
+    devices
+        .iter()
+        .map(|device| {
+            if is_nvme(device) {
+                NvmeDevice::open(device)
+                    .and_then(|it| it.query_power_state())
+                    .map(Report::NvmeState)
+            } else {
+                ata_modes.next().unwrap().map(Report::Mode)
+            }
+        })
+        .collect()
+}
+
+fn is_nvme(device: &str) -> bool {
+    std::path::Path::new(device)
+        .file_name()
+        .and_then(|it| it.to_str())
+        .is_some_and(|it| it.starts_with("nvme"))
+}
+
+fn nvme_main(device: &str, args: &ArgMatches) -> Result<Report> {
+    let mut device = NvmeDevice::open(device)?;
+
+    match args.subcommand() {
+        ("info", _) => {
+            let states = device.query_power_states()?;
+            let current = device.query_power_state()?;
+            let apst = if states.apst_supported {
+                Some(device.query_apst()?)
+            } else {
+                None
+            };
+
+            return Ok(Report::NvmeInfo {
+                states,
+                current,
+                apst,
+            });
+        }
+        ("check", _) => {
+            return Ok(Report::NvmeState(device.query_power_state()?));
+        }
+        ("enable", _) => {
+            device.set_apst(true, false)?;
+        }
+        ("disable", _) => {
+            device.set_apst(false, false)?;
+        }
+        ("set-ps", Some(args)) => {
+            let state: u8 = args
+                .value_of("state")
+                .and_then(|it| it.trim_start_matches("ps").parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("invalid power state"))?;
+            let save = args.is_present("save");
+
+            device.set_power_state(state, save)?;
+        }
+        (name, _) => {
+            anyhow::bail!("{} is not supported on NVMe devices", name);
+        }
+    }
+
+    Ok(Report::Done)
+}
+
+/// Print NVMe power state table, `*` marks current state
+fn print_nvme_info(states: &NvmePowerStates, current: u8, apst: Option<&ApstSetting>) {
+    println!("* = current power state");
+    println!("Power in watts, latencies in microseconds, APST idle time in milliseconds");
+    match apst {
+        Some(apst) if apst.enabled => println!("APST: enabled"),
+        Some(_) => println!("APST: disabled"),
+        None => println!("APST: not supported"),
+    }
+    println!();
+
+    println!(
+        "{:<5}  {:<9} {:<11} {:<13} {:<12} {:<15} {:<11}",
+        "State",
+        "Max Power",
+        "Operational",
+        "Entry Latency",
+        "Exit Latency",
+        "RRT/RRL/RWT/RWL",
+        "APST"
+    );
+
+    for (i, state) in states.states.iter().enumerate() {
+        let transition = match apst.and_then(|it| it.entries.get(i)) {
+            Some(entry) if entry.idle_time != 0 => {
+                format!("{} -> PS{}", entry.idle_time, entry.target_state)
+            }
+            _ => "-".to_string(),
+        };
+
+        println!(
+            "{:<5}  {:<9} {:<11} {:<13} {:<12} {:<15} {:<11}",
+            if i == current as usize {
+                format!("*PS{}", i)
+            } else {
+                format!("PS{}", i)
+            },
+            format!("{:.4}", state.max_power as f64 / 10000.0),
+            !state.non_operational,
+            state.entry_latency,
+            state.exit_latency,
+            format!(
+                "{}/{}/{}/{}",
+                state.relative_read_throughput,
+                state.relative_read_latency,
+                state.relative_write_throughput,
+                state.relative_write_latency
+            ),
+            transition
+        );
+    }
+}
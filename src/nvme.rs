@@ -0,0 +1,465 @@
+use std::convert::TryInto;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+
+use anyhow::Result;
+use libc::{ioctl, O_RDONLY};
+
+use crate::ffi::{NvmeAdminCmd, NvmeAdminOpcode, NvmeFeature, NVME_IOCTL_ADMIN_CMD};
+use crate::transport::open_fd;
+
+/// Submit NVMe admin commands
+///
+/// Implemented by the real ioctl handle, and can be mocked to exercise command encoding
+/// without a device.
+pub trait AdminPassthrough: Send + Sync {
+    /// Submit admin command, return completion queue entry dword 0 on success
+    fn admin_cmd(&self, cmd: &mut NvmeAdminCmd) -> Result<u32>;
+}
+
+struct AdminIoctl {
+    fd: OwnedFd,
+}
+
+impl AdminPassthrough for AdminIoctl {
+    fn admin_cmd(&self, cmd: &mut NvmeAdminCmd) -> Result<u32> {
+        let r = unsafe {
+            ioctl(
+                self.fd.as_raw_fd(),
+                NVME_IOCTL_ADMIN_CMD,
+                cmd as *mut NvmeAdminCmd,
+            )
+        };
+
+        anyhow::ensure!(
+            r >= 0,
+            "nvme admin command {:#04x} failed: {}",
+            cmd.opcode,
+            std::io::Error::last_os_error()
+        );
+        // positive value is the NVMe status field
+        anyhow::ensure!(
+            r == 0,
+            "nvme admin command {:#04x} failed with status {:#x}",
+            cmd.opcode,
+            r
+        );
+
+        Ok(cmd.result)
+    }
+}
+
+pub struct NvmeDevice {
+    admin: Box<dyn AdminPassthrough>,
+}
+
+/// Power state descriptor from Identify Controller
+#[derive(Debug, Copy, Clone)]
+pub struct NvmePowerStateDescriptor {
+    /// maximum power, in 0.0001 W
+    pub max_power: u32,
+    pub non_operational: bool,
+
+    /// entry latency, in microseconds
+    pub entry_latency: u32,
+    /// exit latency, in microseconds
+    pub exit_latency: u32,
+
+    pub relative_read_throughput: u8,
+    pub relative_read_latency: u8,
+    pub relative_write_throughput: u8,
+    pub relative_write_latency: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct NvmePowerStates {
+    pub apst_supported: bool,
+    pub states: Vec<NvmePowerStateDescriptor>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ApstEntry {
+    /// idle time prior to transition, in milliseconds
+    pub idle_time: u32,
+    /// idle transition power state
+    pub target_state: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApstSetting {
+    pub enabled: bool,
+    /// one entry per power state, 0 idle time means no transition
+    pub entries: Vec<ApstEntry>,
+}
+
+const IDENTIFY_LEN: usize = 4096;
+const APST_TABLE_LEN: usize = 256;
+
+impl NvmeDevice {
+    /// Open NVMe controller or namespace with given path, eg /dev/nvme0
+    ///
+    /// **Require root**
+    pub fn open(device: impl AsRef<str>) -> Result<NvmeDevice> {
+        let fd = open_fd(device.as_ref(), O_RDONLY)?;
+
+        Ok(NvmeDevice::with_admin(Box::new(AdminIoctl { fd })))
+    }
+
+    /// Build device over any admin command submitter
+    pub fn with_admin(admin: Box<dyn AdminPassthrough>) -> NvmeDevice {
+        NvmeDevice { admin }
+    }
+
+    /// Query supported power states from Identify Controller
+    pub fn query_power_states(&self) -> Result<NvmePowerStates> {
+        let mut buffer = vec![0u8; IDENTIFY_LEN];
+        let mut cmd = NvmeAdminCmd {
+            opcode: NvmeAdminOpcode::Identify as u8,
+            addr: buffer.as_mut_ptr() as u64,
+            data_len: buffer.len() as u32,
+            cdw10: 0x01, // CNS 01h, identify controller
+            ..Default::default()
+        };
+        self.admin.admin_cmd(&mut cmd)?;
+
+        Ok(parse_identify_controller(&buffer))
+    }
+
+    /// Query current power state
+    pub fn query_power_state(&self) -> Result<u8> {
+        let mut cmd = NvmeAdminCmd {
+            opcode: NvmeAdminOpcode::GetFeatures as u8,
+            cdw10: NvmeFeature::PowerManagement as u32,
+            ..Default::default()
+        };
+        let result = self.admin.admin_cmd(&mut cmd)?;
+
+        Ok((result & 0b1_1111) as u8)
+    }
+
+    /// Set device to specific power state
+    ///
+    /// if `save` set true, the power state persists across power cycles
+    ///
+    /// `state` is checked against the power states the controller reports
+    pub fn set_power_state(&mut self, state: u8, save: bool) -> Result<()> {
+        let count = self.query_power_states()?.states.len();
+        anyhow::ensure!(
+            (state as usize) < count,
+            "invalid power state {}, the controller supports 0 to {}",
+            state,
+            count - 1
+        );
+
+        let save = if save { 1 } else { 0 };
+        let mut cmd = NvmeAdminCmd {
+            opcode: NvmeAdminOpcode::SetFeatures as u8,
+            cdw10: save << 31 | NvmeFeature::PowerManagement as u32,
+            cdw11: state as u32 & 0b1_1111,
+            ..Default::default()
+        };
+        self.admin.admin_cmd(&mut cmd)?;
+
+        Ok(())
+    }
+
+    /// Query Autonomous Power State Transition setting
+    pub fn query_apst(&self) -> Result<ApstSetting> {
+        let mut buffer = [0u8; APST_TABLE_LEN];
+        let mut cmd = NvmeAdminCmd {
+            opcode: NvmeAdminOpcode::GetFeatures as u8,
+            addr: buffer.as_mut_ptr() as u64,
+            data_len: buffer.len() as u32,
+            cdw10: NvmeFeature::AutonomousPowerStateTransition as u32,
+            ..Default::default()
+        };
+        let result = self.admin.admin_cmd(&mut cmd)?;
+
+        Ok(ApstSetting {
+            enabled: result & 1 != 0,
+            entries: parse_apst_table(&buffer),
+        })
+    }
+
+    /// Enable or disable Autonomous Power State Transition, keep current transition table
+    ///
+    /// if `save` set true, save setting
+    pub fn set_apst(&mut self, enable: bool, save: bool) -> Result<()> {
+        let current = self.query_apst()?;
+        let mut buffer = build_apst_table(&current.entries);
+
+        let enable = if enable { 1 } else { 0 };
+        let save = if save { 1 } else { 0 };
+        let mut cmd = NvmeAdminCmd {
+            opcode: NvmeAdminOpcode::SetFeatures as u8,
+            addr: buffer.as_mut_ptr() as u64,
+            data_len: buffer.len() as u32,
+            cdw10: save << 31 | NvmeFeature::AutonomousPowerStateTransition as u32,
+            cdw11: enable,
+            ..Default::default()
+        };
+        self.admin.admin_cmd(&mut cmd)?;
+
+        Ok(())
+    }
+}
+
+fn parse_identify_controller(raw: &[u8]) -> NvmePowerStates {
+    // NPSS is zero based
+    let npss = raw[263] as usize;
+    let apsta = raw[265];
+
+    let states = raw[2048..]
+        .chunks(32)
+        .take(npss + 1)
+        .map(parse_power_state_desc)
+        .collect();
+
+    NvmePowerStates {
+        apst_supported: apsta & 1 != 0,
+        states,
+    }
+}
+
+fn parse_power_state_desc(raw: &[u8]) -> NvmePowerStateDescriptor {
+    let max_power = u16::from_le_bytes(raw[0..=1].try_into().unwrap()) as u32;
+    let flag = raw[3];
+    let entry_latency = u32::from_le_bytes(raw[4..=7].try_into().unwrap());
+    let exit_latency = u32::from_le_bytes(raw[8..=11].try_into().unwrap());
+
+    NvmePowerStateDescriptor {
+        // MXPS set means 0.0001 W unit, else 0.01 W
+        max_power: if flag & 0b01 != 0 {
+            max_power
+        } else {
+            max_power * 100
+        },
+        non_operational: flag & 0b10 != 0,
+        entry_latency,
+        exit_latency,
+        relative_read_throughput: raw[12] & 0b1_1111,
+        relative_read_latency: raw[13] & 0b1_1111,
+        relative_write_throughput: raw[14] & 0b1_1111,
+        relative_write_latency: raw[15] & 0b1_1111,
+    }
+}
+
+fn parse_apst_table(raw: &[u8]) -> Vec<ApstEntry> {
+    raw.chunks(8)
+        .map(|entry| {
+            let entry = u64::from_le_bytes(entry.try_into().unwrap());
+            ApstEntry {
+                idle_time: (entry >> 8 & 0xff_ffff) as u32,
+                target_state: (entry >> 3 & 0b1_1111) as u8,
+            }
+        })
+        .collect()
+}
+
+fn build_apst_table(entries: &[ApstEntry]) -> [u8; APST_TABLE_LEN] {
+    let mut buffer = [0u8; APST_TABLE_LEN];
+
+    for (raw, entry) in buffer.chunks_mut(8).zip(entries) {
+        let value = ((entry.idle_time & 0xff_ffff) as u64) << 8
+            | ((entry.target_state & 0b1_1111) as u64) << 3;
+        raw.copy_from_slice(&value.to_le_bytes());
+    }
+
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Admin command as submitted, with a copy of its data buffer
+    type Submitted = (NvmeAdminCmd, Vec<u8>);
+
+    /// Answer Identify with `identify`, Get Features with `result` and the APST table in `apst`
+    struct MockAdmin {
+        identify: Vec<u8>,
+        apst: [u8; APST_TABLE_LEN],
+        result: u32,
+        submitted: Arc<Mutex<Vec<Submitted>>>,
+    }
+
+    impl AdminPassthrough for MockAdmin {
+        fn admin_cmd(&self, cmd: &mut NvmeAdminCmd) -> Result<u32> {
+            let data: &mut [u8] = match cmd.addr {
+                0 => &mut [],
+                addr => unsafe {
+                    std::slice::from_raw_parts_mut(addr as *mut u8, cmd.data_len as usize)
+                },
+            };
+
+            match cmd.opcode {
+                0x06 => data.copy_from_slice(&self.identify),
+                0x0a if cmd.cdw10 == 0x0c => data.copy_from_slice(&self.apst),
+                _ => {}
+            }
+            self.submitted.lock().unwrap().push((*cmd, data.to_vec()));
+
+            Ok(self.result)
+        }
+    }
+
+    fn mock(
+        identify: Vec<u8>,
+        apst: [u8; APST_TABLE_LEN],
+        result: u32,
+    ) -> (NvmeDevice, Arc<Mutex<Vec<Submitted>>>) {
+        let submitted = Arc::new(Mutex::new(vec![]));
+        let admin = MockAdmin {
+            identify,
+            apst,
+            result,
+            submitted: submitted.clone(),
+        };
+
+        (NvmeDevice::with_admin(Box::new(admin)), submitted)
+    }
+
+    /// Identify Controller page of a drive with 5 power states, the last 2 non-operational
+    fn identify_page() -> Vec<u8> {
+        let mut page = vec![0u8; IDENTIFY_LEN];
+        page[263] = 4; // NPSS
+        page[265] = 1; // APSTA
+
+        // max power, flags, entry latency, exit latency, RRT, RRL, RWT, RWL
+        let states: [(u16, u8, u32, u32, [u8; 4]); 5] = [
+            (782, 0b00, 0, 0, [0, 0, 0, 0]),
+            (630, 0b00, 0, 0, [1, 1, 1, 1]),
+            (350, 0b00, 0, 0, [2, 2, 2, 2]),
+            (400, 0b11, 2000, 1200, [3, 3, 3, 3]),
+            (50, 0b11, 500, 9500, [4, 4, 4, 4]),
+        ];
+        for (raw, (max_power, flag, entry, exit, relative)) in
+            page[2048..].chunks_mut(32).zip(states.iter())
+        {
+            raw[0..2].copy_from_slice(&max_power.to_le_bytes());
+            raw[3] = *flag;
+            raw[4..8].copy_from_slice(&entry.to_le_bytes());
+            raw[8..12].copy_from_slice(&exit.to_le_bytes());
+            raw[12..16].copy_from_slice(relative);
+        }
+
+        page
+    }
+
+    #[test]
+    fn power_states_from_identify() {
+        let (device, submitted) = mock(identify_page(), [0; APST_TABLE_LEN], 0);
+        let power_states = device.query_power_states().unwrap();
+
+        assert!(power_states.apst_supported);
+        assert_eq!(power_states.states.len(), 5);
+
+        let ps0 = power_states.states[0];
+        assert_eq!(ps0.max_power, 78200);
+        assert!(!ps0.non_operational);
+
+        let ps4 = power_states.states[4];
+        assert_eq!(ps4.max_power, 50);
+        assert!(ps4.non_operational);
+        assert_eq!(ps4.entry_latency, 500);
+        assert_eq!(ps4.exit_latency, 9500);
+        assert_eq!(ps4.relative_read_throughput, 4);
+        assert_eq!(ps4.relative_write_latency, 4);
+
+        let (cmd, _) = submitted.lock().unwrap()[0];
+        assert_eq!(cmd.opcode, 0x06);
+        assert_eq!(cmd.cdw10, 0x01);
+        assert_eq!(cmd.data_len, IDENTIFY_LEN as u32);
+    }
+
+    #[test]
+    fn power_management_feature() {
+        let (mut device, submitted) = mock(identify_page(), [0; APST_TABLE_LEN], 0xe3);
+
+        assert_eq!(device.query_power_state().unwrap(), 3);
+        device.set_power_state(4, false).unwrap();
+        device.set_power_state(2, true).unwrap();
+
+        let submitted = submitted.lock().unwrap();
+        let cmds: Vec<_> = submitted
+            .iter()
+            .filter(|(cmd, _)| cmd.opcode != 0x06)
+            .map(|(cmd, _)| (cmd.opcode, cmd.cdw10, cmd.cdw11))
+            .collect();
+        assert_eq!(
+            cmds,
+            [(0x0a, 0x02, 0), (0x09, 0x02, 4), (0x09, 1 << 31 | 0x02, 2),]
+        );
+    }
+
+    #[test]
+    fn power_state_out_of_range() {
+        let (mut device, submitted) = mock(identify_page(), [0; APST_TABLE_LEN], 0);
+
+        let e = device.set_power_state(5, false).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid power state 5, the controller supports 0 to 4"
+        );
+        // only Identify was sent
+        let submitted = submitted.lock().unwrap();
+        assert!(submitted.iter().all(|(cmd, _)| cmd.opcode == 0x06));
+    }
+
+    #[test]
+    fn apst_table_round_trip() {
+        let entries = [
+            ApstEntry {
+                idle_time: 100,
+                target_state: 3,
+            },
+            ApstEntry {
+                idle_time: 0x12_3456,
+                target_state: 4,
+            },
+        ];
+        let table = build_apst_table(&entries);
+
+        assert_eq!(&table[0..8], &[3 << 3, 100, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&table[8..16], &[4 << 3, 0x56, 0x34, 0x12, 0, 0, 0, 0]);
+
+        let parsed = parse_apst_table(&table);
+        assert_eq!(parsed.len(), 32);
+        assert_eq!(
+            (parsed[1].idle_time, parsed[1].target_state),
+            (0x12_3456, 4)
+        );
+        assert_eq!(parsed[2].idle_time, 0);
+    }
+
+    #[test]
+    fn apst_feature() {
+        let mut table = [0u8; APST_TABLE_LEN];
+        table[0..8].copy_from_slice(&((100u64 << 8) | 3 << 3).to_le_bytes());
+        let (mut device, submitted) = mock(identify_page(), table, 1);
+
+        let apst = device.query_apst().unwrap();
+        assert!(apst.enabled);
+        assert_eq!(
+            (apst.entries[0].idle_time, apst.entries[0].target_state),
+            (100, 3)
+        );
+
+        device.set_apst(false, true).unwrap();
+
+        let submitted = submitted.lock().unwrap();
+        // query, then query again to keep the table, then set
+        assert_eq!(submitted.len(), 3);
+        for (cmd, _) in &submitted[0..2] {
+            assert_eq!((cmd.opcode, cmd.cdw10), (0x0a, 0x0c));
+        }
+
+        let (cmd, data) = &submitted[2];
+        assert_eq!(
+            (cmd.opcode, cmd.cdw10, cmd.cdw11),
+            (0x09, 1 << 31 | 0x0c, 0)
+        );
+        assert_eq!(data.as_slice(), &table[..]);
+    }
+}
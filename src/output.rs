@@ -0,0 +1,235 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use wdepc::backup::Backup;
+use wdepc::diff::{Difference, Setting};
+use wdepc::discovery::Disk;
+use wdepc::nvme::{ApstSetting, NvmePowerStates};
+use wdepc::profile::Change;
+use wdepc::{EPCSetting, PowerMode};
+
+/// Version of the JSON and YAML document layout, bumped on incompatible changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Output format selected with `--output`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// human readable tables
+    Text,
+    Json,
+    Yaml,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            _ => anyhow::bail!("unknown output format {}", s),
+        }
+    }
+}
+
+/// Result of a subcommand on one device
+pub enum Report {
+    /// command without output, eg `enable`
+    Done,
+    Epc(EPCSetting),
+    Backup(Backup),
+    Mode(PowerMode),
+    NvmeInfo {
+        states: NvmePowerStates,
+        current: u8,
+        apst: Option<ApstSetting>,
+    },
+    NvmeState(u8),
+    Diff {
+        /// source compared with
+        against: String,
+        differences: Vec<Difference>,
+    },
+    /// changes of `apply`, not sent on dry run
+    Plan {
+        /// false if no drive section matches
+        matched: bool,
+        changes: Vec<Change>,
+        dry_run: bool,
+    },
+}
+
+impl Report {
+    pub fn to_value(&self) -> Value {
+        match self {
+            Report::Done => Value::Null,
+            Report::Epc(setting) => json!({ "power_conditions": setting }),
+            Report::Backup(backup) => json!(backup),
+            Report::Mode(mode) => json!({ "power_mode": mode.to_string() }),
+            Report::NvmeInfo {
+                states,
+                current,
+                apst,
+            } => nvme_info(states, *current, apst.as_ref()),
+            Report::NvmeState(state) => json!({ "power_state": state }),
+            Report::Diff {
+                against,
+                differences,
+            } => {
+                let setting = |setting: &Setting| match *setting {
+                    Setting::Flag(flag) => json!(flag),
+                    Setting::Timer(timer) => json!(timer),
+                };
+                let differences: Vec<Value> = differences
+                    .iter()
+                    .map(|it| {
+                        json!({
+                            "condition": it.condition.map(|it| it.to_string()),
+                            "setting": it.setting,
+                            "left": setting(&it.left),
+                            "right": setting(&it.right),
+                        })
+                    })
+                    .collect();
+                json!({ "against": against, "differences": differences })
+            }
+            Report::Plan {
+                matched,
+                changes,
+                dry_run,
+            } => {
+                let changes: Vec<String> = changes.iter().map(|it| it.to_string()).collect();
+                json!({ "matched": matched, "changes": changes, "dry_run": dry_run })
+            }
+        }
+    }
+}
+
+/// Result of one device, `result` is null on error
+pub fn entry(device: &str, result: &Result<Report>) -> Value {
+    match result {
+        Ok(report) => json!({ "device": device, "error": null, "result": report.to_value() }),
+        Err(e) => json!({ "device": device, "error": format!("{:#}", e), "result": null }),
+    }
+}
+
+/// Listed disk, `paths` holds every path to the drive
+pub fn disk(
+    disk: &Disk,
+    model: &str,
+    serial: Option<&str>,
+    epc: Option<&str>,
+    paths: &[String],
+) -> Value {
+    json!({
+        "device": disk.node(),
+        "error": null,
+        "result": {
+            "block": disk.block,
+            "sg": disk.sg,
+            "transport": disk.transport,
+            "model": model,
+            "serial": serial,
+            "wwn": disk.wwn.map(|it| format!("{:#x}", it)),
+            "epc": epc,
+            "paths": paths,
+            "multipath": disk.multipath,
+        },
+    })
+}
+
+/// Top level document of a subcommand over all devices
+pub fn document(command: &str, devices: Vec<Value>) -> Value {
+    json!({
+        "version": SCHEMA_VERSION,
+        "command": command,
+        "devices": devices,
+    })
+}
+
+/// Backups in a document of `backup`, JSON or YAML, failed devices are left out
+pub fn load_backups(path: &str) -> Result<Vec<Backup>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path))?;
+    // YAML parser reads JSON too
+    let document: Value =
+        serde_yaml::from_str(&text).with_context(|| format!("invalid backup {}", path))?;
+
+    anyhow::ensure!(
+        document["command"] == "backup",
+        "{} is not a document of wdepc backup",
+        path
+    );
+    anyhow::ensure!(
+        document["version"] == SCHEMA_VERSION,
+        "{} has schema version {}, expected {}",
+        path,
+        document["version"],
+        SCHEMA_VERSION
+    );
+
+    let devices = document["devices"].as_array().into_iter().flatten();
+    let backups = devices
+        .filter(|it| !it["result"].is_null())
+        .map(|it| {
+            Backup::deserialize(&it["result"])
+                .with_context(|| format!("invalid backup of {} in {}", it["device"], path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    anyhow::ensure!(!backups.is_empty(), "{} holds no backup", path);
+    Ok(backups)
+}
+
+pub fn print(format: Format, document: &Value) -> Result<()> {
+    match format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(document)?),
+        Format::Yaml => print!("{}", serde_yaml::to_string(document)?),
+        Format::Text => unreachable!("text output is printed by each subcommand"),
+    }
+
+    Ok(())
+}
+
+fn nvme_info(states: &NvmePowerStates, current: u8, apst: Option<&ApstSetting>) -> Value {
+    let power_states: Vec<Value> = states
+        .states
+        .iter()
+        .enumerate()
+        .map(|(i, state)| {
+            json!({
+                "state": i,
+                "max_power": state.max_power as f64 / 10000.0,
+                "operational": !state.non_operational,
+                "entry_latency": state.entry_latency,
+                "exit_latency": state.exit_latency,
+                "relative_read_throughput": state.relative_read_throughput,
+                "relative_read_latency": state.relative_read_latency,
+                "relative_write_throughput": state.relative_write_throughput,
+                "relative_write_latency": state.relative_write_latency,
+            })
+        })
+        .collect();
+
+    let apst = apst.map(|apst| {
+        let entries: Vec<Value> = apst
+            .entries
+            .iter()
+            .take(states.states.len())
+            .map(
+                |entry| json!({ "idle_time": entry.idle_time, "target_state": entry.target_state }),
+            )
+            .collect();
+        json!({ "enabled": apst.enabled, "entries": entries })
+    });
+
+    json!({
+        "power_state": current,
+        "power_states": power_states,
+        "apst_supported": states.apst_supported,
+        "apst": apst,
+    })
+}
@@ -0,0 +1,198 @@
+//! Built-in power presets, from `performance` to `cold`
+//!
+//! Timers are clamped to the range each drive accepts.
+
+use crate::device::{EPCSetting, PowerMode};
+use crate::profile::ConditionProfile;
+
+pub struct Preset {
+    pub name: &'static str,
+    pub about: &'static str,
+    /// timer of each condition from idle a to standby z in 100 milliseconds, None disables it
+    pub timers: [Option<u32>; 5],
+    /// APM level for drives without EPC, 255 disables APM
+    pub apm: u8,
+}
+
+pub const PRESETS: [Preset; 5] = [
+    Preset {
+        name: "performance",
+        about: "idle a only, the drive stays spinning at full speed",
+        timers: [Some(20), None, None, None, None],
+        apm: 254,
+    },
+    Preset {
+        name: "balanced",
+        about: "idle conditions, heads unloaded after 10 minutes, never spins down",
+        timers: [Some(20), Some(1200), Some(6000), None, None],
+        apm: 128,
+    },
+    Preset {
+        name: "nearline",
+        about: "reduced speed after 20 minutes, never spins down",
+        timers: [Some(10), Some(600), Some(3000), Some(12000), None],
+        apm: 128,
+    },
+    Preset {
+        name: "archive",
+        about: "spins down after 15 minutes",
+        timers: [Some(10), Some(300), Some(1200), Some(3000), Some(9000)],
+        apm: 64,
+    },
+    Preset {
+        name: "cold",
+        about: "spins down after 5 minutes, for rarely read drives",
+        timers: [Some(10), Some(100), Some(300), Some(600), Some(3000)],
+        apm: 1,
+    },
+];
+
+impl Preset {
+    pub fn by_name(name: &str) -> Option<&'static Preset> {
+        PRESETS.iter().find(|it| it.name == name)
+    }
+
+    /// Conditions for a drive, timers clamped to its range
+    ///
+    /// Conditions the drive doesn't support are left out
+    pub fn conditions(&self, setting: &EPCSetting) -> Vec<(PowerMode, ConditionProfile)> {
+        PowerMode::CONDITIONS
+            .iter()
+            .zip(self.timers.iter())
+            .filter_map(|(mode, timer)| {
+                let desc = setting.condition(*mode).unwrap();
+                if !desc.supported {
+                    return None;
+                }
+
+                let condition = match *timer {
+                    Some(mut timer) => {
+                        // zero max timer means the drive doesn't report a range
+                        if desc.max_timer != 0 {
+                            timer = timer.max(desc.min_timer).min(desc.max_timer);
+                        }
+                        ConditionProfile {
+                            enabled: true,
+                            timer: Some(timer.min(u16::MAX as u32) as u16),
+                        }
+                    }
+                    None => ConditionProfile {
+                        enabled: false,
+                        timer: None,
+                    },
+                };
+                Some((*mode, condition))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeDrive;
+    use crate::profile::{Change, Profile};
+
+    /// Conditions of `preset` for `drive` as mode, enabled and timer
+    fn conditions(preset: &str, drive: &FakeDrive) -> Vec<(PowerMode, bool, Option<u16>)> {
+        let setting = drive.device().query_epc_setting().unwrap();
+        Preset::by_name(preset)
+            .unwrap()
+            .conditions(&setting)
+            .into_iter()
+            .map(|(mode, it)| (mode, it.enabled, it.timer))
+            .collect()
+    }
+
+    #[test]
+    fn timers_in_range() {
+        let drive = FakeDrive::new();
+
+        assert_eq!(
+            conditions("cold", &drive),
+            [
+                (PowerMode::IdleA, true, Some(10)),
+                (PowerMode::IdleB, true, Some(100)),
+                (PowerMode::IdleC, true, Some(300)),
+                (PowerMode::StandbyY, true, Some(600)),
+                (PowerMode::StandbyZ, true, Some(3000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn timers_clamped() {
+        let drive = FakeDrive::new();
+        {
+            let mut state = drive.state();
+            state.condition(PowerMode::IdleA).min_timer = 50;
+            state.condition(PowerMode::IdleC).max_timer = 200;
+            // no range reported
+            state.condition(PowerMode::StandbyZ).min_timer = 6000;
+            state.condition(PowerMode::StandbyZ).max_timer = 0;
+        }
+
+        assert_eq!(
+            conditions("cold", &drive),
+            [
+                (PowerMode::IdleA, true, Some(50)),
+                (PowerMode::IdleB, true, Some(100)),
+                (PowerMode::IdleC, true, Some(200)),
+                (PowerMode::StandbyY, true, Some(600)),
+                (PowerMode::StandbyZ, true, Some(3000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_left_out() {
+        let drive = FakeDrive::new();
+        drive.state().condition(PowerMode::IdleB).supported = false;
+        drive.state().condition(PowerMode::StandbyY).supported = false;
+
+        assert_eq!(
+            conditions("performance", &drive),
+            [
+                (PowerMode::IdleA, true, Some(20)),
+                (PowerMode::IdleC, false, None),
+                (PowerMode::StandbyZ, false, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_changeable() {
+        let drive = FakeDrive::new();
+        drive.state().condition(PowerMode::IdleC).changeable = false;
+
+        // kept, the drive may already be there
+        assert!(conditions("cold", &drive).contains(&(PowerMode::IdleC, true, Some(300))));
+
+        let device = drive.device();
+        let identify = device.identify().unwrap();
+        let profile = Profile::preset(Preset::by_name("cold").unwrap(), false);
+        let plan = |drive: &FakeDrive| {
+            let setting = drive.device().query_epc_setting().unwrap();
+            profile.drives[0].plan(&identify, Some(&setting))
+        };
+
+        let e = plan(&drive).unwrap_err();
+        assert_eq!(e.to_string(), "idle_c is not changeable");
+
+        {
+            let mut state = drive.state();
+            let idle_c = state.condition(PowerMode::IdleC);
+            idle_c.current_enable = true;
+            idle_c.current_timer = 300;
+        }
+        let changes = plan(&drive).unwrap();
+        assert_eq!(changes.len(), 4);
+        assert!(!changes.iter().any(|it| matches!(
+            it,
+            Change::SetTimer {
+                mode: PowerMode::IdleC,
+                ..
+            }
+        )));
+    }
+}
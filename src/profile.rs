@@ -0,0 +1,803 @@
+//! Declarative EPC profile, the desired state of each drive
+//!
+//! ```toml
+//! # the first matching drive section applies, a section without match applies to every drive
+//! [[drive]]
+//! match = { model = "WDC WUH72*" }
+//! epc = true
+//! # save settings, so they survive power cycles
+//! save = true
+//!
+//! [drive.idle_a]
+//! timer = "2s"
+//!
+//! [drive.standby_z]
+//! enabled = true
+//! timer = "30m"
+//!
+//! # built-in preset, conditions given override it
+//! [[drive]]
+//! match = { model = "ST8000*" }
+//! preset = "archive"
+//!
+//! # drives without EPC use APM, 255 disables it
+//! [[drive]]
+//! apm = 128
+//! ```
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use glob::Pattern;
+use toml::{Table, Value};
+
+use crate::device::{Device, EPCSetting, Identify, PowerMode};
+use crate::preset::Preset;
+
+pub struct Profile {
+    pub drives: Vec<DriveProfile>,
+}
+
+/// Desired state of matching drives, fields left out are not changed
+#[derive(Default)]
+pub struct DriveProfile {
+    /// glob pattern
+    pub model: Option<Pattern>,
+    pub serial: Option<String>,
+    pub wwn: Option<u64>,
+
+    /// enable or disable EPC
+    pub epc: Option<bool>,
+    /// save power condition settings
+    pub save: bool,
+    /// conditions and APM level not given are taken from the preset
+    pub preset: Option<&'static Preset>,
+    pub conditions: Vec<(PowerMode, ConditionProfile)>,
+    /// APM level for drives without EPC, 255 disables APM
+    pub apm: Option<u8>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ConditionProfile {
+    pub enabled: bool,
+    /// in 100 milliseconds
+    pub timer: Option<u16>,
+}
+
+/// Command bringing a drive closer to its profile
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    EnableEpc,
+    DisableEpc,
+    SetTimer {
+        mode: PowerMode,
+        timer: u16,
+        enable: bool,
+        save: bool,
+    },
+    SetState {
+        mode: PowerMode,
+        enable: bool,
+        save: bool,
+    },
+    /// None disables APM
+    SetApm(Option<u8>),
+}
+
+impl Change {
+    pub fn apply(&self, device: &mut Device) -> Result<()> {
+        match *self {
+            Change::EnableEpc => device.enable_epc(),
+            Change::DisableEpc => device.disable_epc(),
+            Change::SetTimer {
+                mode,
+                timer,
+                enable,
+                save,
+            } => device.set_timer(mode.try_into()?, timer, enable, save),
+            Change::SetState { mode, enable, save } => {
+                device.set_state(mode.try_into()?, enable, save)
+            }
+            Change::SetApm(level) => device.set_apm(level),
+        }
+    }
+}
+
+/// Change as the `wdepc` subcommand doing the same, APM has no subcommand and is described
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let save = |save| if save { " --save" } else { "" };
+
+        match *self {
+            Change::EnableEpc => write!(f, "enable"),
+            Change::DisableEpc => write!(f, "disable"),
+            Change::SetTimer {
+                mode,
+                timer,
+                enable,
+                save: s,
+            } => write!(
+                f,
+                "set-timer {} {} --enable {}{}",
+                mode,
+                timer,
+                enable,
+                save(s)
+            ),
+            Change::SetState {
+                mode,
+                enable,
+                save: s,
+            } => write!(f, "set-state {} --enable {}{}", mode, enable, save(s)),
+            Change::SetApm(Some(level)) => write!(f, "APM level -> {}", level),
+            Change::SetApm(None) => write!(f, "APM -> disabled"),
+        }
+    }
+}
+
+impl Profile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Profile> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read profile {}", path.display()))?;
+
+        Profile::parse(&text).with_context(|| format!("invalid profile {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Profile> {
+        let mut table: Table = text.parse()?;
+
+        let drives = match table.remove("drive") {
+            Some(Value::Array(drives)) => drives,
+            Some(_) => anyhow::bail!("drive: expected [[drive]] sections"),
+            None => Vec::new(),
+        };
+        if let Some(key) = table.keys().next() {
+            anyhow::bail!("unknown key {}", key);
+        }
+
+        let drives = drives
+            .into_iter()
+            .enumerate()
+            .map(|(i, drive)| {
+                let name = format!("drive[{}]", i);
+                match drive {
+                    Value::Table(drive) => parse_drive(drive, &name),
+                    _ => anyhow::bail!("{}: expected table", name),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Profile { drives })
+    }
+
+    /// Profile applying `preset` to every drive
+    pub fn preset(preset: &'static Preset, save: bool) -> Profile {
+        let drive = DriveProfile {
+            preset: Some(preset),
+            save,
+            ..DriveProfile::default()
+        };
+
+        Profile {
+            drives: vec![drive],
+        }
+    }
+
+    /// First drive section matching the drive
+    pub fn find(&self, identify: &Identify) -> Option<&DriveProfile> {
+        self.drives.iter().find(|it| it.matches(identify))
+    }
+}
+
+impl DriveProfile {
+    pub fn matches(&self, identify: &Identify) -> bool {
+        self.model
+            .as_ref()
+            .map_or(true, |it| it.matches(&identify.model))
+            && self
+                .serial
+                .as_ref()
+                .map_or(true, |it| *it == identify.serial)
+            && self.wwn.map_or(true, |it| identify.wwn == Some(it))
+    }
+
+    /// Desired EPC state, a preset needs EPC enabled
+    pub fn epc(&self) -> Option<bool> {
+        self.epc.or(self.preset.map(|_| true))
+    }
+
+    /// Desired conditions of a drive, the preset's clamped to the drive's range and
+    /// overridden by conditions given, from idle a to standby z
+    pub fn conditions(&self, setting: &EPCSetting) -> Vec<(PowerMode, ConditionProfile)> {
+        let mut conditions = self
+            .preset
+            .map_or_else(Vec::new, |it| it.conditions(setting));
+        for (mode, condition) in &self.conditions {
+            conditions.retain(|(it, _)| it != mode);
+            conditions.push((*mode, *condition));
+        }
+        conditions.sort_by_key(|(mode, _)| {
+            PowerMode::CONDITIONS
+                .iter()
+                .position(|it| it == mode)
+                .unwrap()
+        });
+
+        conditions
+    }
+
+    /// Commands to bring the drive to this profile, empty if it is already there
+    ///
+    /// `setting` is required if the drive supports EPC
+    pub fn plan(&self, identify: &Identify, setting: Option<&EPCSetting>) -> Result<Vec<Change>> {
+        let mut changes = Vec::new();
+
+        if !identify.epc_supported {
+            // APM is the fallback of drives without EPC
+            match self.apm.or(self.preset.map(|it| it.apm)) {
+                Some(level) => {
+                    anyhow::ensure!(identify.apm_supported, "drive doesn't support APM");
+                    let level = if level == 255 { None } else { Some(level) };
+                    if identify.apm_level != level {
+                        changes.push(Change::SetApm(level));
+                    }
+                }
+                None => anyhow::ensure!(
+                    self.epc != Some(true) && self.conditions.is_empty() && self.preset.is_none(),
+                    "drive doesn't support EPC"
+                ),
+            }
+            return Ok(changes);
+        }
+
+        let epc = self.epc();
+        match epc {
+            Some(true) if !identify.epc_enabled => changes.push(Change::EnableEpc),
+            Some(false) if identify.epc_enabled => changes.push(Change::DisableEpc),
+            _ => {}
+        }
+
+        if self.conditions.is_empty() && self.preset.is_none() {
+            return Ok(changes);
+        }
+        // drives abort power condition commands while EPC is disabled
+        anyhow::ensure!(
+            epc.unwrap_or(identify.epc_enabled),
+            "EPC is disabled, set epc = true to change power conditions"
+        );
+        let setting = setting.context("EPC settings are required")?;
+
+        let conditions = self.conditions(setting);
+        for (mode, condition) in &conditions {
+            let desc = setting.condition(*mode).unwrap();
+            anyhow::ensure!(desc.supported, "{} is not supported", mode);
+
+            // settings already saved aren't saved again
+            let enable_saved = desc.saved_enable == condition.enabled;
+            let enable_done = desc.current_enable == condition.enabled;
+            let (change, save) = match condition.timer {
+                Some(timer) => {
+                    anyhow::ensure!(
+                        desc.max_timer == 0
+                            || (desc.min_timer..=desc.max_timer).contains(&(timer as u32)),
+                        "{} timer {} is out of range {} to {}",
+                        mode,
+                        timer,
+                        desc.min_timer,
+                        desc.max_timer
+                    );
+                    let save = self.save && !(enable_saved && desc.saved_timer == timer as u32);
+                    if !save && enable_done && desc.current_timer == timer as u32 {
+                        continue;
+                    }
+                    let change = Change::SetTimer {
+                        mode: *mode,
+                        timer,
+                        enable: condition.enabled,
+                        save,
+                    };
+                    (change, save)
+                }
+                None => {
+                    let save = self.save && !enable_saved;
+                    if !save && enable_done {
+                        continue;
+                    }
+                    let change = Change::SetState {
+                        mode: *mode,
+                        enable: condition.enabled,
+                        save,
+                    };
+                    (change, save)
+                }
+            };
+
+            anyhow::ensure!(desc.changeable, "{} is not changeable", mode);
+            anyhow::ensure!(!save || desc.savable, "{} is not savable", mode);
+            changes.push(change);
+        }
+
+        Ok(changes)
+    }
+}
+
+fn parse_drive(mut drive: Table, name: &str) -> Result<DriveProfile> {
+    let mut profile = DriveProfile::default();
+
+    if let Some(matcher) = drive.remove("match") {
+        let name = format!("{}.match", name);
+        let Value::Table(matcher) = matcher else {
+            anyhow::bail!("{}: expected table", name);
+        };
+        for (key, value) in matcher {
+            let name = format!("{}.{}", name, key);
+            let value = string(value, &name)?;
+            match key.as_str() {
+                "model" => {
+                    let model = Pattern::new(&value)
+                        .with_context(|| format!("{}: invalid pattern {}", name, value))?;
+                    profile.model = Some(model);
+                }
+                "serial" => profile.serial = Some(value),
+                "wwn" => {
+                    let hex = value.trim_start_matches("0x");
+                    let wwn = u64::from_str_radix(hex, 16)
+                        .with_context(|| format!("{}: invalid WWN {}", name, value))?;
+                    profile.wwn = Some(wwn);
+                }
+                _ => anyhow::bail!("{}: unknown key", name),
+            }
+        }
+    }
+
+    for (key, value) in drive {
+        let name = format!("{}.{}", name, key);
+        match key.as_str() {
+            "epc" => profile.epc = Some(boolean(value, &name)?),
+            "save" => profile.save = boolean(value, &name)?,
+            "preset" => {
+                let value = string(value, &name)?;
+                let preset = Preset::by_name(&value)
+                    .with_context(|| format!("{}: unknown preset {}", name, value))?;
+                profile.preset = Some(preset);
+            }
+            "apm" => {
+                let level = integer(value, &name)?;
+                let level = u8::try_from(level)
+                    .ok()
+                    .filter(|it| *it != 0)
+                    .with_context(|| format!("{}: APM level {} is not 1 to 255", name, level))?;
+                profile.apm = Some(level);
+            }
+            key => {
+                let mode = PowerMode::CONDITIONS
+                    .iter()
+                    .copied()
+                    .find(|it| it.to_string() == key)
+                    .with_context(|| format!("{}: unknown key", name))?;
+                let Value::Table(condition) = value else {
+                    anyhow::bail!("{}: expected table", name);
+                };
+                let condition = parse_condition(condition, &name)?;
+                profile.conditions.push((mode, condition));
+            }
+        }
+    }
+
+    Ok(profile)
+}
+
+fn parse_condition(condition: Table, name: &str) -> Result<ConditionProfile> {
+    let mut enabled = None;
+    let mut timer = None;
+
+    for (key, value) in condition {
+        let name = format!("{}.{}", name, key);
+        match key.as_str() {
+            "enabled" => enabled = Some(boolean(value, &name)?),
+            "timer" => {
+                let value = string(value, &name)?;
+                timer = Some(parse_timer(&value).with_context(|| name.clone())?);
+            }
+            _ => anyhow::bail!("{}: unknown key", name),
+        }
+    }
+    anyhow::ensure!(
+        enabled.is_some() || timer.is_some(),
+        "{}: enabled or timer is required",
+        name
+    );
+
+    Ok(ConditionProfile {
+        // a timer is set to be used
+        enabled: enabled.unwrap_or(true),
+        timer,
+    })
+}
+
+/// Parse duration such as `500ms`, `2s`, `30m`, `1h` or `1h30m` into 100 milliseconds
+pub fn parse_timer(s: &str) -> Result<u16> {
+    let invalid = || format!("invalid timer {}, eg 500ms, 2s, 30m or 1h", s);
+
+    let mut ms: u64 = 0;
+    let mut rest = s.trim();
+    anyhow::ensure!(!rest.is_empty(), invalid());
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let value: u64 = rest[..digits].parse().ok().with_context(invalid)?;
+        rest = &rest[digits..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let scale = match &rest[..unit] {
+            "ms" => 1,
+            "s" => 1000,
+            "m" | "min" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            _ => anyhow::bail!(invalid()),
+        };
+        rest = &rest[unit..];
+
+        ms = ms.saturating_add(value.saturating_mul(scale));
+    }
+
+    let timer = u16::try_from(ms / 100)
+        .ok()
+        .with_context(|| format!("timer {} is longer than 6553.5s", s))?;
+    anyhow::ensure!(ms % 100 == 0, "timer {} is not a multiple of 100ms", s);
+    Ok(timer)
+}
+
+/// Format 100 milliseconds as parsed by [`parse_timer`], eg 18000 as `30m`
+pub fn format_timer(timer: u32) -> String {
+    let ms = timer as u64 * 100;
+    let units = [
+        ("h", 60 * 60 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+        ("ms", 1),
+    ];
+
+    let mut rest = ms;
+    let mut s = String::new();
+    for (unit, scale) in units.iter() {
+        if rest >= *scale {
+            s += &format!("{}{}", rest / scale, unit);
+            rest %= scale;
+        }
+    }
+    if s.is_empty() {
+        s = "0s".to_string();
+    }
+    s
+}
+
+fn string(value: Value, name: &str) -> Result<String> {
+    match value {
+        Value::String(value) => Ok(value),
+        _ => anyhow::bail!("{}: expected string", name),
+    }
+}
+
+fn boolean(value: Value, name: &str) -> Result<bool> {
+    match value {
+        Value::Boolean(value) => Ok(value),
+        _ => anyhow::bail!("{}: expected true or false", name),
+    }
+}
+
+fn integer(value: Value, name: &str) -> Result<i64> {
+    match value {
+        Value::Integer(value) => Ok(value),
+        _ => anyhow::bail!("{}: expected integer", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeDrive;
+
+    /// Plan of the drive section of `profile` matching `drive`
+    fn plan(profile: &str, drive: &FakeDrive) -> Result<Vec<Change>> {
+        let device = drive.device();
+        let identify = device.identify()?;
+        let setting = if identify.epc_supported {
+            Some(device.query_epc_setting()?)
+        } else {
+            None
+        };
+
+        let profile = Profile::parse(profile)?;
+        profile
+            .find(&identify)
+            .context("no drive section matches")?
+            .plan(&identify, setting.as_ref())
+    }
+
+    const PROFILE: &str = r#"
+[[drive]]
+epc = true
+save = true
+
+[drive.idle_a]
+timer = "2s"
+
+[drive.standby_z]
+enabled = true
+timer = "15m"
+"#;
+
+    #[test]
+    fn plan_matching_drive() {
+        let drive = FakeDrive::new();
+        assert_eq!(plan(PROFILE, &drive).unwrap(), []);
+    }
+
+    #[test]
+    fn plan_saves_only_changed_settings() {
+        let drive = FakeDrive::new();
+        // current timer differs, saved one is right
+        drive.state().condition(PowerMode::StandbyZ).current_timer = 3000;
+        // idle b enabled but not saved so
+        drive.state().condition(PowerMode::IdleB).current_enable = true;
+        let profile = format!("{}\n[drive.idle_b]\nenabled = true\n", PROFILE);
+
+        assert_eq!(
+            plan(&profile, &drive).unwrap(),
+            [
+                Change::SetState {
+                    mode: PowerMode::IdleB,
+                    enable: true,
+                    save: true,
+                },
+                Change::SetTimer {
+                    mode: PowerMode::StandbyZ,
+                    timer: 9000,
+                    enable: true,
+                    save: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn plan_timer_out_of_range() {
+        let drive = FakeDrive::new();
+        drive.state().condition(PowerMode::IdleA).min_timer = 100;
+
+        let e = plan(PROFILE, &drive).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "idle_a timer 20 is out of range 100 to 65535"
+        );
+
+        drive.state().condition(PowerMode::IdleA).min_timer = 1;
+        drive.state().condition(PowerMode::StandbyZ).max_timer = 6000;
+        let e = plan(PROFILE, &drive).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "standby_z timer 9000 is out of range 1 to 6000"
+        );
+    }
+
+    #[test]
+    fn plan_epc_disabled() {
+        let drive = FakeDrive::new();
+        drive.state().epc_enabled = false;
+
+        let changes = plan(PROFILE, &drive).unwrap();
+        assert_eq!(changes[0], Change::EnableEpc);
+        // saved settings already match
+        assert_eq!(changes.len(), 1);
+
+        // conditions need EPC enabled
+        let profile = PROFILE.replace("epc = true\n", "");
+        let e = plan(&profile, &drive).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "EPC is disabled, set epc = true to change power conditions"
+        );
+
+        let profile = "[[drive]]\nepc = false\n";
+        assert_eq!(plan(profile, &drive).unwrap(), []);
+    }
+
+    #[test]
+    fn plan_apm_fallback() {
+        let drive = FakeDrive::new();
+        drive.state().epc_supported = false;
+
+        let profile = "[[drive]]\napm = 128\n";
+        assert_eq!(plan(profile, &drive).unwrap(), [Change::SetApm(Some(128))]);
+        drive.state().apm_level = Some(128);
+        assert_eq!(plan(profile, &drive).unwrap(), []);
+        assert_eq!(
+            plan("[[drive]]\napm = 255\n", &drive).unwrap(),
+            [Change::SetApm(None)]
+        );
+        // the preset's APM level
+        let preset = Preset::by_name("archive").unwrap();
+        assert_ne!(preset.apm, 128);
+        assert_eq!(
+            plan("[[drive]]\npreset = \"archive\"\n", &drive).unwrap(),
+            [Change::SetApm(Some(preset.apm).filter(|it| *it != 255))]
+        );
+
+        let e = plan(PROFILE, &drive).unwrap_err();
+        assert_eq!(e.to_string(), "drive doesn't support EPC");
+
+        drive.state().apm_supported = false;
+        let e = plan(profile, &drive).unwrap_err();
+        assert_eq!(e.to_string(), "drive doesn't support APM");
+    }
+
+    #[test]
+    fn parse_profile() {
+        let profile = Profile::parse(
+            r#"
+[[drive]]
+match = { model = "WDC WUH72*", wwn = "0x5000cca29ac12345" }
+apm = 200
+
+[drive.idle_c]
+enabled = false
+
+[[drive]]
+preset = "archive"
+"#,
+        )
+        .unwrap();
+        assert_eq!(profile.drives.len(), 2);
+
+        let drive = &profile.drives[0];
+        assert_eq!(drive.wwn, Some(0x5000_cca2_9ac1_2345));
+        assert_eq!(drive.apm, Some(200));
+        assert_eq!(drive.epc, None);
+        let (mode, condition) = drive.conditions[0];
+        assert_eq!(mode, PowerMode::IdleC);
+        assert!(!condition.enabled);
+        assert_eq!(condition.timer, None);
+        assert_eq!(profile.drives[1].epc(), Some(true));
+
+        let identify = FakeDrive::new().device().identify().unwrap();
+        assert!(std::ptr::eq(profile.find(&identify).unwrap(), drive));
+        let other = Identify {
+            wwn: Some(1),
+            ..identify
+        };
+        assert!(std::ptr::eq(
+            profile.find(&other).unwrap(),
+            &profile.drives[1]
+        ));
+    }
+
+    #[test]
+    fn parse_profile_errors() {
+        let errors = [
+            ("epc = true", "unknown key epc"),
+            ("[[drive]]\nidle_d = {}", "drive[0].idle_d: unknown key"),
+            ("[[drive]]\nepc = 1", "drive[0].epc: expected true or false"),
+            (
+                "[[drive]]\napm = 0",
+                "drive[0].apm: APM level 0 is not 1 to 255",
+            ),
+            (
+                "[[drive]]\napm = 256",
+                "drive[0].apm: APM level 256 is not 1 to 255",
+            ),
+            (
+                "[[drive]]\npreset = \"fast\"",
+                "drive[0].preset: unknown preset fast",
+            ),
+            (
+                "[[drive]]\nmatch = { size = \"8T\" }",
+                "drive[0].match.size: unknown key",
+            ),
+            (
+                "[[drive]]\n[drive.idle_a]",
+                "drive[0].idle_a: enabled or timer is required",
+            ),
+        ];
+
+        for (profile, message) in errors.iter() {
+            let e = Profile::parse(profile).err().unwrap();
+            assert_eq!(e.to_string(), *message, "{}", profile);
+        }
+
+        let e = Profile::parse("[[drive]]\n[drive.idle_a]\ntimer = \"150ms\"")
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!("{:#}", e),
+            "drive[0].idle_a.timer: timer 150ms is not a multiple of 100ms"
+        );
+    }
+
+    #[test]
+    fn timer_units() {
+        let timers = [
+            ("500ms", 5),
+            ("2s", 20),
+            ("30m", 18000),
+            ("1min", 600),
+            ("1h30m", 54000),
+            ("1m30s", 900),
+            (" 2s ", 20),
+            ("0s", 0),
+            ("6553500ms", 65535),
+        ];
+        for (s, timer) in timers.iter() {
+            assert_eq!(parse_timer(s).unwrap(), *timer, "{}", s);
+            assert_eq!(parse_timer(&format_timer(*timer as u32)).unwrap(), *timer);
+        }
+        assert_eq!(format_timer(54000), "1h30m");
+        assert_eq!(format_timer(0), "0s");
+    }
+
+    #[test]
+    fn timer_errors() {
+        let errors = [
+            ("150ms", "timer 150ms is not a multiple of 100ms"),
+            ("1s50ms", "timer 1s50ms is not a multiple of 100ms"),
+            ("2h", "timer 2h is longer than 6553.5s"),
+            ("6553600ms", "timer 6553600ms is longer than 6553.5s"),
+            // saturates instead of wrapping around
+            (
+                "18446744073709551615h",
+                "timer 18446744073709551615h is longer than 6553.5s",
+            ),
+            ("", "invalid timer , eg 500ms, 2s, 30m or 1h"),
+            ("  ", "invalid timer   , eg 500ms, 2s, 30m or 1h"),
+            ("30", "invalid timer 30, eg 500ms, 2s, 30m or 1h"),
+            ("s", "invalid timer s, eg 500ms, 2s, 30m or 1h"),
+            ("2d", "invalid timer 2d, eg 500ms, 2s, 30m or 1h"),
+            ("-2s", "invalid timer -2s, eg 500ms, 2s, 30m or 1h"),
+            (
+                "99999999999999999999s",
+                "invalid timer 99999999999999999999s, eg 500ms, 2s, 30m or 1h",
+            ),
+        ];
+
+        for (s, message) in errors.iter() {
+            assert_eq!(parse_timer(s).unwrap_err().to_string(), *message, "{}", s);
+        }
+    }
+
+    #[test]
+    fn change_display() {
+        let changes = [
+            (Change::EnableEpc, "enable"),
+            (Change::DisableEpc, "disable"),
+            (
+                Change::SetTimer {
+                    mode: PowerMode::StandbyZ,
+                    timer: 9000,
+                    enable: true,
+                    save: true,
+                },
+                "set-timer standby_z 9000 --enable true --save",
+            ),
+            (
+                Change::SetState {
+                    mode: PowerMode::IdleB,
+                    enable: false,
+                    save: false,
+                },
+                "set-state idle_b --enable false",
+            ),
+            (Change::SetApm(Some(128)), "APM level -> 128"),
+            (Change::SetApm(None), "APM -> disabled"),
+        ];
+
+        for (change, text) in changes.iter() {
+            assert_eq!(change.to_string(), *text);
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! Python extension module `wdepc`, built with the `python` feature
+//!
+//! ```python
+//! import wdepc
+//!
+//! device = wdepc.Device("/dev/sda")
+//! if device.query_mode() != wdepc.PowerMode.StandbyZ:
+//!     device.set_timer(wdepc.PowerMode.StandbyZ, 36000, enable=True, save=False)
+//! ```
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::device::{
+    Device, DeviceType, EPCSetting, Identify, PassthroughType, PowerCondDescriptor, PowerCondition,
+    PowerMode,
+};
+use crate::transport::{ScsiStatus, Transport};
+
+create_exception!(wdepc, WdepcError, PyException, "Base of wdepc errors");
+create_exception!(
+    wdepc,
+    OpenError,
+    WdepcError,
+    "Device can not be opened or no pass-through works"
+);
+create_exception!(
+    wdepc,
+    CommandError,
+    WdepcError,
+    "Command failed or was rejected by the drive"
+);
+
+fn open_error(e: anyhow::Error) -> PyErr {
+    OpenError::new_err(format!("{:#}", e))
+}
+
+fn command_error(e: anyhow::Error) -> PyErr {
+    CommandError::new_err(format!("{:#}", e))
+}
+
+#[pyclass(name = "PowerMode", eq, eq_int, frozen)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PyPowerMode {
+    Active,
+    IdleA,
+    IdleB,
+    IdleC,
+    StandbyY,
+    StandbyZ,
+    Unknown,
+    /// every supported condition, for set_timer, set_state and restore, never queried
+    All,
+}
+
+impl From<PowerMode> for PyPowerMode {
+    fn from(mode: PowerMode) -> Self {
+        match mode {
+            PowerMode::Active => PyPowerMode::Active,
+            PowerMode::IdleA => PyPowerMode::IdleA,
+            PowerMode::IdleB => PyPowerMode::IdleB,
+            PowerMode::IdleC => PyPowerMode::IdleC,
+            PowerMode::StandbyY => PyPowerMode::StandbyY,
+            PowerMode::StandbyZ => PyPowerMode::StandbyZ,
+            PowerMode::Unknown => PyPowerMode::Unknown,
+        }
+    }
+}
+
+impl PyPowerMode {
+    /// Power condition to set, active and unknown are not settable
+    ///
+    /// `goto_cond` rejects all, the drive can't be in every condition at once
+    fn settable(self) -> PyResult<PowerCondition> {
+        match self {
+            PyPowerMode::IdleA => Ok(PowerCondition::IdleA),
+            PyPowerMode::IdleB => Ok(PowerCondition::IdleB),
+            PyPowerMode::IdleC => Ok(PowerCondition::IdleC),
+            PyPowerMode::StandbyY => Ok(PowerCondition::StandbyY),
+            PyPowerMode::StandbyZ => Ok(PowerCondition::StandbyZ),
+            PyPowerMode::All => Ok(PowerCondition::All),
+            PyPowerMode::Active | PyPowerMode::Unknown => Err(PyValueError::new_err(format!(
+                "{:?} is not a power condition",
+                self
+            ))),
+        }
+    }
+}
+
+/// Power condition, times are in 100 milliseconds
+#[pyclass(name = "PowerCondition", get_all, frozen)]
+#[derive(Clone)]
+struct PyPowerCondition {
+    supported: bool,
+    savable: bool,
+    changeable: bool,
+    default_enable: bool,
+    saved_enable: bool,
+    current_enable: bool,
+    default_timer: u32,
+    saved_timer: u32,
+    current_timer: u32,
+    recovery_time: u32,
+    min_timer: u32,
+    max_timer: u32,
+}
+
+#[pymethods]
+impl PyPowerCondition {
+    fn __repr__(&self) -> String {
+        format!(
+            "PowerCondition(supported={}, current_enable={}, current_timer={}, \
+             saved_enable={}, saved_timer={}, default_enable={}, default_timer={})",
+            py_bool(self.supported),
+            py_bool(self.current_enable),
+            self.current_timer,
+            py_bool(self.saved_enable),
+            self.saved_timer,
+            py_bool(self.default_enable),
+            self.default_timer
+        )
+    }
+}
+
+impl From<PowerCondDescriptor> for PyPowerCondition {
+    fn from(desc: PowerCondDescriptor) -> Self {
+        PyPowerCondition {
+            supported: desc.supported,
+            savable: desc.savable,
+            changeable: desc.changeable,
+            default_enable: desc.default_enable,
+            saved_enable: desc.saved_enable,
+            current_enable: desc.current_enable,
+            default_timer: desc.default_timer,
+            saved_timer: desc.saved_timer,
+            current_timer: desc.current_timer,
+            recovery_time: desc.recovery_time,
+            min_timer: desc.min_timer,
+            max_timer: desc.max_timer,
+        }
+    }
+}
+
+#[pyclass(name = "EPCSetting", get_all, frozen)]
+struct PyEpcSetting {
+    idle_a: PyPowerCondition,
+    idle_b: PyPowerCondition,
+    idle_c: PyPowerCondition,
+    standby_y: PyPowerCondition,
+    standby_z: PyPowerCondition,
+}
+
+#[pymethods]
+impl PyEpcSetting {
+    fn __repr__(&self) -> String {
+        format!(
+            "EPCSetting(idle_a={}, idle_b={}, idle_c={}, standby_y={}, standby_z={})",
+            self.idle_a.__repr__(),
+            self.idle_b.__repr__(),
+            self.idle_c.__repr__(),
+            self.standby_y.__repr__(),
+            self.standby_z.__repr__()
+        )
+    }
+}
+
+impl From<EPCSetting> for PyEpcSetting {
+    fn from(setting: EPCSetting) -> Self {
+        PyEpcSetting {
+            idle_a: setting.idle_a.into(),
+            idle_b: setting.idle_b.into(),
+            idle_c: setting.idle_c.into(),
+            standby_y: setting.standby_y.into(),
+            standby_z: setting.standby_z.into(),
+        }
+    }
+}
+
+#[pyclass(name = "Identify", get_all, frozen)]
+struct PyIdentify {
+    model: String,
+    serial: String,
+    firmware: String,
+    wwn: Option<u64>,
+    epc_supported: bool,
+    epc_enabled: bool,
+    apm_supported: bool,
+    apm_level: Option<u8>,
+}
+
+#[pymethods]
+impl PyIdentify {
+    fn __repr__(&self) -> String {
+        format!(
+            "Identify(model={:?}, serial={:?}, epc_supported={}, epc_enabled={})",
+            self.model,
+            self.serial,
+            py_bool(self.epc_supported),
+            py_bool(self.epc_enabled)
+        )
+    }
+}
+
+impl From<Identify> for PyIdentify {
+    fn from(identify: Identify) -> Self {
+        PyIdentify {
+            model: identify.model,
+            serial: identify.serial,
+            firmware: identify.firmware,
+            wwn: identify.wwn,
+            epc_supported: identify.epc_supported,
+            epc_enabled: identify.epc_enabled,
+            apm_supported: identify.apm_supported,
+            apm_level: identify.apm_level,
+        }
+    }
+}
+
+fn py_bool(value: bool) -> &'static str {
+    if value {
+        "True"
+    } else {
+        "False"
+    }
+}
+
+/// Transport calling `sg_io(cdb, data, out_len)` of a Python object
+///
+/// It returns `(status, sense, data)`, `data` is what the drive sends back
+struct PyTransport {
+    object: PyObject,
+}
+
+impl Transport for PyTransport {
+    fn sg_io(
+        &self,
+        cdb: &mut [u8],
+        in_data: Option<&[u8]>,
+        out_data: Option<&mut [u8]>,
+    ) -> anyhow::Result<(ScsiStatus, [u8; 32])> {
+        Python::with_gil(|py| {
+            let out_len = out_data.as_ref().map_or(0, |it| it.len());
+            let cdb = PyBytes::new(py, cdb);
+            let data = in_data.map(|it| PyBytes::new(py, it));
+
+            let (status, sense, data): (u8, Vec<u8>, Vec<u8>) = self
+                .object
+                .call_method1(py, "sg_io", (cdb, data, out_len))
+                .and_then(|it| it.extract(py))
+                .map_err(|e| anyhow::anyhow!("transport sg_io failed: {}", e))?;
+
+            let mut sense_buf = [0u8; 32];
+            let len = sense.len().min(sense_buf.len());
+            sense_buf[..len].copy_from_slice(&sense[..len]);
+
+            if let Some(out_data) = out_data {
+                let len = data.len().min(out_data.len());
+                out_data[..len].copy_from_slice(&data[..len]);
+            }
+
+            let status = ScsiStatus {
+                status,
+                host_status: 0,
+            };
+            Ok((status, sense_buf))
+        })
+    }
+}
+
+fn parse_type(ty: &str) -> PyResult<DeviceType> {
+    ty.parse()
+        .map_err(|e: anyhow::Error| PyValueError::new_err(e.to_string()))
+}
+
+#[pyclass(name = "Device")]
+struct PyDevice {
+    device: Device,
+}
+
+#[pymethods]
+impl PyDevice {
+    /// Open device, `type` is as `--type`, eg `sat,12`
+    #[new]
+    #[pyo3(signature = (path, r#type = "auto"))]
+    fn new(py: Python<'_>, path: &str, r#type: &str) -> PyResult<Self> {
+        let ty = parse_type(r#type)?;
+        let device = py
+            .allow_threads(|| Device::open_with_type(path, ty))
+            .map_err(open_error)?;
+
+        Ok(PyDevice { device })
+    }
+
+    /// Build device over a Python object with a `sg_io(cdb, data, out_len)` method,
+    /// eg a fake drive
+    #[staticmethod]
+    #[pyo3(signature = (transport, passthrough = "auto"))]
+    fn with_transport(py: Python<'_>, transport: PyObject, passthrough: &str) -> PyResult<Self> {
+        let passthrough: PassthroughType = passthrough
+            .parse()
+            .map_err(|e: anyhow::Error| PyValueError::new_err(e.to_string()))?;
+        let transport = Box::new(PyTransport { object: transport });
+        let device = py
+            .allow_threads(|| Device::with_transport(transport, passthrough))
+            .map_err(open_error)?;
+
+        Ok(PyDevice { device })
+    }
+
+    fn query_mode(&self, py: Python<'_>) -> PyResult<PyPowerMode> {
+        let mode = py
+            .allow_threads(|| self.device.query_mode())
+            .map_err(command_error)?;
+        Ok(mode.into())
+    }
+
+    fn identify(&self, py: Python<'_>) -> PyResult<PyIdentify> {
+        let identify = py
+            .allow_threads(|| self.device.identify())
+            .map_err(command_error)?;
+        Ok(identify.into())
+    }
+
+    fn query_epc_setting(&self, py: Python<'_>) -> PyResult<PyEpcSetting> {
+        let setting = py
+            .allow_threads(|| self.device.query_epc_setting())
+            .map_err(command_error)?;
+        Ok(setting.into())
+    }
+
+    /// Timer is in 100 milliseconds
+    #[pyo3(signature = (mode, timer, enable = true, save = false))]
+    fn set_timer(
+        &mut self,
+        py: Python<'_>,
+        mode: PyPowerMode,
+        timer: u16,
+        enable: bool,
+        save: bool,
+    ) -> PyResult<()> {
+        let mode = mode.settable()?;
+        py.allow_threads(|| self.device.set_timer(mode, timer, enable, save))
+            .map_err(command_error)
+    }
+
+    #[pyo3(signature = (mode, enable, save = false))]
+    fn set_state(
+        &mut self,
+        py: Python<'_>,
+        mode: PyPowerMode,
+        enable: bool,
+        save: bool,
+    ) -> PyResult<()> {
+        let mode = mode.settable()?;
+        py.allow_threads(|| self.device.set_state(mode, enable, save))
+            .map_err(command_error)
+    }
+
+    fn goto_cond(&mut self, py: Python<'_>, mode: PyPowerMode) -> PyResult<()> {
+        let mode = mode.settable()?;
+        py.allow_threads(|| self.device.goto_cond(mode))
+            .map_err(command_error)
+    }
+
+    fn enable_epc(&mut self, py: Python<'_>) -> PyResult<()> {
+        py.allow_threads(|| self.device.enable_epc())
+            .map_err(command_error)
+    }
+
+    fn disable_epc(&mut self, py: Python<'_>) -> PyResult<()> {
+        py.allow_threads(|| self.device.disable_epc())
+            .map_err(command_error)
+    }
+
+    /// Restore from default if `default`, else from saved
+    #[pyo3(signature = (mode, default = false, save = false))]
+    fn restore(
+        &mut self,
+        py: Python<'_>,
+        mode: PyPowerMode,
+        default: bool,
+        save: bool,
+    ) -> PyResult<()> {
+        let mode = mode.settable()?;
+        py.allow_threads(|| self.device.restore(mode, default, save))
+            .map_err(command_error)
+    }
+}
+
+#[pymodule]
+fn wdepc(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyDevice>()?;
+    m.add_class::<PyPowerMode>()?;
+    m.add_class::<PyPowerCondition>()?;
+    m.add_class::<PyEpcSetting>()?;
+    m.add_class::<PyIdentify>()?;
+
+    let py = m.py();
+    m.add("WdepcError", py.get_type::<WdepcError>())?;
+    m.add("OpenError", py.get_type::<OpenError>())?;
+    m.add("CommandError", py.get_type::<CommandError>())?;
+
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! Count of settings saves sent to each drive, kept across runs
+//!
+//! Each save writes the drive's non-volatile memory, frequent saves, eg from config
+//! management applying a profile every few minutes, wear it.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+/// Add `saves` to the count of drive `key` in JSON file `path`, return the new count
+pub fn record(path: &str, key: &str, saves: u32) -> Result<u64> {
+    let path = Path::new(path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("unable to create {}", dir.display()))?;
+    }
+    // runs on other drives read, add and write the same file, one would lose the other's count
+    let _lock = lock(&path.with_extension("lock"))?;
+
+    let mut counts: BTreeMap<String, u64> = match fs::read_to_string(path) {
+        Ok(text) => {
+            let value: Value = serde_json::from_str(&text)
+                .with_context(|| format!("invalid save counter {}", path.display()))?;
+            value
+                .as_object()
+                .with_context(|| format!("{}: expected object", path.display()))?
+                .iter()
+                .filter_map(|(key, count)| Some((key.clone(), count.as_u64()?)))
+                .collect()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("unable to read {}", path.display()));
+        }
+    };
+
+    let count = counts.entry(key.to_string()).or_insert(0);
+    *count += saves as u64;
+    let count = *count;
+
+    // written aside and renamed, a reader never sees a partial file
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_string_pretty(&counts)?)
+        .with_context(|| format!("unable to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("unable to write {}", path.display()))?;
+
+    Ok(count)
+}
+
+/// Exclusive advisory lock on `path`, held until the file is dropped
+fn lock(path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("unable to open {}", path.display()))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("unable to lock {}", path.display()));
+    }
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_records_add_up() {
+        let dir = std::env::temp_dir().join(format!("wdepc-saves-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("saves.json");
+        let path = path.to_str().unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|n| {
+                let path = path.to_string();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        record(&path, &format!("serial:{}", n % 2), 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|it| it.join().unwrap());
+
+        assert_eq!(record(path, "serial:0", 0).unwrap(), 40);
+        assert_eq!(record(path, "serial:1", 0).unwrap(), 40);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use glob::Pattern;
+
+use crate::device::{Device, DeviceType};
+use crate::discovery::{find_mount, Disk, Sysfs};
+use crate::zfs;
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Way to address a drive that is stable across boots
+#[derive(Debug, Clone)]
+pub enum Selector {
+    /// device node or any link to it, eg /dev/sda or /dev/disk/by-id/ata-...,
+    /// or a glob pattern, eg /dev/sd[b-m]
+    Path(String),
+    /// `serial:WD-XXXX`
+    Serial(String),
+    /// `wwn:0x5000cca...`
+    Wwn(u64),
+    /// `model:HUH721212*`, glob pattern
+    Model(Pattern),
+    /// every disk supporting EPC
+    All,
+    /// disks backing the filesystem mounted at or containing the path, eg /srv/archive
+    Mount(String),
+    /// member disks of md array, eg md0
+    Md(String),
+    /// disks under device mapper device, eg vg-lv or dm-3, LVM volumes included
+    Dm(String),
+    /// disks of ZFS pool leaf vdevs, eg tank
+    Zpool(String),
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(serial) = s.strip_prefix("serial:") {
+            return Ok(Selector::Serial(serial.to_string()));
+        }
+
+        if let Some(wwn) = s.strip_prefix("wwn:") {
+            let hex = wwn.trim_start_matches("0x");
+            let wwn =
+                u64::from_str_radix(hex, 16).with_context(|| format!("invalid WWN {}", wwn))?;
+            return Ok(Selector::Wwn(wwn));
+        }
+
+        if let Some(model) = s.strip_prefix("model:") {
+            let model =
+                Pattern::new(model).with_context(|| format!("invalid model pattern {}", model))?;
+            return Ok(Selector::Model(model));
+        }
+
+        Ok(Selector::Path(s.to_string()))
+    }
+}
+
+/// Drive identity, from IDENTIFY DEVICE if the drive answers, else from sysfs
+struct Identity {
+    model: String,
+    serial: Option<String>,
+    wwn: Option<u64>,
+    epc_supported: bool,
+}
+
+impl Selector {
+    /// Resolve to device nodes, a model pattern may match several drives
+    ///
+    /// Paths are resolved through links, so `/dev/disk/by-id/...` gives `/dev/sdX`,
+    /// glob patterns are expanded
+    pub fn resolve(&self, sysfs: &Sysfs, ty: DeviceType) -> Result<Vec<String>> {
+        let matches = |identity: &Identity| match self {
+            Selector::Serial(serial) => identity.serial.as_deref() == Some(serial.as_str()),
+            Selector::Wwn(wwn) => identity.wwn == Some(*wwn),
+            Selector::Model(model) => model.matches(&identity.model),
+            Selector::All => identity.epc_supported,
+            _ => unreachable!(),
+        };
+
+        match self {
+            Selector::Path(path) => return resolve_path(path),
+            Selector::Mount(path) => return resolve_mount(path, sysfs),
+            Selector::Md(name) => {
+                let name = name.trim_start_matches("/dev/");
+                return sysfs.physical_disks(name);
+            }
+            Selector::Dm(name) => {
+                let name = name.trim_start_matches("/dev/mapper/");
+                let name = sysfs.dm_by_name(name.trim_start_matches("/dev/"))?;
+                return sysfs.physical_disks(&name);
+            }
+            Selector::Zpool(pool) => return resolve_zpool(pool, sysfs),
+            _ => {}
+        }
+
+        let devices: Vec<String> = sysfs
+            .disks()?
+            .iter()
+            .filter(|disk| matches(&identity(disk, ty)))
+            .map(|disk| disk.node().to_string())
+            .collect();
+
+        anyhow::ensure!(!devices.is_empty(), "no drive matches {}", self);
+        if let Selector::Serial(_) | Selector::Wwn(_) = self {
+            anyhow::ensure!(devices.len() == 1, "{} matches several drives", self);
+        }
+
+        Ok(devices)
+    }
+}
+
+/// Key identifying a physical drive across paths, WWN preferred over serial
+///
+/// None if the drive reports neither
+pub fn drive_key(wwn: Option<u64>, serial: Option<&str>) -> Option<String> {
+    match (wwn, serial) {
+        (Some(wwn), _) => Some(format!("wwn:{:#x}", wwn)),
+        (None, Some(serial)) if !serial.is_empty() => Some(format!("serial:{}", serial)),
+        _ => None,
+    }
+}
+
+/// Paths left after [`dedup_paths`]
+pub struct Dedup {
+    /// one path per drive
+    pub devices: Vec<String>,
+    /// path dropped, and the path kept for the same drive
+    pub skipped: Vec<(String, String)>,
+}
+
+/// Keep one path per physical drive, drop other paths of multipath drives
+///
+/// Devices not found in sysfs, eg NVMe, are kept as is
+pub fn dedup_paths(devices: Vec<String>, sysfs: &Sysfs, ty: DeviceType) -> Result<Dedup> {
+    // a single path is its own drive, don't IDENTIFY it just to find out
+    if devices.len() < 2 {
+        return Ok(Dedup {
+            devices,
+            skipped: Vec::new(),
+        });
+    }
+
+    let disks = sysfs.disks()?;
+
+    let mut seen: Vec<(String, String)> = Vec::new();
+    let mut unique = Vec::new();
+    let mut skipped = Vec::new();
+    for device in devices {
+        let disk = disks.iter().find(|disk| {
+            disk.block.as_deref() == Some(device.as_str())
+                || disk.sg.as_deref() == Some(device.as_str())
+        });
+        let key = disk.and_then(|disk| {
+            let identity = identity(disk, ty);
+            drive_key(identity.wwn, identity.serial.as_deref())
+        });
+
+        if let Some(key) = key {
+            if let Some((_, first)) = seen.iter().find(|(seen, _)| *seen == key) {
+                skipped.push((device, first.clone()));
+                continue;
+            }
+            seen.push((key, device.clone()));
+        }
+        unique.push(device);
+    }
+
+    Ok(Dedup {
+        devices: unique,
+        skipped,
+    })
+}
+
+impl std::fmt::Display for Selector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selector::Path(path) => write!(f, "{}", path),
+            Selector::Serial(serial) => write!(f, "serial:{}", serial),
+            Selector::Wwn(wwn) => write!(f, "wwn:{:#x}", wwn),
+            Selector::Model(model) => write!(f, "model:{}", model),
+            Selector::All => write!(f, "all"),
+            Selector::Mount(path) => write!(f, "path {}", path),
+            Selector::Md(name) => write!(f, "md {}", name),
+            Selector::Dm(name) => write!(f, "dm {}", name),
+            Selector::Zpool(pool) => write!(f, "zpool {}", pool),
+        }
+    }
+}
+
+fn identity(disk: &Disk, ty: DeviceType) -> Identity {
+    match Device::open_with_type(disk.node(), ty).and_then(|it| it.identify()) {
+        Ok(identify) => Identity {
+            model: identify.model,
+            serial: Some(identify.serial),
+            wwn: identify.wwn.or(disk.wwn),
+            epc_supported: identify.epc_supported,
+        },
+        Err(_) => Identity {
+            model: disk.model.clone(),
+            serial: disk.serial.clone(),
+            wwn: disk.wwn,
+            epc_supported: false,
+        },
+    }
+}
+
+fn resolve_path(path: &str) -> Result<Vec<String>> {
+    if !path.contains(['*', '?', '[']) {
+        let path = Path::new(path)
+            .canonicalize()
+            .with_context(|| format!("{} not found", path))?;
+        return Ok(vec![path.to_string_lossy().into_owned()]);
+    }
+
+    let mut devices = Vec::new();
+    for entry in glob::glob(path).with_context(|| format!("invalid pattern {}", path))? {
+        let path = entry?.canonicalize()?;
+        devices.push(path.to_string_lossy().into_owned());
+    }
+
+    anyhow::ensure!(!devices.is_empty(), "no device matches {}", path);
+
+    Ok(devices)
+}
+
+fn resolve_mount(path: &str, sysfs: &Sysfs) -> Result<Vec<String>> {
+    let canonical = Path::new(path)
+        .canonicalize()
+        .with_context(|| format!("{} not found", path))?;
+    let mountinfo = std::fs::read_to_string(MOUNTINFO)
+        .with_context(|| format!("unable to read {}", MOUNTINFO))?;
+    let mount =
+        find_mount(&mountinfo, &canonical).with_context(|| format!("no mount for {}", path))?;
+
+    // btrfs and other multi device filesystems report an anonymous device number
+    let block = if !mount.devno.starts_with("0:") {
+        sysfs.block_by_devno(&mount.devno)?
+    } else if mount.source.starts_with("/dev/") {
+        let source = Path::new(&mount.source).canonicalize()?;
+        source
+            .file_name()
+            .and_then(|it| it.to_str())
+            .with_context(|| format!("invalid mount source {}", mount.source))?
+            .to_string()
+    } else if mount.fstype == "zfs" {
+        let pool = mount.source.split('/').next().unwrap_or_default();
+        anyhow::bail!("{} is on ZFS, select its pool with --zpool {}", path, pool);
+    } else {
+        anyhow::bail!(
+            "{} is on {} {}, not backed by a block device",
+            path,
+            mount.fstype,
+            mount.source
+        );
+    };
+
+    sysfs.physical_disks(&block)
+}
+
+fn resolve_zpool(pool: &str, sysfs: &Sysfs) -> Result<Vec<String>> {
+    let mut disks = Vec::new();
+
+    for device in zfs::pool_devices(pool)? {
+        let block = Path::new(&device)
+            .canonicalize()
+            .with_context(|| format!("{} of pool {} not found", device, pool))?;
+        let block = block
+            .file_name()
+            .and_then(|it| it.to_str())
+            .with_context(|| format!("invalid device {}", device))?
+            .to_string();
+
+        for disk in sysfs.physical_disks(&block)? {
+            if !disks.contains(&disk) {
+                disks.push(disk);
+            }
+        }
+    }
+
+    Ok(disks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::PassthroughType;
+    use crate::transport::TransportType;
+
+    #[test]
+    fn single_path_is_not_deduplicated() {
+        let ty = DeviceType {
+            passthrough: PassthroughType::Auto,
+            transport: TransportType::SgIo,
+        };
+        // sysfs isn't read, nor the drive opened
+        let sysfs = Sysfs::new("/nonexistent");
+
+        let dedup = dedup_paths(vec!["/dev/sda".to_string()], &sysfs, ty).unwrap();
+        assert_eq!(dedup.devices, ["/dev/sda"]);
+        assert!(dedup.skipped.is_empty());
+    }
+
+    #[test]
+    fn parse_wwn() {
+        let wwn = 0x5000_cca2_9ac1_2345;
+        for s in ["wwn:0x5000cca29ac12345", "wwn:5000cca29ac12345"] {
+            assert!(
+                matches!(s.parse(), Ok(Selector::Wwn(it)) if it == wwn),
+                "{}",
+                s
+            );
+        }
+
+        let e = "wwn:0x5000ccz".parse::<Selector>().unwrap_err();
+        assert_eq!(e.to_string(), "invalid WWN 0x5000ccz");
+    }
+
+    #[test]
+    fn parse_model() {
+        let selector: Selector = "model:WUH72*".parse().unwrap();
+        assert!(matches!(&selector, Selector::Model(it) if it.matches("WUH721818ALE6L4")));
+        assert_eq!(selector.to_string(), "model:WUH72*");
+
+        let e = "model:WUH[72".parse::<Selector>().unwrap_err();
+        assert_eq!(e.to_string(), "invalid model pattern WUH[72");
+    }
+
+    #[test]
+    fn parse_serial_and_path() {
+        assert!(matches!("serial:3WJ0ABCD".parse(), Ok(Selector::Serial(it)) if it == "3WJ0ABCD"));
+        assert!(matches!("/dev/sda".parse(), Ok(Selector::Path(it)) if it == "/dev/sda"));
+    }
+
+    #[test]
+    fn drive_key_prefers_wwn() {
+        assert_eq!(
+            drive_key(Some(0x5000_cca2_9ac1_2345), Some("3WJ0ABCD")).as_deref(),
+            Some("wwn:0x5000cca29ac12345")
+        );
+        assert_eq!(
+            drive_key(None, Some("3WJ0ABCD")).as_deref(),
+            Some("serial:3WJ0ABCD")
+        );
+        // drives without either would all share one key
+        assert_eq!(drive_key(None, Some("")), None);
+        assert_eq!(drive_key(None, None), None);
+    }
+
+    #[test]
+    fn glob_without_match() {
+        let e = resolve_path("/dev/wdepc-nonexistent-sd[a-z]").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "no device matches /dev/wdepc-nonexistent-sd[a-z]"
+        );
+
+        let e = resolve_path("/dev/wdepc-nonexistent").unwrap_err();
+        assert_eq!(e.to_string(), "/dev/wdepc-nonexistent not found");
+    }
+}
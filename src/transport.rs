@@ -0,0 +1,371 @@
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use libc::c_int;
+use libc::{ioctl, O_ACCMODE, O_RDONLY, O_RDWR};
+
+use crate::ffi;
+use crate::ffi::{
+    build_megaraid_pthru, MegasasIocPacket, SgIoHdr, MEGASAS_IOCTL_NODE, MEGASAS_IOC_FIRMWARE,
+    MEGASAS_MAX_IOCTL_SGE, MEGASAS_PTHRU_SENSE_OFF, MEGASAS_PTHRU_SGL_OFF, MFI_FRAME_DIR_NONE,
+    MFI_FRAME_DIR_READ, MFI_FRAME_DIR_WRITE, MFI_STAT_OK, MFI_STAT_SCSI_DONE_WITH_ERROR,
+    SG_DXFER_FROM_DEV, SG_DXFER_NONE, SG_DXFER_TO_DEV, SG_IO,
+};
+
+/// How SCSI commands reach the drive
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransportType {
+    /// SG_IO ioctl on the device node
+    SgIo,
+    /// SG_IO ioctl with v4 header, on bsg or sg node
+    SgIoV4,
+    /// MegaRAID firmware pass-through to given physical device id
+    MegaRaid(u8),
+}
+
+/// SCSI command completion status
+#[derive(Debug, Copy, Clone)]
+pub struct ScsiStatus {
+    pub status: u8,
+    pub host_status: u16,
+}
+
+/// Execute SCSI commands
+pub trait Transport: Send + Sync {
+    /// Execute `cdb`, `in_data` is sent to device and `out_data` receives data from device
+    ///
+    /// Return completion status and sense buffer
+    fn sg_io(
+        &self,
+        cdb: &mut [u8],
+        in_data: Option<&[u8]>,
+        out_data: Option<&mut [u8]>,
+    ) -> Result<(ScsiStatus, [u8; 32])>;
+}
+
+/// SG_IO v3 ioctl on a block or sg node
+pub struct SgIo {
+    fd: OwnedFd,
+}
+
+impl SgIo {
+    pub fn new(fd: OwnedFd) -> SgIo {
+        SgIo { fd }
+    }
+}
+
+impl Transport for SgIo {
+    fn sg_io(
+        &self,
+        cdb: &mut [u8],
+        in_data: Option<&[u8]>,
+        out_data: Option<&mut [u8]>,
+    ) -> Result<(ScsiStatus, [u8; 32])> {
+        let mut hdr = SgIoHdr::default();
+        let mut sense = [0u8; 32];
+
+        hdr.cmd_len = cdb.len() as u8;
+
+        hdr.mx_sb_len = sense.len() as u8;
+        hdr.cmdp = cdb.as_mut_ptr();
+        hdr.sbp = sense.as_mut_ptr();
+
+        match (in_data, out_data) {
+            (Some(in_data), None) => {
+                hdr.dxfer_direction = SG_DXFER_TO_DEV;
+                hdr.dxfer_len = in_data.len() as u32;
+                hdr.dxferp = in_data.as_ptr() as *mut _; // safe, no write to in_data
+            }
+            (None, Some(out_data)) => {
+                hdr.dxfer_direction = SG_DXFER_FROM_DEV;
+                hdr.dxfer_len = out_data.len() as u32;
+                hdr.dxferp = out_data.as_mut_ptr() as *mut _;
+            }
+            (None, None) => {
+                hdr.dxfer_direction = SG_DXFER_NONE;
+            }
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one direction allowed");
+            }
+        }
+
+        let r = unsafe { ioctl(self.fd.as_raw_fd(), SG_IO, &mut hdr) };
+        anyhow::ensure!(r >= 0, "sg_io failed: {}", std::io::Error::last_os_error());
+
+        let status = ScsiStatus {
+            status: hdr.status,
+            host_status: hdr.host_status,
+        };
+
+        Ok((status, sense))
+    }
+}
+
+/// SG_IO v4 ioctl on a bsg or sg node
+///
+/// Unlike v3, data can be sent and received in the same command
+pub struct SgIoV4 {
+    fd: OwnedFd,
+}
+
+impl SgIoV4 {
+    pub fn new(fd: OwnedFd) -> SgIoV4 {
+        SgIoV4 { fd }
+    }
+}
+
+impl Transport for SgIoV4 {
+    fn sg_io(
+        &self,
+        cdb: &mut [u8],
+        in_data: Option<&[u8]>,
+        out_data: Option<&mut [u8]>,
+    ) -> Result<(ScsiStatus, [u8; 32])> {
+        let mut sense = [0u8; 32];
+        let mut hdr = sg_io_v4_header(cdb, &mut sense, in_data, out_data);
+
+        let r = unsafe { ioctl(self.fd.as_raw_fd(), SG_IO, &mut hdr) };
+        anyhow::ensure!(r >= 0, "sg_io failed: {}", std::io::Error::last_os_error());
+
+        let status = ScsiStatus {
+            status: hdr.device_status as u8,
+            host_status: hdr.transport_status as u16,
+        };
+
+        Ok((status, sense))
+    }
+}
+
+/// SG_IO v4 header for `cdb`, pointing at `sense` and data buffers
+fn sg_io_v4_header(
+    cdb: &mut [u8],
+    sense: &mut [u8],
+    in_data: Option<&[u8]>,
+    out_data: Option<&mut [u8]>,
+) -> ffi::SgIoV4 {
+    let mut hdr = ffi::SgIoV4 {
+        request_len: cdb.len() as u32,
+        request: cdb.as_mut_ptr() as u64,
+        max_response_len: sense.len() as u32,
+        response: sense.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+
+    if let Some(in_data) = in_data {
+        hdr.dout_xfer_len = in_data.len() as u32;
+        hdr.dout_xferp = in_data.as_ptr() as u64; // safe, no write to in_data
+    }
+    if let Some(out_data) = out_data {
+        hdr.din_xfer_len = out_data.len() as u32;
+        hdr.din_xferp = out_data.as_mut_ptr() as u64;
+    }
+
+    hdr
+}
+
+/// MegaRAID firmware pass-through, reach physical drives hidden behind logical disks
+pub struct MegaRaid {
+    fd: OwnedFd,
+    host_no: u16,
+    target_id: u8,
+}
+
+impl MegaRaid {
+    /// Open controller ioctl node, the controller is the SCSI host of `device`
+    pub fn open(device: &str, target_id: u8) -> Result<MegaRaid> {
+        let host_no =
+            scsi_host(device).with_context(|| format!("unable to find SCSI host of {}", device))?;
+
+        let fd = open_fd(MEGASAS_IOCTL_NODE, O_RDWR)?;
+
+        Ok(MegaRaid {
+            fd,
+            host_no,
+            target_id,
+        })
+    }
+}
+
+impl Transport for MegaRaid {
+    fn sg_io(
+        &self,
+        cdb: &mut [u8],
+        in_data: Option<&[u8]>,
+        out_data: Option<&mut [u8]>,
+    ) -> Result<(ScsiStatus, [u8; 32])> {
+        let mut sense = [0u8; 32];
+
+        let (flags, data, data_len) = match (in_data, out_data) {
+            (Some(in_data), None) => (
+                MFI_FRAME_DIR_WRITE,
+                in_data.as_ptr() as *mut libc::c_void,
+                in_data.len(),
+            ),
+            (None, Some(out_data)) => (
+                MFI_FRAME_DIR_READ,
+                out_data.as_mut_ptr() as *mut libc::c_void,
+                out_data.len(),
+            ),
+            (None, None) => (MFI_FRAME_DIR_NONE, std::ptr::null_mut(), 0),
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one direction allowed");
+            }
+        };
+
+        let mut packet = MegasasIocPacket {
+            host_no: self.host_no,
+            pad1: 0,
+            sgl_off: MEGASAS_PTHRU_SGL_OFF as u32,
+            sge_count: if data_len > 0 { 1 } else { 0 },
+            sense_off: MEGASAS_PTHRU_SENSE_OFF as u32,
+            sense_len: sense.len() as u32,
+            frame: build_megaraid_pthru(
+                self.target_id,
+                cdb,
+                flags,
+                data as u64,
+                data_len as u32,
+                sense.as_mut_ptr() as u64,
+                sense.len() as u8,
+            ),
+            sgl: [libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            }; MEGASAS_MAX_IOCTL_SGE],
+        };
+        packet.sgl[0] = libc::iovec {
+            iov_base: data,
+            iov_len: data_len,
+        };
+
+        let r = unsafe { ioctl(self.fd.as_raw_fd(), MEGASAS_IOC_FIRMWARE, &mut packet) };
+        anyhow::ensure!(
+            r >= 0,
+            "megaraid pass-through failed: {}",
+            std::io::Error::last_os_error()
+        );
+
+        // only cmd_status is written back into the frame
+        let cmd_status = packet.frame[0x02];
+        anyhow::ensure!(
+            cmd_status == MFI_STAT_OK || cmd_status == MFI_STAT_SCSI_DONE_WITH_ERROR,
+            "megaraid command to device {} failed with status {:#x}",
+            self.target_id,
+            cmd_status
+        );
+
+        let status = ScsiStatus {
+            // CHECK CONDITION
+            status: if cmd_status == MFI_STAT_SCSI_DONE_WITH_ERROR {
+                0x02
+            } else {
+                0x00
+            },
+            host_status: 0,
+        };
+
+        Ok((status, sense))
+    }
+}
+
+/// Open `path` with `open(2)` flags, eg `O_RDWR | O_EXCL`
+///
+/// The returned fd is closed on drop
+pub fn open_fd(path: &str, flags: c_int) -> Result<OwnedFd> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(flags & O_ACCMODE != O_RDONLY)
+        .custom_flags(flags & !O_ACCMODE)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("open {} failed: {}", path, e))?;
+
+    Ok(file.into())
+}
+
+/// SCSI host number of block or sg `device`, from `H:C:T:L` in sysfs
+fn scsi_host(device: &str) -> Option<u16> {
+    let name = Path::new(device).canonicalize().ok()?;
+    let name = name.file_name()?.to_str()?;
+
+    let sysfs = if name.starts_with("sg") {
+        format!("/sys/class/scsi_generic/{}/device", name)
+    } else {
+        format!("/sys/block/{}/device", name)
+    };
+    let sysfs = Path::new(&sysfs).canonicalize().ok()?;
+    let hctl = sysfs.file_name()?.to_str()?;
+
+    hctl.split(':').next()?.parse().ok()
+}
+
+/// sg node of block `device`, eg /dev/sg1 for /dev/sda
+///
+/// sg nodes are returned as is
+pub fn sg_node(device: &str) -> Option<String> {
+    let path = Path::new(device).canonicalize().ok()?;
+    let name = path.file_name()?.to_str()?;
+
+    if name.starts_with("sg") {
+        return Some(path.to_str()?.to_string());
+    }
+
+    let sysfs = format!("/sys/block/{}/device/scsi_generic", name);
+    let sg = std::fs::read_dir(sysfs).ok()?.next()?.ok()?;
+
+    Some(format!("/dev/{}", sg.file_name().to_str()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{AtaCmd, AtaTaskfile, Protocol, SG_IO_V4_GUARD};
+
+    #[test]
+    fn sg_io_v4_data_in() {
+        let mut cdb = AtaTaskfile {
+            cmd: AtaCmd::IdentifyDevice,
+            protocol: Protocol::PioIn,
+            feature: 0,
+            sector_count: 1,
+            lba: 0,
+        }
+        .to_passthrough16();
+        let mut sense = [0u8; 32];
+        let mut buffer = [0u8; 512];
+        let buffer_addr = buffer.as_ptr() as u64;
+
+        let hdr = sg_io_v4_header(&mut cdb, &mut sense, None, Some(&mut buffer));
+
+        assert_eq!(hdr.guard, SG_IO_V4_GUARD);
+        assert_eq!((hdr.protocol, hdr.subprotocol), (0, 0));
+        assert_eq!(hdr.request_len, 16);
+        assert_eq!(hdr.request, cdb.as_ptr() as u64);
+        assert_eq!(hdr.max_response_len, 32);
+        assert_eq!(hdr.response, sense.as_ptr() as u64);
+        assert_eq!((hdr.din_xfer_len, hdr.din_xferp), (512, buffer_addr));
+        assert_eq!((hdr.dout_xfer_len, hdr.dout_xferp), (0, 0));
+        assert_eq!((hdr.din_iovec_count, hdr.dout_iovec_count), (0, 0));
+        assert_eq!(hdr.timeout, 0);
+    }
+
+    #[test]
+    fn sg_io_v4_data_out() {
+        let mut cdb = [0xa1, 0x0a, 0x06, 0, 1, 0, 0, 0, 0xa0, 0x3f, 0, 0];
+        let mut sense = [0u8; 32];
+        let buffer = [0x5au8; 512];
+
+        let hdr = sg_io_v4_header(&mut cdb, &mut sense, Some(&buffer), None);
+
+        assert_eq!(hdr.request_len, 12);
+        assert_eq!(hdr.request, cdb.as_ptr() as u64);
+        assert_eq!(hdr.response, sense.as_ptr() as u64);
+        assert_eq!(
+            (hdr.dout_xfer_len, hdr.dout_xferp),
+            (512, buffer.as_ptr() as u64)
+        );
+        assert_eq!((hdr.din_xfer_len, hdr.din_xferp), (0, 0));
+    }
+}
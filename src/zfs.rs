@@ -0,0 +1,151 @@
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+/// Leaf vdev paths of ZFS `pool`, from `zpool status -P`
+///
+/// Paths are as configured, eg /dev/disk/by-id/ata-...-part1
+pub fn pool_devices(pool: &str) -> Result<Vec<String>> {
+    let output = Command::new("zpool")
+        .args(["status", "-P", pool])
+        .output()
+        .context("unable to run zpool")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "zpool status {} failed: {}",
+        pool,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    let devices = parse_status(&String::from_utf8_lossy(&output.stdout));
+    anyhow::ensure!(!devices.is_empty(), "no device found in pool {}", pool);
+
+    Ok(devices)
+}
+
+/// Parse leaf vdevs from the `config:` section of `zpool status -P` output
+///
+/// Data, log, cache and spare devices are included, missing devices show a guid
+/// instead of a path and are skipped, as are devices the pool can't open
+pub fn parse_status(status: &str) -> Vec<String> {
+    let mut devices = Vec::new();
+    let mut config = false;
+
+    for line in status.lines() {
+        let trimmed = line.trim();
+
+        if trimmed == "config:" {
+            config = true;
+            continue;
+        }
+        if !config || trimmed.is_empty() {
+            continue;
+        }
+        // next section, eg `errors:`
+        if !line.starts_with(char::is_whitespace) {
+            break;
+        }
+
+        let mut fields = trimmed.split_whitespace();
+        let name = fields.next().unwrap_or_default();
+        let state = fields.next().unwrap_or_default();
+        if matches!(state, "UNAVAIL" | "REMOVED") {
+            continue;
+        }
+        if name.starts_with('/') && !devices.iter().any(|it| it == name) {
+            devices.push(name.to_string());
+        }
+    }
+
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIRROR_RAIDZ: &str = "  pool: tank
+ state: ONLINE
+  scan: scrub repaired 0B in 05:12:41 with 0 errors on Sun Oct 11 05:36:42 2026
+config:
+
+	NAME                                                 STATE     READ WRITE CKSUM
+	tank                                                 ONLINE       0     0     0
+	  mirror-0                                           ONLINE       0     0     0
+	    /dev/disk/by-id/ata-WDC_WUH721818ALE6L4_3WJ0AAAA-part1  ONLINE       0     0     0
+	    /dev/disk/by-id/ata-WDC_WUH721818ALE6L4_3WJ0BBBB-part1  ONLINE       0     0     0
+	  raidz2-1                                           ONLINE       0     0     0
+	    /dev/sdc1                                        ONLINE       0     0     0
+	    /dev/sdd1                                        ONLINE       0     0     0
+	    /dev/sde1                                        ONLINE       0     0     0
+	    /dev/sdf1                                        ONLINE       0     0     0
+	logs
+	  mirror-2                                           ONLINE       0     0     0
+	    /dev/nvme0n1p2                                   ONLINE       0     0     0
+	    /dev/nvme1n1p2                                   ONLINE       0     0     0
+	cache
+	  /dev/nvme0n1p3                                     ONLINE       0     0     0
+	spares
+	  /dev/disk/by-id/ata-WDC_WUH721818ALE6L4_3WJ0CCCC-part1    AVAIL
+
+errors: No known data errors
+";
+
+    const DEGRADED: &str = "  pool: archive
+ state: DEGRADED
+status: One or more devices could not be used because the label is missing or
+	invalid.  Sufficient replicas exist for the pool to continue
+	functioning in a degraded state.
+action: Replace the device using 'zpool replace'.
+   see: https://openzfs.github.io/openzfs-docs/msg/ZFS-8000-4J
+config:
+
+	NAME                                              STATE     READ WRITE CKSUM
+	archive                                           DEGRADED     0     0     0
+	  raidz1-0                                        DEGRADED     0     0     0
+	    /dev/disk/by-id/wwn-0x5000cca29ac10001-part1  ONLINE       0     0     0
+	    15658386013520431925                          UNAVAIL      0     0     0  was /dev/disk/by-id/wwn-0x5000cca29ac10002-part1
+	    /dev/disk/by-id/wwn-0x5000cca29ac10003-part1  UNAVAIL      0     0     0  cannot open
+	    /dev/disk/by-id/wwn-0x5000cca29ac10004-part1  FAULTED      3   104     0  too many errors
+	spares
+	  /dev/disk/by-id/wwn-0x5000cca29ac10005-part1    INUSE     currently in use
+
+errors: No known data errors
+";
+
+    #[test]
+    fn mirror_raidz_log_cache_spare() {
+        assert_eq!(
+            parse_status(MIRROR_RAIDZ),
+            [
+                "/dev/disk/by-id/ata-WDC_WUH721818ALE6L4_3WJ0AAAA-part1",
+                "/dev/disk/by-id/ata-WDC_WUH721818ALE6L4_3WJ0BBBB-part1",
+                "/dev/sdc1",
+                "/dev/sdd1",
+                "/dev/sde1",
+                "/dev/sdf1",
+                "/dev/nvme0n1p2",
+                "/dev/nvme1n1p2",
+                "/dev/nvme0n1p3",
+                "/dev/disk/by-id/ata-WDC_WUH721818ALE6L4_3WJ0CCCC-part1",
+            ]
+        );
+    }
+
+    #[test]
+    fn degraded_skips_unavailable() {
+        assert_eq!(
+            parse_status(DEGRADED),
+            [
+                "/dev/disk/by-id/wwn-0x5000cca29ac10001-part1",
+                "/dev/disk/by-id/wwn-0x5000cca29ac10004-part1",
+                "/dev/disk/by-id/wwn-0x5000cca29ac10005-part1",
+            ]
+        );
+    }
+
+    #[test]
+    fn no_config() {
+        assert!(parse_status("cannot open 'tank': no such pool\n").is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+"""Tests of the Python module over a fake drive
+
+Build and install the module first, eg `maturin develop`, then run
+`python -m pytest tests/python`, or `python -m unittest discover tests/python` without pytest
+"""
+
+import struct
+import unittest
+
+import wdepc
+
+
+def ata_return(count):
+    """Descriptor sense with an ATA Status Return descriptor"""
+    sense = bytearray(22)
+    sense[0] = 0x72
+    sense[1] = 0x01
+    sense[3] = 0x1D
+    sense[7] = 14
+    sense[8] = 0x09
+    sense[9] = 0x0C
+    sense[13] = count
+    sense[21] = 0x50
+    return bytes(sense)
+
+
+class FakeDrive:
+    """Answer ATA PASS-THROUGH(16) commands of an EPC drive"""
+
+    def __init__(self, mode=0xFF):
+        self.mode = mode
+        self.fail = False
+        self.commands = []
+        # supported, savable, changeable and every enable flag, timers
+        self.conditions = {
+            0: (0xFC, 20, 20, 20),
+            64: (0xE0, 1200, 1200, 1200),
+            128: (0xE0, 6000, 6000, 6000),
+            512 + 384: (0xE0, 6000, 6000, 6000),
+            512 + 448: (0xFC, 9000, 9000, 3000),
+        }
+
+    def sg_io(self, cdb, data, out_len):
+        self.commands.append(bytes(cdb))
+        if self.fail or cdb[0] != 0x85:
+            raise OSError("invalid opcode")
+
+        command = cdb[14]
+        if command == 0xE5:
+            return 0, ata_return(self.mode), b""
+        if command == 0xEC:
+            words = [0] * 256
+            words[119] = 1 << 7
+            words[120] = 1 << 7
+            return 0, b"", struct.pack("<256H", *words)
+        if command == 0x47:
+            page = cdb[8]
+            out = bytearray(out_len)
+            if page == 0x00:
+                out[0x08 * 2] = 2
+            elif page == 0x08:
+                for offset, (flag, default, saved, current) in self.conditions.items():
+                    out[offset + 1] = flag
+                    struct.pack_into("<6I", out, offset + 4, default, saved, current, 10, 1, 0xFFFF)
+            return 0, b"", bytes(out)
+        if command == 0xEF:
+            return 0, b"", b""
+        raise OSError("unsupported command")
+
+
+class PowerModeTest(unittest.TestCase):
+    def test_mapping(self):
+        modes = {
+            0xFF: wdepc.PowerMode.Active,
+            0x81: wdepc.PowerMode.IdleA,
+            0x82: wdepc.PowerMode.IdleB,
+            0x83: wdepc.PowerMode.IdleC,
+            0x01: wdepc.PowerMode.StandbyY,
+            0x00: wdepc.PowerMode.StandbyZ,
+            0x42: wdepc.PowerMode.Unknown,
+        }
+        for count, mode in modes.items():
+            device = wdepc.Device.with_transport(FakeDrive(mode=count))
+            self.assertEqual(device.query_mode(), mode)
+
+    def test_not_settable(self):
+        drive = FakeDrive()
+        device = wdepc.Device.with_transport(drive, "sat")
+        for mode in (wdepc.PowerMode.Active, wdepc.PowerMode.Unknown):
+            with self.assertRaises(ValueError):
+                device.set_timer(mode, 100)
+            with self.assertRaises(ValueError):
+                device.goto_cond(mode)
+        self.assertEqual(drive.commands, [])
+
+    def test_set_timer(self):
+        drive = FakeDrive()
+        device = wdepc.Device.with_transport(drive, "sat")
+        device.set_timer(wdepc.PowerMode.IdleB, 0x1234, enable=True)
+
+        cdb = drive.commands[-1]
+        # SET FEATURES EPC, idle b, set timer with enable
+        self.assertEqual((cdb[14], cdb[4], cdb[6]), (0xEF, 0x4A, 0x82))
+        self.assertEqual((cdb[8], cdb[10]), (0x22, 0x34))
+        self.assertEqual(cdb[12], 0x12)
+
+    def test_all(self):
+        drive = FakeDrive()
+        device = wdepc.Device.with_transport(drive, "sat")
+        device.set_state(wdepc.PowerMode.All, False)
+
+        cdb = drive.commands[-1]
+        # SET FEATURES EPC, every condition, set state
+        self.assertEqual((cdb[14], cdb[4], cdb[6], cdb[8]), (0xEF, 0x4A, 0xFF, 0x03))
+
+        commands = len(drive.commands)
+        with self.assertRaises(wdepc.CommandError):
+            device.goto_cond(wdepc.PowerMode.All)
+        self.assertEqual(len(drive.commands), commands)
+
+
+class ErrorTest(unittest.TestCase):
+    def test_open_error(self):
+        with self.assertRaises(wdepc.OpenError):
+            wdepc.Device("/dev/wdepc-nonexistent")
+
+        drive = FakeDrive()
+        drive.fail = True
+        with self.assertRaises(wdepc.OpenError) as raised:
+            wdepc.Device.with_transport(drive)
+        self.assertIsInstance(raised.exception, wdepc.WdepcError)
+
+    def test_command_error(self):
+        drive = FakeDrive()
+        device = wdepc.Device.with_transport(drive, "sat")
+        drive.fail = True
+        with self.assertRaises(wdepc.CommandError) as raised:
+            device.query_mode()
+        self.assertIsInstance(raised.exception, wdepc.WdepcError)
+        self.assertNotIsInstance(raised.exception, wdepc.OpenError)
+
+    def test_invalid_type(self):
+        with self.assertRaises(ValueError):
+            wdepc.Device.with_transport(FakeDrive(), "sat,13")
+
+
+class PowerConditionTest(unittest.TestCase):
+    def test_fields(self):
+        device = wdepc.Device.with_transport(FakeDrive(), "sat")
+        setting = device.query_epc_setting()
+
+        idle_a = setting.idle_a
+        self.assertTrue(idle_a.supported)
+        self.assertTrue(idle_a.savable)
+        self.assertTrue(idle_a.changeable)
+        self.assertTrue(idle_a.default_enable)
+        self.assertTrue(idle_a.saved_enable)
+        self.assertTrue(idle_a.current_enable)
+        self.assertEqual(idle_a.default_timer, 20)
+
+        standby_z = setting.standby_z
+        self.assertEqual(
+            (standby_z.default_timer, standby_z.saved_timer, standby_z.current_timer),
+            (9000, 9000, 3000),
+        )
+        self.assertEqual(standby_z.recovery_time, 10)
+        self.assertEqual((standby_z.min_timer, standby_z.max_timer), (1, 0xFFFF))
+        self.assertFalse(setting.idle_b.current_enable)
+        self.assertIn("current_timer=3000", repr(standby_z))
+
+    def test_identify(self):
+        identify = wdepc.Device.with_transport(FakeDrive(), "sat").identify()
+        self.assertTrue(identify.epc_supported)
+        self.assertTrue(identify.epc_enabled)
+        self.assertIsNone(identify.apm_level)
+
+
+if __name__ == "__main__":
+    unittest.main()